@@ -1,7 +1,8 @@
-use miette::{bail, Result, Severity};
+use miette::Result;
 
 use crate::{
     debugger::Breakpoints,
+    error,
     symbol::{Flag, Label, Register, Span},
 };
 
@@ -15,23 +16,35 @@ pub struct Air {
 
     pub breakpoints: Breakpoints,
 
+    /// Handlers registered with `.trap`, installed in the trap vector table when run
+    pub traps: Vec<TrapHandler>,
+
     pub src: &'static str,
 }
 
+/// Subroutine registered to handle a trap vector, such as with `.trap x30, handler`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrapHandler {
+    pub vector: u8,
+    pub label: Label,
+    pub span: Span,
+}
+
 impl Air {
     pub fn new(src: &'static str) -> Self {
         Air {
             orig: None,
             ast: Vec::new(),
             breakpoints: Breakpoints::new(),
+            traps: Vec::new(),
             src,
         }
     }
 
     /// Set the .orig offset for the program. Error if set twice.
-    pub fn set_orig(&mut self, val: u16) -> Result<()> {
+    pub fn set_orig(&mut self, val: u16, span: Span) -> Result<()> {
         if self.orig.is_some() {
-            bail!("Origin set twice.")
+            Err(error::parse_orig_twice(span, self.src))
         } else {
             self.orig = Some(val);
             Ok(())
@@ -61,10 +74,22 @@ impl Air {
 
     pub fn backpatch(&mut self) -> Result<()> {
         for stmt in self.ast.iter_mut() {
-            stmt.backpatch()?;
+            stmt.backpatch(self.src)?;
+        }
+        for trap in self.traps.iter_mut() {
+            trap.label = trap.label.clone().filled(self.src)?;
         }
         Ok(())
     }
+
+    /// Vector and handler address of each `.trap` directive. Air must be backpatched.
+    pub fn trap_handlers(&self) -> impl Iterator<Item = (u8, u16)> + '_ {
+        let orig = self.orig.unwrap_or(0x3000);
+        self.traps.iter().filter_map(move |trap| match trap.label {
+            Label::Ref(line) => Some((trap.vector, orig.wrapping_add(line).wrapping_sub(1))),
+            Label::Unfilled(..) => None,
+        })
+    }
 }
 
 impl<'a> IntoIterator for &'a Air {
@@ -147,6 +172,37 @@ pub enum AirStmt {
     Trap { trap_vect: u8 },
 }
 
+impl AirStmt {
+    /// Label referenced by the statement, if any.
+    pub fn label(&self) -> Option<&Label> {
+        match self {
+            AirStmt::Branch { dest_label, .. }
+            | AirStmt::JumbSub { dest_label }
+            | AirStmt::Store { dest_label, .. }
+            | AirStmt::StoreInd { dest_label, .. }
+            | AirStmt::Call { dest_label } => Some(dest_label),
+            AirStmt::Load { src_label, .. }
+            | AirStmt::LoadInd { src_label, .. }
+            | AirStmt::LoadEAddr { src_label, .. } => Some(src_label),
+            _ => None,
+        }
+    }
+
+    pub fn label_mut(&mut self) -> Option<&mut Label> {
+        match self {
+            AirStmt::Branch { dest_label, .. }
+            | AirStmt::JumbSub { dest_label }
+            | AirStmt::Store { dest_label, .. }
+            | AirStmt::StoreInd { dest_label, .. }
+            | AirStmt::Call { dest_label } => Some(dest_label),
+            AirStmt::Load { src_label, .. }
+            | AirStmt::LoadInd { src_label, .. }
+            | AirStmt::LoadEAddr { src_label, .. } => Some(src_label),
+            _ => None,
+        }
+    }
+}
+
 /// Used for ADD and AND commands as they support either 5-bit immediate values or registers as the
 /// last operand.
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
@@ -183,31 +239,10 @@ impl AsmLine {
     }
 
     /// Fill label references using values from symbol table
-    pub fn backpatch(&mut self) -> Result<()> {
-        let inner_label = match self.stmt {
-            AirStmt::Branch {
-                ref mut dest_label, ..
-            } => dest_label,
-            AirStmt::JumbSub { ref mut dest_label } => dest_label,
-            AirStmt::Load {
-                ref mut src_label, ..
-            } => src_label,
-            AirStmt::LoadInd {
-                ref mut src_label, ..
-            } => src_label,
-            AirStmt::LoadEAddr {
-                ref mut src_label, ..
-            } => src_label,
-            AirStmt::Store {
-                ref mut dest_label, ..
-            } => dest_label,
-            AirStmt::StoreInd {
-                ref mut dest_label, ..
-            } => dest_label,
-            AirStmt::Call { ref mut dest_label } => dest_label,
-            _ => return Ok(()),
-        };
-        *inner_label = inner_label.clone().filled()?;
+    pub fn backpatch(&mut self, src: &'static str) -> Result<()> {
+        if let Some(label) = self.stmt.label_mut() {
+            *label = label.clone().filled(src)?;
+        }
         Ok(())
     }
 
@@ -380,19 +415,13 @@ impl AsmLine {
     fn bit_offs(&self, ref_label: &Label, bits: u32) -> Result<u16> {
         let label_pos = match ref_label {
             Label::Ref(val) => val,
-            Label::Unfilled(_) => panic!("Tried to offset unfilled label"),
+            Label::Unfilled(..) => panic!("Tried to offset unfilled label"),
         };
         let (offset, _) = label_pos.overflowing_sub(self.line);
         let offset = (offset as i16) - 1;
         // Must fit in specified offset bits
         if offset.abs() > 2i16.pow(bits - 1) - if offset > 0 { 1 } else { 0 } {
-            bail!(
-                severity = Severity::Error,
-                r#"Difference between label and label reference is too large: at line {}, referencing line {}
-                Please note that this could be because of a long .stringz literal or large .blkw allocation."#,
-                self.line,
-                label_pos
-            )
+            return Err(error::emit_offset_range(self.line, *label_pos));
         }
         Ok((offset as u16) & (2u16.pow(bits) - 1))
     }
@@ -448,6 +477,18 @@ mod test {
         assert!(air.backpatch().is_err());
     }
 
+    #[test]
+    fn backpatch_suggestion() {
+        let mut air = AsmParser::new("br Loop\nloop halt")
+            .unwrap()
+            .parse()
+            .unwrap();
+        let report = air.backpatch().unwrap_err();
+        assert_eq!(report.help().unwrap().to_string(), "did you mean `loop`?");
+        let label = report.labels().unwrap().next().unwrap();
+        assert_eq!((label.offset(), label.len()), (3, 4));
+    }
+
     // Code emission tests
     #[test]
     fn emit_add_reg() {
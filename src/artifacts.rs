@@ -0,0 +1,191 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use miette::{IntoDiagnostic, Result};
+
+/// Directory used for build artifacts when `--out-dir` is not given.
+pub const DEFAULT_OUT_DIR: &str = ".lace";
+
+/// Records which source produced each artifact, so they can be cleaned later.
+const MANIFEST_NAME: &str = "manifest";
+const MANIFEST_HEADER: &str = "# lace artifacts v1";
+
+/// Build artifacts directory, and the manifest describing its contents.
+///
+/// Every artifact is stored directly inside the directory, and is owned by exactly one source
+/// file.
+#[derive(Debug)]
+pub struct Artifacts {
+    dir: PathBuf,
+    entries: Vec<Entry>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Entry {
+    /// Absolute path of source file
+    source: PathBuf,
+    /// Artifact file name, relative to artifacts directory
+    artifact: PathBuf,
+}
+
+impl Artifacts {
+    /// Open artifacts directory, reading manifest if it exists.
+    ///
+    /// Directory is not created until [`Artifacts::save`] is called.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        let manifest = dir.join(MANIFEST_NAME);
+        let entries = if manifest.exists() {
+            parse_manifest(&fs::read_to_string(manifest).into_diagnostic()?)
+        } else {
+            Vec::new()
+        };
+        Ok(Artifacts { dir, entries })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Get path to write an artifact with the given extension for `source`, and record it in
+    /// the manifest.
+    ///
+    /// Artifacts are named after the source file stem. If another source already owns that name,
+    /// a numeric suffix is added.
+    pub fn create(&mut self, source: &Path, extension: &str) -> Result<PathBuf> {
+        let source = absolute(source)?;
+        let stem = source
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "out".to_string());
+
+        let mut artifact = PathBuf::from(format!("{stem}.{extension}"));
+        let mut suffix = 1;
+        while let Some(owner) = self.owner(&artifact) {
+            if owner == source {
+                return Ok(self.dir.join(artifact));
+            }
+            suffix += 1;
+            artifact = PathBuf::from(format!("{stem}-{suffix}.{extension}"));
+        }
+
+        self.entries.push(Entry {
+            source,
+            artifact: artifact.clone(),
+        });
+        Ok(self.dir.join(artifact))
+    }
+
+    /// Remove every artifact produced by `source`.
+    ///
+    /// Returns paths of removed files.
+    pub fn clean(&mut self, source: &Path) -> Result<Vec<PathBuf>> {
+        let source = absolute(source)?;
+        self.remove_where(|entry| entry.source == source)
+    }
+
+    /// Remove every artifact recorded in the manifest, and the manifest itself.
+    ///
+    /// Returns paths of removed artifact files.
+    pub fn clean_all(&mut self) -> Result<Vec<PathBuf>> {
+        let removed = self.remove_where(|_| true)?;
+        let manifest = self.dir.join(MANIFEST_NAME);
+        if manifest.exists() {
+            fs::remove_file(manifest).into_diagnostic()?;
+        }
+        // Only remove directory if nothing else was placed inside
+        if self.dir.exists() && fs::read_dir(&self.dir).into_diagnostic()?.next().is_none() {
+            fs::remove_dir(&self.dir).into_diagnostic()?;
+        }
+        Ok(removed)
+    }
+
+    /// Write manifest, creating artifacts directory if necessary.
+    pub fn save(&self) -> Result<()> {
+        if self.entries.is_empty() && !self.dir.exists() {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.dir).into_diagnostic()?;
+        fs::write(self.dir.join(MANIFEST_NAME), self.manifest()).into_diagnostic()
+    }
+
+    fn owner(&self, artifact: &Path) -> Option<&Path> {
+        self.entries
+            .iter()
+            .find(|entry| entry.artifact == artifact)
+            .map(|entry| entry.source.as_path())
+    }
+
+    fn remove_where(&mut self, predicate: impl Fn(&Entry) -> bool) -> Result<Vec<PathBuf>> {
+        let mut removed = Vec::new();
+        let mut kept = Vec::new();
+        for entry in self.entries.drain(..) {
+            if !predicate(&entry) {
+                kept.push(entry);
+                continue;
+            }
+            let path = self.dir.join(&entry.artifact);
+            // Artifact may have been deleted manually
+            if path.exists() {
+                fs::remove_file(&path).into_diagnostic()?;
+                removed.push(path);
+            }
+        }
+        self.entries = kept;
+        Ok(removed)
+    }
+
+    fn manifest(&self) -> String {
+        let mut manifest = format!("{MANIFEST_HEADER}\n");
+        for entry in &self.entries {
+            manifest.push_str(&format!(
+                "{}\t{}\n",
+                entry.artifact.display(),
+                entry.source.display()
+            ));
+        }
+        manifest
+    }
+}
+
+/// Parse manifest lines of the form `<artifact>\t<source>`, ignoring comments and malformed lines.
+fn parse_manifest(manifest: &str) -> Vec<Entry> {
+    manifest
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let (artifact, source) = line.split_once('\t')?;
+            Some(Entry {
+                source: PathBuf::from(source),
+                artifact: PathBuf::from(artifact),
+            })
+        })
+        .collect()
+}
+
+/// Canonical path if the file exists, otherwise an absolute path.
+fn absolute(path: &Path) -> Result<PathBuf> {
+    match fs::canonicalize(path) {
+        Ok(path) => Ok(path),
+        Err(_) => std::path::absolute(path).into_diagnostic(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn manifest_round_trip() {
+        let dir = PathBuf::from("/nonexistent/.lace");
+        let mut artifacts = Artifacts::open(&dir).unwrap();
+        let first = artifacts.create(Path::new("/src/a/hw.asm"), "lc3").unwrap();
+        let second = artifacts.create(Path::new("/src/b/hw.asm"), "lc3").unwrap();
+        let again = artifacts.create(Path::new("/src/a/hw.asm"), "lc3").unwrap();
+
+        assert_eq!(first, dir.join("hw.lc3"));
+        assert_eq!(second, dir.join("hw-2.lc3"));
+        assert_eq!(again, first);
+        assert_eq!(parse_manifest(&artifacts.manifest()), artifacts.entries);
+    }
+}
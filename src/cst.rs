@@ -0,0 +1,352 @@
+//! Lossless concrete syntax tree.
+//!
+//! Unlike the preprocessed token stream consumed by [`AsmParser`](crate::AsmParser), the tree
+//! keeps every byte of the source: whitespace, comments and directives are retained as tokens and
+//! grouped by physical source line. Printing a [`SyntaxTree`] reproduces the original source
+//! exactly, which makes it suitable for formatting, renaming and other source rewriting tools.
+
+use std::fmt;
+
+use miette::Result;
+
+use crate::lexer::{cursor::Cursor, Token, TokenKind};
+use crate::symbol::{DirKind, Span, SrcOffset};
+use crate::{error, features};
+
+/// Every token in a source file, grouped by line.
+#[derive(Clone, Debug)]
+pub struct SyntaxTree {
+    src: &'static str,
+    lines: Vec<SyntaxLine>,
+    /// Source following `.end`, which is never tokenised and is kept verbatim.
+    trailing: Span,
+}
+
+/// A single physical line of source.
+#[derive(Clone, Debug)]
+pub struct SyntaxLine {
+    /// Zero-based line index within the source.
+    number: usize,
+    /// Location of the line, excluding the line terminator.
+    span: Span,
+    /// All tokens on this line, including whitespace and comments.
+    tokens: Vec<Token>,
+}
+
+impl SyntaxTree {
+    /// Tokenise entire source, stopping after `.end` if present.
+    ///
+    /// Features named by `.feature` directives are enabled as soon as they are found, so that
+    /// following instructions are tokenised with the feature enabled.
+    pub fn parse(src: &'static str) -> Result<Self> {
+        let mut cur = Cursor::new(src);
+        let mut lines = Vec::new();
+        let mut line = SyntaxLine::new(0, 0);
+        let mut after_feature = false;
+
+        let trailing_start = loop {
+            let tok = cur.advance_token()?;
+            match tok.kind {
+                TokenKind::Eof => break src.len(),
+                // Whitespace tokens may contain line breaks, which are split into separate lines
+                TokenKind::Whitespace => {
+                    let mut start = tok.span.offs();
+                    for (i, c) in src[tok.span.as_range()].char_indices() {
+                        if c != '\n' {
+                            continue;
+                        }
+                        let newline = tok.span.offs() + i;
+                        line.push_whitespace(start..newline);
+                        line.finish(newline);
+                        lines.push(line);
+                        line = SyntaxLine::new(lines.len(), newline + 1);
+                        start = newline + 1;
+                    }
+                    line.push_whitespace(start..tok.span.end());
+                }
+                TokenKind::Dir(DirKind::End) => {
+                    line.tokens.push(tok);
+                    break tok.span.end();
+                }
+                TokenKind::Comment | TokenKind::DocComment => line.tokens.push(tok),
+                _ => {
+                    if after_feature && tok.kind == TokenKind::Label {
+                        let name = &src[tok.span.as_range()];
+                        features::enable(&name.to_ascii_lowercase())
+                            .map_err(|reason| error::lex_bad_feature(tok.span, src, reason))?;
+                    }
+                    after_feature = tok.kind == TokenKind::Dir(DirKind::Feature);
+                    line.tokens.push(tok);
+                }
+            }
+        };
+        line.finish(trailing_start);
+        lines.push(line);
+
+        Ok(SyntaxTree {
+            src,
+            lines,
+            trailing: (trailing_start..src.len()).into(),
+        })
+    }
+
+    pub fn src(&self) -> &'static str {
+        self.src
+    }
+
+    pub fn lines(&self) -> &[SyntaxLine] {
+        &self.lines
+    }
+
+    /// Source text following `.end`.
+    pub fn trailing(&self) -> &'static str {
+        &self.src[self.trailing.as_range()]
+    }
+
+    /// Every token in source order, excluding whitespace and comments.
+    pub fn significant_tokens(&self) -> impl Iterator<Item = Token> + '_ {
+        self.lines.iter().flat_map(SyntaxLine::significant_tokens)
+    }
+
+    /// Get line containing the given byte offset.
+    pub fn line_at(&self, offset: usize) -> Option<&SyntaxLine> {
+        self.lines
+            .iter()
+            .find(|line| line.span.offs() <= offset && offset <= line.span.end())
+    }
+
+    /// Get token (including whitespace and comments) containing the given byte offset.
+    pub fn token_at(&self, offset: usize) -> Option<Token> {
+        self.line_at(offset)?
+            .tokens
+            .iter()
+            .copied()
+            .find(|tok| tok.span.offs() <= offset && offset < tok.span.end())
+    }
+
+    /// Get source text of a token.
+    pub fn text(&self, tok: &Token) -> &'static str {
+        &self.src[tok.span.as_range()]
+    }
+
+    /// Every label preceded by doc comments, in source order.
+    ///
+    /// Doc comments must be on their own lines directly above the label. Any line containing code,
+    /// or a blank line, separates doc comments from the label that follows.
+    pub fn documented_labels(&self) -> Vec<DocumentedLabel> {
+        let mut documented = Vec::new();
+        let mut doc = Vec::new();
+        for line in &self.lines {
+            if let Some(label) = line.label() {
+                if !doc.is_empty() {
+                    documented.push(DocumentedLabel {
+                        label,
+                        doc: std::mem::take(&mut doc),
+                    });
+                }
+            }
+            match line.doc_comment() {
+                Some(tok) if line.significant_tokens().next().is_none() => {
+                    let text = self.text(&tok).trim_start_matches(';');
+                    doc.push(text.strip_prefix(' ').unwrap_or(text).trim_end());
+                }
+                _ => doc.clear(),
+            }
+        }
+        documented
+    }
+}
+
+/// Label along with the doc comments directly above it.
+#[derive(Clone, Debug)]
+pub struct DocumentedLabel {
+    pub label: Token,
+    /// Text of each doc comment line, without leading `;;`.
+    pub doc: Vec<&'static str>,
+}
+
+impl fmt::Display for SyntaxTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, line) in self.lines.iter().enumerate() {
+            if i > 0 {
+                f.write_str("\n")?;
+            }
+            for tok in &line.tokens {
+                f.write_str(self.text(tok))?;
+            }
+        }
+        f.write_str(self.trailing())
+    }
+}
+
+impl SyntaxLine {
+    fn new(number: usize, start: usize) -> Self {
+        SyntaxLine {
+            number,
+            span: Span::new(SrcOffset(start), 0),
+            tokens: Vec::new(),
+        }
+    }
+
+    fn push_whitespace(&mut self, range: std::ops::Range<usize>) {
+        if !range.is_empty() {
+            self.tokens
+                .push(Token::new(TokenKind::Whitespace, range.into()));
+        }
+    }
+
+    fn finish(&mut self, end: usize) {
+        self.span = (self.span.offs()..end).into();
+    }
+
+    /// Zero-based line index within the source.
+    pub fn number(&self) -> usize {
+        self.number
+    }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    /// All tokens on this line, including whitespace and comments.
+    pub fn tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+
+    /// Tokens on this line, excluding whitespace and comments.
+    pub fn significant_tokens(&self) -> impl Iterator<Item = Token> + '_ {
+        self.tokens.iter().copied().filter(|tok| {
+            !matches!(
+                tok.kind,
+                TokenKind::Whitespace | TokenKind::Comment | TokenKind::DocComment
+            )
+        })
+    }
+
+    /// Label defined at the start of this line, if any.
+    pub fn label(&self) -> Option<Token> {
+        self.significant_tokens()
+            .next()
+            .filter(|tok| tok.kind == TokenKind::Label)
+    }
+
+    /// Instruction, trap or directive on this line, if any.
+    pub fn head(&self) -> Option<Token> {
+        self.significant_tokens().find(|tok| {
+            matches!(
+                tok.kind,
+                TokenKind::Instr(_) | TokenKind::Trap(_) | TokenKind::Dir(_)
+            )
+        })
+    }
+
+    /// Tokens following the instruction, trap or directive on this line.
+    pub fn operands(&self) -> impl Iterator<Item = Token> + '_ {
+        let head = self.head();
+        self.significant_tokens()
+            .skip_while(move |tok| Some(*tok) != head)
+            .skip(1)
+    }
+
+    pub fn comment(&self) -> Option<Token> {
+        self.tokens
+            .iter()
+            .copied()
+            .find(|tok| tok.kind == TokenKind::Comment)
+    }
+
+    pub fn doc_comment(&self) -> Option<Token> {
+        self.tokens
+            .iter()
+            .copied()
+            .find(|tok| tok.kind == TokenKind::DocComment)
+    }
+
+    /// Line contains only whitespace, if anything.
+    pub fn is_blank(&self) -> bool {
+        self.tokens
+            .iter()
+            .all(|tok| tok.kind == TokenKind::Whitespace)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::symbol::{InstrKind, Register};
+
+    fn round_trip(src: &'static str) {
+        let tree = SyntaxTree::parse(src).unwrap();
+        assert_eq!(tree.to_string(), src);
+    }
+
+    #[test]
+    fn round_trip_files() {
+        crate::features::init("stack".parse().unwrap());
+        round_trip(include_str!("../tests/files/hw.asm"));
+        round_trip(include_str!("../tests/files/fibonacci.asm"));
+        round_trip("");
+        round_trip("\n\n");
+        round_trip("  add r0, r0, #1 ; comment\r\n.end ; trailing @@ garbage\n");
+    }
+
+    #[test]
+    fn lines() {
+        let tree = SyntaxTree::parse("; comment\nlabel add r0 r1 #2\n\n  halt").unwrap();
+        let lines = tree.lines();
+        assert_eq!(lines.len(), 4);
+
+        assert!(lines[0].comment().is_some());
+        assert!(lines[0].head().is_none());
+
+        let label = lines[1].label().unwrap();
+        assert_eq!(tree.text(&label), "label");
+        assert_eq!(
+            lines[1].head().unwrap().kind,
+            TokenKind::Instr(InstrKind::Add)
+        );
+        let operands: Vec<_> = lines[1].operands().map(|tok| tok.kind).collect();
+        assert_eq!(
+            operands[..2],
+            [TokenKind::Reg(Register::R0), TokenKind::Reg(Register::R1)]
+        );
+
+        assert!(lines[2].is_blank());
+        assert_eq!(lines[3].number(), 3);
+        assert_eq!(tree.text(&lines[3].head().unwrap()), "halt");
+    }
+
+    #[test]
+    fn end_stops_lexing() {
+        let tree = SyntaxTree::parse("halt\n.end\n$$$").unwrap();
+        assert_eq!(tree.trailing(), "\n$$$");
+        assert_eq!(tree.significant_tokens().count(), 2);
+    }
+
+    #[test]
+    fn documented_labels() {
+        let src = ";; Not attached\n\n;; Prints R0.\n;;   Clobbers R1\nprint\n  ret\n;; Data\nadd r0 r0 r0\nnum .fill x0";
+        let tree = SyntaxTree::parse(src).unwrap();
+        let documented = tree.documented_labels();
+        assert_eq!(documented.len(), 1);
+        assert_eq!(tree.text(&documented[0].label), "print");
+        assert_eq!(documented[0].doc, ["Prints R0.", "  Clobbers R1"]);
+    }
+
+    #[test]
+    fn feature_directive() {
+        assert!(SyntaxTree::parse("push r0").is_err());
+        assert!(SyntaxTree::parse(".feature stack\npush r0").is_ok());
+        crate::reset_state();
+        assert!(SyntaxTree::parse("push r0").is_err());
+        assert!(SyntaxTree::parse(".feature unknown").is_err());
+    }
+
+    #[test]
+    fn token_at() {
+        let tree = SyntaxTree::parse("lea r0 hw\nhw .fill x0").unwrap();
+        let tok = tree.token_at(8).unwrap();
+        assert_eq!(tok.kind, TokenKind::Label);
+        assert_eq!(tree.text(&tok), "hw");
+    }
+}
@@ -36,7 +36,7 @@ fn eval_inner(state: &mut RunState, line: &'static str) -> Result<()> {
         }
 
         // Don't allow `RTI` (interrupt) instruction
-        // Since it can only be used in supervisor mode, and it is unimplemented regardless
+        // Since it can only be used in supervisor mode
         AirStmt::Interrupt => {
             dprintln!(
                 Alternate,
@@ -63,8 +63,8 @@ fn eval_inner(state: &mut RunState, line: &'static str) -> Result<()> {
 
         // Don't allow unknown/invalid trap instructions
         // To prevent exception and program exit
-        // WARNING: If custom traps are implemented, this condition should be changed!!
-        AirStmt::Trap { trap_vect } if !(0x20..=0x27).contains(&trap_vect) => {
+        // Traps with a handler in the trap vector table are allowed
+        AirStmt::Trap { trap_vect } if !state.has_trap_routine(trap_vect) => {
             dprintln!(
                 Alternate,
                 Error,
@@ -82,11 +82,13 @@ fn eval_inner(state: &mut RunState, line: &'static str) -> Result<()> {
 
     // Check labels
     let mut asm = AsmLine::new(0, stmt, Span::dummy());
-    asm.backpatch()?;
+    asm.backpatch(line)?;
 
     // Compile and execute
     let instr = asm.emit()?;
-    state.execute(instr);
+    if let Err(err) = state.execute(instr) {
+        dprintln!(Alternate, Error, "RuntimeError", ["Exception: {}.", err],);
+    }
 
     Ok(())
 }
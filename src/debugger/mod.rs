@@ -9,9 +9,9 @@ use self::asm::AsmSource;
 use self::command::{Command, CommandReader, Label, Location, MemoryLocation};
 use crate::air::AsmLine;
 use crate::output::{Condition, Output};
-use crate::runtime::{RunState, HALT_ADDRESS, USER_MEMORY_END};
+use crate::runtime::{RunState, RuntimeError, HALT_ADDRESS, USER_MEMORY_END};
 use crate::symbol::with_symbol_table;
-use crate::{dprintln, features};
+use crate::{dprintln, error, features};
 
 pub use self::breakpoint::{Breakpoint, Breakpoints};
 
@@ -137,6 +137,22 @@ impl Debugger {
         }
     }
 
+    /// Report a fault from the program, and wait for a command instead of exiting.
+    pub(super) fn pause_on_error(&mut self, error: &RuntimeError) {
+        dprintln!(
+            Alternate,
+            Error,
+            "RuntimeError",
+            ["Exception: {}. Pausing execution.", error],
+        );
+        self.status = Status::WaitForAction;
+    }
+
+    /// Replace state restored by `reset`, such as after loading an operating system.
+    pub(super) fn set_initial_state(&mut self, initial_state: RunState) {
+        self.initial_state = initial_state;
+    }
+
     pub(super) fn orig(&self) -> u16 {
         debug_assert_eq!(
             self.asm_source.orig(),
@@ -146,6 +162,11 @@ impl Debugger {
         self.asm_source.orig()
     }
 
+    /// Amount of instructions executed since last command.
+    pub(super) fn instruction_count(&self) -> u32 {
+        self.instruction_count
+    }
+
     pub(super) fn increment_instruction_count(&mut self) {
         self.instruction_count += 1;
     }
@@ -672,12 +693,9 @@ fn resolve_symbol_address(label: &str) -> Option<u16> {
             "Labels::NotFound",
             ["Label not found named `{}`.", label],
         );
-        // Check for case-*insensitive* match
-        for key in sym.keys() {
-            if key.eq_ignore_ascii_case(label) {
-                dprintln!(Sometimes, Warning, "Hint: Similar label named `{}`", key);
-                break;
-            }
+        // Check for case-insensitive or misspelled match
+        if let Some(key) = error::closest_match(label, sym.keys().map(String::as_str)) {
+            dprintln!(Sometimes, Warning, "Hint: Similar label named `{}`", key);
         }
         None
     })
@@ -0,0 +1,424 @@
+//! Collect every diagnostic for a source file, rather than stopping at the first error.
+//!
+//! Used by editor tooling, where all problems in a file should be reported at once. Diagnostics can
+//! also be serialized as JSON or SARIF, for tools which cannot parse human-readable reports.
+
+use std::ops::Range;
+
+use clap::ValueEnum;
+use miette::{Report, Severity};
+use serde_json::{json, Value};
+
+use crate::lexer::{cursor::Cursor, TokenKind};
+use crate::symbol::{reset_state, Span, StaticSource};
+use crate::AsmParser;
+
+/// Maximum amount of errors reported for a single file.
+const MAX_ERRORS: usize = 64;
+
+/// Owned copy of a [`Report`], which does not borrow the source it was created from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: Option<String>,
+    pub message: String,
+    pub help: Option<String>,
+    pub labels: Vec<DiagnosticLabel>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiagnosticLabel {
+    pub span: Span,
+    pub label: Option<String>,
+}
+
+impl Diagnostic {
+    /// Span of the primary label, if any.
+    pub fn span(&self) -> Option<Span> {
+        self.labels.first().map(|label| label.span)
+    }
+
+    /// Attach a span to a diagnostic which was created without one.
+    fn or_span(mut self, span: Span) -> Self {
+        if self.labels.is_empty() {
+            self.labels.push(DiagnosticLabel { span, label: None });
+        }
+        self
+    }
+}
+
+impl From<&Report> for Diagnostic {
+    fn from(report: &Report) -> Self {
+        let labels = report
+            .labels()
+            .map(|labels| {
+                labels
+                    .map(|label| DiagnosticLabel {
+                        span: (label.offset()..label.offset() + label.len()).into(),
+                        label: label.label().map(str::to_string),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Diagnostic {
+            severity: report.severity().unwrap_or(Severity::Error),
+            code: report.code().map(|code| code.to_string()),
+            message: report.to_string(),
+            help: report.help().map(|help| help.to_string()),
+            labels,
+        }
+    }
+}
+
+/// How diagnostics are presented to the user.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum MessageFormat {
+    /// Graphical reports, with source context.
+    #[default]
+    Human,
+    /// One JSON object per diagnostic, each on its own line.
+    Json,
+    /// A single SARIF log containing every diagnostic.
+    Sarif,
+}
+
+impl Diagnostic {
+    /// Serialize diagnostic as a JSON object.
+    ///
+    /// Lines and columns are 1-based, with columns counted in characters.
+    pub fn to_json(&self, file: &str, src: &str) -> Value {
+        let labels: Vec<Value> = self
+            .labels
+            .iter()
+            .map(|label| {
+                json!({
+                    "message": label.label,
+                    "span": span_json(src, label.span),
+                })
+            })
+            .collect();
+        json!({
+            "file": file,
+            "severity": severity_name(self.severity),
+            "code": self.code,
+            "message": self.message,
+            "help": self.help,
+            "span": self.span().map(|span| span_json(src, span)),
+            "labels": labels,
+        })
+    }
+
+    fn sarif_result(&self, uri: &str, src: &str) -> Value {
+        let level = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Advice => "note",
+        };
+        let mut text = self.message.clone();
+        if let Some(help) = &self.help {
+            text.push_str("\nhelp: ");
+            text.push_str(help);
+        }
+        let location = |span: Span, message: Option<&String>| {
+            let mut location = json!({
+                "physicalLocation": {
+                    "artifactLocation": { "uri": uri },
+                    "region": sarif_region(src, span),
+                },
+            });
+            if let Some(message) = message {
+                location["message"] = json!({ "text": message });
+            }
+            location
+        };
+        let mut result = json!({
+            "ruleId": self.code.as_deref().unwrap_or("lace"),
+            "level": level,
+            "message": { "text": text },
+            "locations": self
+                .labels
+                .first()
+                .map(|label| vec![location(label.span, label.label.as_ref())])
+                .unwrap_or_default(),
+        });
+        let related: Vec<Value> = self
+            .labels
+            .iter()
+            .skip(1)
+            .map(|label| location(label.span, label.label.as_ref()))
+            .collect();
+        if !related.is_empty() {
+            result["relatedLocations"] = related.into();
+        }
+        result
+    }
+}
+
+/// Build a SARIF 2.1.0 log for diagnostics from a single file.
+pub fn sarif(file: &str, src: &str, diagnostics: &[Diagnostic]) -> Value {
+    let uri = file.replace('\\', "/");
+    let mut rules: Vec<&str> = diagnostics
+        .iter()
+        .map(|diagnostic| diagnostic.code.as_deref().unwrap_or("lace"))
+        .collect();
+    rules.sort_unstable();
+    rules.dedup();
+
+    json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "lace",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": "https://github.com/rozukke/lace",
+                    "rules": rules.iter().map(|id| json!({ "id": id })).collect::<Vec<_>>(),
+                },
+            },
+            "columnKind": "unicodeCodePoints",
+            "artifacts": [{ "location": { "uri": uri } }],
+            "results": diagnostics
+                .iter()
+                .map(|diagnostic| diagnostic.sarif_result(&uri, src))
+                .collect::<Vec<_>>(),
+        }],
+    })
+}
+
+fn severity_name(severity: Severity) -> &'static str {
+    match severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+        Severity::Advice => "advice",
+    }
+}
+
+/// 1-based line and character column of a byte offset.
+fn line_column(src: &str, offset: usize) -> (usize, usize) {
+    let mut offset = offset.min(src.len());
+    while !src.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let before = &src[..offset];
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line = before.matches('\n').count() + 1;
+    let column = before[line_start..].chars().count() + 1;
+    (line, column)
+}
+
+fn span_json(src: &str, span: Span) -> Value {
+    let (start_line, start_column) = line_column(src, span.offs());
+    let (end_line, end_column) = line_column(src, span.end());
+    json!({
+        "start": span.offs(),
+        "end": span.end(),
+        "start_line": start_line,
+        "start_column": start_column,
+        "end_line": end_line,
+        "end_column": end_column,
+    })
+}
+
+fn sarif_region(src: &str, span: Span) -> Value {
+    let (start_line, start_column) = line_column(src, span.offs());
+    let (end_line, end_column) = line_column(src, span.end());
+    json!({
+        "startLine": start_line,
+        "startColumn": start_column,
+        "endLine": end_line,
+        "endColumn": end_column,
+        "byteOffset": span.offs(),
+        "byteLength": span.len(),
+    })
+}
+
+/// Result of a single assembly attempt.
+enum Attempt {
+    /// No more fatal errors can be found.
+    Finished,
+    /// A fatal error was found; blank the given source range and try again.
+    Retry(Range<usize>),
+}
+
+/// Assemble source, collecting every diagnostic.
+///
+/// Whenever a fatal lexer or parser error is found, the offending statement is replaced with a
+/// `HALT` placeholder (keeping byte offsets and addresses intact) and assembly is attempted again.
+/// Any leading label on that line is kept, to avoid reporting every reference to it as missing.
+///
+/// Symbol table is reset afterwards.
+pub fn check(src: &str) -> Vec<Diagnostic> {
+    let mut text = src.to_string();
+    let mut diagnostics = Vec::new();
+
+    while diagnostics.len() < MAX_ERRORS {
+        let mut source = StaticSource::new(text.clone());
+        let attempt = check_once(source.src(), &mut diagnostics);
+        reset_state();
+        // All reports referencing source have been dropped
+        source.reclaim();
+
+        let Attempt::Retry(range) = attempt else {
+            break;
+        };
+        let replacement = placeholder(&text[range.clone()]);
+        if replacement == text[range.clone()] {
+            // No progress can be made
+            break;
+        }
+        text.replace_range(range, &replacement);
+    }
+    diagnostics
+}
+
+fn check_once(src: &'static str, diagnostics: &mut Vec<Diagnostic>) -> Attempt {
+    let mut push = |diagnostic: Diagnostic| {
+        if !diagnostics.contains(&diagnostic) {
+            diagnostics.push(diagnostic);
+        }
+    };
+    let fatal = |report: Report, push: &mut dyn FnMut(Diagnostic)| {
+        let diagnostic = Diagnostic::from(&report);
+        let attempt = match diagnostic.span() {
+            Some(span) => Attempt::Retry(blank_range(src, span)),
+            None => Attempt::Finished,
+        };
+        push(diagnostic);
+        attempt
+    };
+
+    let parser = match AsmParser::new(src) {
+        Ok(parser) => parser,
+        Err(report) => return fatal(report, &mut push),
+    };
+    for warning in parser.warnings() {
+        push(Diagnostic::from(warning));
+    }
+    let mut air = match parser.parse() {
+        Ok(air) => air,
+        Err(report) => return fatal(report, &mut push),
+    };
+
+    // Labels and offsets are checked per statement, so every error can be found in one pass
+    for stmt in air.ast.iter_mut() {
+        let result = stmt.backpatch(src).and_then(|()| stmt.emit());
+        if let Err(report) = result {
+            push(Diagnostic::from(&report).or_span(stmt.span));
+        }
+    }
+    Attempt::Finished
+}
+
+/// Blank out `text`, keeping line breaks, with a `HALT` instruction if it fits.
+fn placeholder(text: &str) -> String {
+    const PLACEHOLDER: &str = " halt";
+    let mut replacement: String = text
+        .chars()
+        .map(|c| if c == '\n' { c } else { ' ' })
+        .collect();
+    let fits = replacement
+        .get(..PLACEHOLDER.len())
+        .is_some_and(|prefix| !prefix.contains('\n'));
+    if fits {
+        replacement.replace_range(..PLACEHOLDER.len(), PLACEHOLDER);
+    }
+    replacement
+}
+
+/// Range of whole lines covered by `span`, excluding any leading label.
+fn blank_range(src: &'static str, span: Span) -> Range<usize> {
+    let offs = span.offs().min(src.len());
+    let end = span.end().clamp(offs, src.len());
+    let start = src[..offs].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let end = src[end..].find('\n').map(|i| end + i).unwrap_or(src.len());
+
+    // Keep label if error is not located at the label itself
+    let line = &src[start..end];
+    let indent = line.len() - line.trim_start().len();
+    let word_len = line[indent..]
+        .find(|c: char| c.is_whitespace() || c == ';')
+        .unwrap_or(line.len() - indent);
+    let word_end = start + indent + word_len;
+    if word_len > 0 && offs >= word_end {
+        let is_label = Cursor::new(&src[start + indent..word_end])
+            .advance_token()
+            .is_ok_and(|tok| tok.kind == TokenKind::Label);
+        if is_label {
+            return word_end..end;
+        }
+    }
+    start..end
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check_codes(src: &str) -> Vec<(usize, Option<String>)> {
+        check(src)
+            .into_iter()
+            .map(|diagnostic| (diagnostic.span().unwrap().offs(), diagnostic.code))
+            .collect()
+    }
+
+    #[test]
+    fn multiple_errors() {
+        let src = "add r0 r0 ##\nfoo .fill add\nlabel and r1 r1 x\nbr label\nhalt\n";
+        let codes = check_codes(src);
+        assert_eq!(
+            codes,
+            [
+                (10, Some("lex::bad_lit".to_string())),
+                (23, Some("preproc::bad_lit".to_string())),
+                (43, Some("parse::unexpected_token".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn backpatch_errors() {
+        let src = "br missing\nbr other\nhalt";
+        assert_eq!(check(src).len(), 2);
+    }
+
+    #[test]
+    fn json_output() {
+        let src = "halt\nbr lop\nloop halt\n";
+        let diagnostics = check(src);
+        assert_eq!(diagnostics.len(), 1);
+        let json = diagnostics[0].to_json("test.asm", src);
+        assert_eq!(json["file"], "test.asm");
+        assert_eq!(json["severity"], "error");
+        assert_eq!(json["code"], "backpatch::label_not_found");
+        assert_eq!(json["help"], "did you mean `loop`?");
+        assert_eq!(
+            json["span"],
+            json!({
+                "start": 8,
+                "end": 11,
+                "start_line": 2,
+                "start_column": 4,
+                "end_line": 2,
+                "end_column": 7,
+            })
+        );
+    }
+
+    #[test]
+    fn sarif_output() {
+        let src = "br missing\nhalt";
+        let log = sarif("src/test.asm", src, &check(src));
+        let result = &log["runs"][0]["results"][0];
+        assert_eq!(result["ruleId"], "backpatch::label_not_found");
+        assert_eq!(result["level"], "error");
+        let location = &result["locations"][0]["physicalLocation"];
+        assert_eq!(location["artifactLocation"]["uri"], "src/test.asm");
+        assert_eq!(location["region"]["startColumn"], 4);
+    }
+
+    #[test]
+    fn no_errors() {
+        assert!(check(include_str!("../tests/files/hw.asm")).is_empty());
+    }
+}
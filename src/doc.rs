@@ -0,0 +1,255 @@
+//! Reference documentation generated from `;;` doc comments.
+//!
+//! Doc comments directly above a label describe the subroutine beginning at that label. Sentences
+//! starting with `Inputs`, `Outputs` or `Clobbers` declare the registers the subroutine uses:
+//!
+//! ```text
+//! ;; Prints R0 as decimal. Clobbers R1
+//! print_dec
+//! ```
+
+use std::fmt::Write;
+
+use clap::ValueEnum;
+use miette::Result;
+
+use crate::cst::{DocumentedLabel, SyntaxTree};
+use crate::symbol::with_symbol_table;
+use crate::{Air, AsmParser};
+
+/// Output format of generated documentation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    #[default]
+    Markdown,
+    Html,
+}
+
+impl Format {
+    /// File extension of documents in this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Markdown => "md",
+            Format::Html => "html",
+        }
+    }
+}
+
+/// A documented label, along with its location in memory.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Subroutine {
+    pub name: String,
+    /// Doc comment text, excluding register declarations.
+    pub description: String,
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    pub clobbers: Vec<String>,
+    pub address: u16,
+    /// Amount of words until the next documented label, or the end of the program.
+    pub size: u16,
+}
+
+/// Assemble source and collect every documented label.
+///
+/// Symbol table is populated while assembling.
+pub fn document(src: &'static str) -> Result<Vec<Subroutine>> {
+    let tree = SyntaxTree::parse(src)?;
+    let mut air = AsmParser::from_tree(&tree)?.parse()?;
+    air.backpatch()?;
+    Ok(subroutines(&tree, &air))
+}
+
+fn subroutines(tree: &SyntaxTree, air: &Air) -> Vec<Subroutine> {
+    let orig = air.orig().unwrap_or(0x3000);
+    let end = orig.wrapping_add(air.len() as u16);
+
+    let mut subroutines: Vec<Subroutine> = tree
+        .documented_labels()
+        .iter()
+        .filter_map(|documented| {
+            let name = tree.text(&documented.label);
+            let line = with_symbol_table(|sym| sym.get(name).copied())?;
+            let mut subroutine = parse_doc(documented);
+            subroutine.name = name.to_string();
+            subroutine.address = orig.wrapping_add(line).wrapping_sub(1);
+            Some(subroutine)
+        })
+        .collect();
+
+    let mut next = end;
+    for subroutine in subroutines.iter_mut().rev() {
+        subroutine.size = next.wrapping_sub(subroutine.address);
+        next = subroutine.address;
+    }
+    subroutines
+}
+
+/// Split doc comment into description and register declarations.
+fn parse_doc(documented: &DocumentedLabel) -> Subroutine {
+    let mut subroutine = Subroutine::default();
+    let mut description = Vec::new();
+
+    for sentence in documented.doc.iter().flat_map(|line| sentences(line)) {
+        let (keyword, rest) = sentence
+            .split_once(|c: char| c.is_whitespace() || c == ':')
+            .unwrap_or((sentence, ""));
+        let registers = match keyword.to_ascii_lowercase().as_str() {
+            "input" | "inputs" => &mut subroutine.inputs,
+            "output" | "outputs" => &mut subroutine.outputs,
+            "clobber" | "clobbers" => &mut subroutine.clobbers,
+            _ => {
+                description.push(sentence);
+                continue;
+            }
+        };
+        registers.extend(
+            rest.split(|c: char| !c.is_ascii_alphanumeric())
+                .filter(|word| is_register(word))
+                .map(str::to_ascii_uppercase),
+        );
+    }
+
+    subroutine.description = description.join(" ");
+    subroutine
+}
+
+/// Split line into sentences, each keeping its trailing full stop.
+fn sentences(line: &str) -> impl Iterator<Item = &str> {
+    let mut rest = line.trim();
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        // Full stop must be followed by whitespace, to avoid splitting numbers or file names
+        let end = rest
+            .match_indices(". ")
+            .next()
+            .map(|(i, _)| i + 1)
+            .unwrap_or(rest.len());
+        let (sentence, next) = rest.split_at(end);
+        rest = next.trim_start();
+        Some(sentence)
+    })
+}
+
+fn is_register(word: &str) -> bool {
+    matches!(word.as_bytes(), [b'r' | b'R', b'0'..=b'7'])
+}
+
+/// Render reference page for a source file.
+pub fn render(title: &str, subroutines: &[Subroutine], format: Format) -> String {
+    match format {
+        Format::Markdown => render_markdown(title, subroutines),
+        Format::Html => render_html(title, subroutines),
+    }
+}
+
+fn render_markdown(title: &str, subroutines: &[Subroutine]) -> String {
+    let mut out = format!("# {title}\n");
+    for subroutine in subroutines {
+        let _ = write!(out, "\n## `{}`\n\n", subroutine.name);
+        if !subroutine.description.is_empty() {
+            let _ = write!(out, "{}\n\n", subroutine.description);
+        }
+        let _ = writeln!(
+            out,
+            "- **Address:** `x{:04X}`\n- **Size:** {} words",
+            subroutine.address, subroutine.size
+        );
+        for (heading, registers) in register_lists(subroutine) {
+            let _ = writeln!(out, "- **{heading}:** {}", registers.join(", "));
+        }
+    }
+    out
+}
+
+fn render_html(title: &str, subroutines: &[Subroutine]) -> String {
+    let title = escape_html(title);
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n</head>\n<body>\n<h1>{title}</h1>\n"
+    );
+    for subroutine in subroutines {
+        let name = escape_html(&subroutine.name);
+        let _ = writeln!(out, "<h2 id=\"{name}\"><code>{name}</code></h2>");
+        if !subroutine.description.is_empty() {
+            let _ = writeln!(out, "<p>{}</p>", escape_html(&subroutine.description));
+        }
+        let _ = writeln!(
+            out,
+            "<ul>\n<li><b>Address:</b> <code>x{:04X}</code></li>\n<li><b>Size:</b> {} words</li>",
+            subroutine.address, subroutine.size
+        );
+        for (heading, registers) in register_lists(subroutine) {
+            let _ = writeln!(out, "<li><b>{heading}:</b> {}</li>", registers.join(", "));
+        }
+        out.push_str("</ul>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+/// Non-empty register declarations, with their headings.
+fn register_lists(subroutine: &Subroutine) -> impl Iterator<Item = (&'static str, &[String])> {
+    [
+        ("Inputs", subroutine.inputs.as_slice()),
+        ("Outputs", subroutine.outputs.as_slice()),
+        ("Clobbers", subroutine.clobbers.as_slice()),
+    ]
+    .into_iter()
+    .filter(|(_, registers)| !registers.is_empty())
+}
+
+pub(crate) fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subroutine_docs() {
+        let src = ".orig x3000\njsr print\nhalt\n;; Prints R0 as decimal. Clobbers R1\nprint add r1 r0 #0\nret\n;; Inputs: R0, r2.\n;; Outputs R3\nother ret\n";
+        let subroutines = document(src).unwrap();
+        assert_eq!(
+            subroutines,
+            [
+                Subroutine {
+                    name: "print".to_string(),
+                    description: "Prints R0 as decimal.".to_string(),
+                    clobbers: vec!["R1".to_string()],
+                    address: 0x3002,
+                    size: 2,
+                    ..Default::default()
+                },
+                Subroutine {
+                    name: "other".to_string(),
+                    inputs: vec!["R0".to_string(), "R2".to_string()],
+                    outputs: vec!["R3".to_string()],
+                    address: 0x3004,
+                    size: 1,
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn render_markdown() {
+        let subroutine = Subroutine {
+            name: "print".to_string(),
+            description: "Prints R0.".to_string(),
+            clobbers: vec!["R1".to_string()],
+            address: 0x3002,
+            size: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            render("hw.asm", &[subroutine], Format::Markdown),
+            "# hw.asm\n\n## `print`\n\nPrints R0.\n\n- **Address:** `x3002`\n- **Size:** 2 words\n- **Clobbers:** R1\n"
+        );
+    }
+}
@@ -70,6 +70,31 @@ pub fn lex_stack_extension_not_enabled(instr: &str, span: Span, src: &'static st
     .with_source_code(src)
 }
 
+pub fn lex_strict_nonstandard(kind: &str, name: &str, span: Span, src: &'static str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "lex::strict_nonstandard",
+        help = format!(
+            "this {kind} is a lace extension, which other LC3 tools do not support\n\
+            remove the 'strict' feature to allow extensions"
+        ),
+        labels = vec![LabeledSpan::at(span, format!("non-standard {kind}"))],
+        "Non-standard '{name}' {kind} used in strict mode",
+    )
+    .with_source_code(src)
+}
+
+pub fn lex_bad_feature(span: Span, src: &'static str, reason: String) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "lex::bad_feature",
+        help = "available features: 'stack'",
+        labels = vec![LabeledSpan::at(span, "invalid feature")],
+        "{reason}",
+    )
+    .with_source_code(src)
+}
+
 // Preprocessor errors
 
 pub fn preproc_bad_lit(span: Span, src: &'static str, is_present: bool) -> Report {
@@ -107,6 +132,29 @@ pub fn preproc_no_str(span: Span, src: &'static str) -> Report {
     .with_source_code(src)
 }
 
+pub fn preproc_strict_no_orig(span: Span, src: &'static str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "preproc::strict_no_orig",
+        help = "strict mode requires programs to begin with a directive like `.orig x3000`",
+        labels = vec![LabeledSpan::at(span, "expected .orig")],
+        "Missing .orig directive at start of program",
+    )
+    .with_source_code(src)
+}
+
+pub fn preproc_strict_no_end(src: &'static str) -> Report {
+    let offset = src.len().saturating_sub(1);
+    miette!(
+        severity = Severity::Error,
+        code = "preproc::strict_no_end",
+        help = "strict mode requires programs to finish with an `.end` directive",
+        labels = vec![LabeledSpan::at_offset(offset, "expected .end")],
+        "Missing .end directive at end of program",
+    )
+    .with_source_code(src)
+}
+
 // Parser errors
 
 pub fn parse_duplicate_label(span: Span, src: &'static str) -> Report {
@@ -120,6 +168,28 @@ pub fn parse_duplicate_label(span: Span, src: &'static str) -> Report {
     .with_source_code(src)
 }
 
+pub fn parse_orig_twice(span: Span, src: &'static str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "parse::orig_twice",
+        help = "programs can only have one `.orig` directive, at the start of the file",
+        labels = vec![LabeledSpan::at(span, "second .orig")],
+        "Origin set twice",
+    )
+    .with_source_code(src)
+}
+
+pub fn parse_trap_native(span: Span, src: &'static str, vector: u8) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "parse::trap_native",
+        help = "choose a vector outside of x20-x27, such as x30",
+        labels = vec![LabeledSpan::at(span, "handled natively")],
+        "Trap vector x{vector:02X} already has a routine",
+    )
+    .with_source_code(src)
+}
+
 pub fn parse_generic_unexpected(src: &'static str, expected: &str, found: Token) -> Report {
     let mut help = "check the operands for this instruction".to_string();
     if found.kind == TokenKind::Label {
@@ -154,13 +224,146 @@ pub fn parse_eof(src: &'static str) -> Report {
     .with_source_code(src)
 }
 
+pub fn parse_unknown_mnemonic(span: Span, src: &'static str, suggestion: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "parse::unknown_mnemonic",
+        help = format!("did you mean `{suggestion}`?"),
+        labels = vec![LabeledSpan::at(span, "unknown instruction")],
+        "Unknown instruction or trap '{}'",
+        &src[span.as_range()],
+    )
+    .with_source_code(src)
+}
+
 pub fn parse_lit_range(span: Span, src: &'static str, bits: Bits) -> Report {
     miette!(
         severity = Severity::Error,
-        code = "parse::unexpected_token",
+        code = "parse::lit_range",
         help = format!("this instruction expects literals that can be contained in {bits} bits",),
         labels = vec![LabeledSpan::at(span, "out-of-range literal")],
         "Found numeric literal of incorrect size"
     )
     .with_source_code(src)
 }
+
+// Backpatch errors
+
+pub fn backpatch_label_not_found(
+    label: &str,
+    span: Span,
+    src: &'static str,
+    suggestion: Option<&str>,
+) -> Report {
+    let help = match suggestion {
+        Some(suggestion) => format!("did you mean `{suggestion}`?"),
+        None => "labels must be defined at the start of a line, and are case-sensitive".to_string(),
+    };
+    miette!(
+        severity = Severity::Error,
+        code = "backpatch::label_not_found",
+        help = help,
+        labels = vec![LabeledSpan::at(span, "unknown label")],
+        "Label '{label}' not found",
+    )
+    .with_source_code(src)
+}
+
+// Emit errors
+
+/// Source is not attached, as it is not available when emitting a single statement.
+pub fn emit_offset_range(line: u16, label_line: u16) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "emit::offset_range",
+        help = "this could be because of a long .stringz literal or large .blkw allocation",
+        "Difference between label and label reference is too large: at line {line}, referencing line {label_line}",
+    )
+}
+
+// Optimizer rewrites
+
+pub fn opt_nop_add(span: Span, src: &'static str) -> Report {
+    miette!(
+        severity = Severity::Advice,
+        code = "opt::nop_add",
+        help = "the following instruction sets the condition codes, so they are never read",
+        labels = vec![LabeledSpan::at(span, "removed")],
+        "Removed addition of zero",
+    )
+    .with_source_code(src)
+}
+
+pub fn opt_branch_next(span: Span, src: &'static str) -> Report {
+    miette!(
+        severity = Severity::Advice,
+        code = "opt::branch_next",
+        help = "execution continues at the next instruction whether or not the branch is taken",
+        labels = vec![LabeledSpan::at(span, "removed")],
+        "Removed branch to the next instruction",
+    )
+    .with_source_code(src)
+}
+
+pub fn opt_fold_add(span: Span, src: &'static str, replacement: &str) -> Report {
+    miette!(
+        severity = Severity::Advice,
+        code = "opt::fold_add",
+        help = format!("replaced with `{replacement}`"),
+        labels = vec![LabeledSpan::at(span, "folded")],
+        "Folded consecutive immediate additions",
+    )
+    .with_source_code(src)
+}
+
+pub fn opt_dead_store(span: Span, src: &'static str, label: &str) -> Report {
+    miette!(
+        severity = Severity::Advice,
+        code = "opt::dead_store",
+        help = format!("'{label}' is never read"),
+        labels = vec![LabeledSpan::at(span, "removed")],
+        "Removed store to a label which is never read",
+    )
+    .with_source_code(src)
+}
+
+// Suggestions
+
+/// Find the candidate most similar to `name`, for "did you mean" hints.
+///
+/// A case-insensitive match is always preferred. Otherwise, the closest candidate by edit
+/// distance is returned, if it is close enough to be a plausible typo.
+pub(crate) fn closest_match<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<&'a str> {
+    let lowercase = name.to_ascii_lowercase();
+    let max_distance = (name.len() / 3).max(1);
+    candidates
+        .into_iter()
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| {
+            let distance = edit_distance(&lowercase, &candidate.to_ascii_lowercase());
+            (distance, candidate)
+        })
+        .filter(|(distance, _)| *distance <= max_distance)
+        // Ties are broken alphabetically, so suggestions are deterministic
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
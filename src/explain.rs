@@ -0,0 +1,495 @@
+//! Extended explanations for diagnostic codes, printed by `lace explain`.
+//!
+//! Every code reported by the assembler must have an entry here. Codes are stable: once published,
+//! a code is never reused for a different problem.
+
+use miette::{miette, Result};
+
+use crate::error;
+
+/// Every diagnostic code, along with its explanation.
+pub const EXPLANATIONS: &[(&str, &str)] = &[
+    (
+        "lex::dir",
+        r#"An unknown directive was used.
+
+Directives begin with a `.` and tell the assembler how to lay out the program,
+rather than being instructions themselves. Only a fixed set of directives
+exists: `.orig`, `.end`, `.fill`, `.blkw`, `.stringz`, `.break`, `.feature` and
+`.trap`.
+
+Erroneous code example:
+
+    .origin x3000
+
+Corrected:
+
+    .orig x3000
+"#,
+    ),
+    (
+        "lex::str_lit",
+        r#"A string literal was not closed.
+
+String literals begin and end with a `"` character, and cannot span multiple
+lines. Use `\n` to include a line break within the string.
+
+Erroneous code example:
+
+    greeting .stringz "Hello, world!
+
+Corrected:
+
+    greeting .stringz "Hello, world!"
+"#,
+    ),
+    (
+        "lex::bad_lit",
+        r#"An integer literal was malformed, or does not fit in 16 bits.
+
+Decimal literals begin with `#` and range from -32,768 to 65,535. Hex literals
+begin with `x` and range from x0000 to xFFFF.
+
+Erroneous code example:
+
+    big .fill #70000
+
+Corrected:
+
+    big .fill #7000
+"#,
+    ),
+    (
+        "lex::unknown",
+        r#"A token could not be recognised.
+
+This is most often caused by a negative integer literal without a `#` prefix,
+or a character which has no meaning in LC3 assembly.
+
+Erroneous code example:
+
+    add r0, r0, -1
+
+Corrected:
+
+    add r0, r0, #-1
+"#,
+    ),
+    (
+        "lex::stack_extension_not_enabled",
+        r#"A stack instruction was used without enabling the stack extension.
+
+`push`, `pop`, `call` and `rets` are lace extensions to the LC3 instruction set,
+and must be enabled with `-f stack` or a `.feature stack` directive placed
+before they are used. While the extension is disabled, these names are still
+reserved and cannot be used as labels.
+
+Erroneous code example:
+
+    push r0
+
+Corrected:
+
+    .feature stack
+    push r0
+"#,
+    ),
+    (
+        "lex::strict_nonstandard",
+        r#"A lace extension was used in strict mode.
+
+With `-f strict`, lace only accepts programs which the reference LC3 tools can
+also assemble. The stack instructions, the `putn` and `reg` traps, the `.break`
+and `.feature` directives and `0x` hex prefixes are all rejected.
+
+Erroneous code example:
+
+    ld r0, 0x10
+    putn
+
+Corrected:
+
+    ld r0, x10
+    out
+"#,
+    ),
+    (
+        "lex::bad_feature",
+        r#"A `.feature` directive named an unknown or conflicting feature.
+
+The only feature which can be enabled from source is `stack`. Strict mode
+must be enabled on the command line, and cannot be combined with `stack`.
+
+Erroneous code example:
+
+    .feature stacks
+
+Corrected:
+
+    .feature stack
+"#,
+    ),
+    (
+        "preproc::bad_lit",
+        r#"A directive was given an invalid number.
+
+`.fill` and `.blkw` require an integer or hex literal. This is reported as a
+warning when `.blkw` is given a negative size, which is almost always a mistake.
+
+Erroneous code example:
+
+    buffer .blkw ten
+
+Corrected:
+
+    buffer .blkw #10
+"#,
+    ),
+    (
+        "preproc::stringz",
+        r#"`.stringz` was not followed by a string literal.
+
+Erroneous code example:
+
+    message .stringz hello
+
+Corrected:
+
+    message .stringz "hello"
+"#,
+    ),
+    (
+        "preproc::strict_no_orig",
+        r#"A program in strict mode did not begin with `.orig`.
+
+Reference LC3 tools require the starting address of every program to be given
+explicitly. Without strict mode, lace assumes x3000.
+
+Erroneous code example:
+
+    lea r0, msg
+
+Corrected:
+
+    .orig x3000
+    lea r0, msg
+"#,
+    ),
+    (
+        "preproc::strict_no_end",
+        r#"A program in strict mode did not finish with `.end`.
+
+Reference LC3 tools ignore everything after `.end`, and require it to be present.
+
+Erroneous code example:
+
+    .orig x3000
+    halt
+
+Corrected:
+
+    .orig x3000
+    halt
+    .end
+"#,
+    ),
+    (
+        "parse::duplicate_label",
+        r#"A label was defined more than once.
+
+Every label refers to a single address, so it can only be placed at the start of
+one line per file. Labels are case-sensitive.
+
+Erroneous code example:
+
+    loop add r0, r0, #-1
+         brp loop
+    loop halt
+
+Corrected:
+
+    loop add r0, r0, #-1
+         brp loop
+    done halt
+"#,
+    ),
+    (
+        "parse::orig_twice",
+        r#"`.orig` was used more than once.
+
+A source file assembles to a single block of memory, which starts at the address
+given by its only `.orig` directive.
+
+Erroneous code example:
+
+    .orig x3000
+    halt
+    .orig x4000
+
+Corrected:
+
+    .orig x3000
+    halt
+"#,
+    ),
+    (
+        "parse::trap_native",
+        r#"`.trap` registered a handler for a vector which already has a routine.
+
+Vectors x20-x27 are used by `getc`, `out`, `puts`, `in`, `putsp`, `halt`, `putn`
+and `reg`, which lace handles natively. Handlers can only be registered for the
+other vectors.
+
+Erroneous code example:
+
+    .trap x21, print
+    trap x21
+
+Corrected:
+
+    .trap x30, print
+    trap x30
+"#,
+    ),
+    (
+        "parse::unexpected_token",
+        r#"An operand of the wrong kind was found.
+
+Each instruction expects a fixed sequence of operands, such as registers, labels
+or literals. Decimal literals written without a `#` are read as labels.
+
+Erroneous code example:
+
+    add r0, r0, 10
+
+Corrected:
+
+    add r0, r0, #10
+"#,
+    ),
+    (
+        "parse::unexpected_eof",
+        r#"The file ended in the middle of a statement.
+
+This usually means the last instruction is missing operands.
+
+Erroneous code example:
+
+    and r0, r0
+
+Corrected:
+
+    and r0, r0, #0
+"#,
+    ),
+    (
+        "parse::unknown_mnemonic",
+        r#"A line began with a name which looks like a misspelled instruction.
+
+A line may begin with a label, but a label followed by another label, or a name
+very similar to an instruction or trap, is most likely a typo.
+
+Erroneous code example:
+
+    addd r0, r0, r1
+    haltt
+
+Corrected:
+
+    add r0, r0, r1
+    halt
+"#,
+    ),
+    (
+        "parse::lit_range",
+        r#"An immediate value does not fit in its instruction.
+
+Instructions only have a few bits to spare for literal operands. For example,
+`add` and `and` accept 5-bit signed values, from #-16 to #15. Larger values must
+be loaded from memory with `ld`.
+
+Erroneous code example:
+
+    add r0, r0, #20
+
+Corrected:
+
+    ld r1, twenty
+    add r0, r0, r1
+    ...
+    twenty .fill #20
+"#,
+    ),
+    (
+        "backpatch::label_not_found",
+        r#"A label was referenced, but never defined.
+
+Labels are defined by placing them at the start of a line, and are
+case-sensitive.
+
+Erroneous code example:
+
+    brnzp Loop
+    loop halt
+
+Corrected:
+
+    brnzp loop
+    loop halt
+"#,
+    ),
+    (
+        "emit::offset_range",
+        r#"A label is too far away from the instruction referencing it.
+
+PC-relative instructions store the distance to their label in a few bits: 9 bits
+for `br`, `ld`, `st`, `lea`, `ldi` and `sti`, which reach 256 words in either
+direction. Large `.blkw` or `.stringz` data between the two is the usual cause.
+Move the data closer, or load the full address with `ld` and use `ldr`.
+
+Erroneous code example:
+
+    ld r0, value
+    .blkw #300
+    value .fill #1
+
+Corrected:
+
+    ld r0, value
+    br skip
+    value .fill #1
+    skip .blkw #300
+"#,
+    ),
+    (
+        "opt::nop_add",
+        r#"`lace compile --optimize` removed an addition of zero.
+
+`add r1, r1, #0` leaves the register unchanged, and only sets the condition
+codes. When the next instruction sets the condition codes again, the addition
+has no effect at all. Statements which a label points at are never removed.
+
+Optimized code example:
+
+    add r1, r1, #0
+    and r0, r0, #0
+
+Equivalent to:
+
+    and r0, r0, #0
+"#,
+    ),
+    (
+        "opt::branch_next",
+        r#"`lace compile --optimize` removed a branch to the next instruction.
+
+Whether or not the branch is taken, execution continues at the same place.
+
+Optimized code example:
+
+    brz done
+    done halt
+
+Equivalent to:
+
+    done halt
+"#,
+    ),
+    (
+        "opt::fold_add",
+        r#"`lace compile --optimize` folded two immediate additions into one.
+
+When an immediate `add` is followed by another immediate `add` to the same
+register, both can be done at once, as long as the sum fits in 5 bits.
+
+Optimized code example:
+
+    add r1, r2, #5
+    add r1, r1, #-3
+
+Equivalent to:
+
+    add r1, r2, #2
+"#,
+    ),
+    (
+        "opt::dead_store",
+        r#"`lace compile --optimize` removed a store to a label which is never read.
+
+A `.fill` or `.blkw` word which is only ever written by `st` has no effect on
+the program. Stores are kept if the program uses `ldi`, `sti`, `ldr` or `str`,
+which could access any address, `puts` or `putsp`, which read past the label
+they are given, or a `.fill` holding an address within the program, which
+would no longer point at the same word.
+
+Optimized code example:
+
+    st r0, saved
+    halt
+    saved .fill #0
+
+Equivalent to:
+
+    halt
+    saved .fill #0
+"#,
+    ),
+];
+
+/// Find the explanation for a diagnostic code.
+pub fn explain(code: &str) -> Result<&'static str> {
+    let code = code.trim();
+    if let Some((_, explanation)) = EXPLANATIONS.iter().find(|(known, _)| *known == code) {
+        return Ok(explanation);
+    }
+    let suggestion = error::closest_match(code, EXPLANATIONS.iter().map(|(known, _)| *known));
+    Err(match suggestion {
+        Some(suggestion) => miette!(
+            help = format!("did you mean `{suggestion}`?"),
+            "No explanation for code '{code}'"
+        ),
+        None => miette!(
+            help = "run `lace explain` without a code to list every code",
+            "No explanation for code '{code}'"
+        ),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn every_code_explained() {
+        let src = include_str!("error.rs");
+        let codes: Vec<&str> = src
+            .split("code = \"")
+            .skip(1)
+            .map(|rest| &rest[..rest.find('"').unwrap()])
+            .collect();
+        assert!(!codes.is_empty());
+        for code in codes {
+            assert!(explain(code).is_ok(), "missing explanation for '{code}'");
+        }
+    }
+
+    #[test]
+    fn every_directive_listed() {
+        let explanation = explain("lex::dir").unwrap();
+        for dir in crate::lexer::DIRECTIVES {
+            assert!(
+                explanation.contains(&format!("`{dir}`")),
+                "missing directive '{dir}'"
+            );
+        }
+    }
+
+    #[test]
+    fn suggests_code() {
+        let report = explain("parse::unknown_mnemonc").unwrap_err();
+        assert_eq!(
+            report.help().unwrap().to_string(),
+            "did you mean `parse::unknown_mnemonic`?"
+        );
+    }
+}
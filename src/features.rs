@@ -2,42 +2,202 @@ use std::cell::RefCell;
 use std::fmt;
 use std::str::FromStr;
 
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct Features {
     stack: bool,
+    /// Reject every non-standard extension, for compatibility with reference LC3 tools
+    strict: bool,
+}
+
+/// Marks a compiled binary which requires features, and is followed by a word of feature bits.
+pub const BINARY_MAGIC: [u16; 2] = [0x4C41, 0x4345];
+
+/// Set in the feature bits word when a trap table follows it: a word counting the handlers,
+/// then the vector and address of each handler.
+const TRAP_TABLE: u16 = 0x8000;
+
+/// Vector and address of each trap handler recorded in a binary.
+pub type TrapTable = Vec<(u8, u16)>;
+
+/// Every feature is disabled until `init` or `require` is called, so source can be assembled
+/// without setting up features first.
+#[derive(Debug, Default, Clone, Copy)]
+struct State {
+    /// Features given on the command line
+    flags: Features,
+    /// Features enabled by `.feature` directives in the current source file
+    pragmas: Features,
 }
 
 thread_local! {
-    /// Must only be mutated within `set_features`
-    static FEATURES: RefCell<Option<Features>> = const { RefCell::new(None) };
+    /// Must only be mutated within `init`, `require` and `reset_pragmas`
+    static FEATURES: RefCell<Option<State>> = const { RefCell::new(None) };
 }
 
 pub fn stack() -> bool {
     with_features(|features| features.stack)
 }
 
+pub fn strict() -> bool {
+    with_features(|features| features.strict)
+}
+
 pub fn init(value: Features) {
     FEATURES.with(|features| {
         let mut features = features.borrow_mut();
         assert!(
             features.is_none(),
-            "tried to initialize features state multiple times"
+            "tried to initialize features state multiple times, or after features were required"
         );
-        *features = Some(value);
+        *features = Some(State {
+            flags: value,
+            pragmas: Features::default(),
+        });
+    });
+}
+
+/// Every feature currently enabled, either by flag or by source.
+pub fn enabled() -> Features {
+    with_features(|features| *features)
+}
+
+/// Enable a feature from within a source file, for example with `.feature stack`.
+///
+/// Stays enabled until [`reset_pragmas`] is called.
+pub fn enable(name: &str) -> Result<(), String> {
+    if name == "strict" {
+        return Err("Feature 'strict' can only be enabled with a flag".to_string());
+    }
+    require(name.parse()?)
+}
+
+/// Enable every feature in `required`, unless it conflicts with the features given by flag.
+pub fn require(required: Features) -> Result<(), String> {
+    with_state(|state| state.flags).combine(required)?;
+    FEATURES.with_borrow_mut(|state| {
+        let state = state.get_or_insert_with(State::default);
+        state.pragmas = state.pragmas.union(required);
+    });
+    Ok(())
+}
+
+/// Disable features enabled by the previous source file.
+pub fn reset_pragmas() {
+    FEATURES.with_borrow_mut(|state| {
+        if let Some(state) = state {
+            state.pragmas = Features::default();
+        }
     });
 }
 
+/// Header to prefix a compiled binary with, recording the features required to run it and the
+/// trap handlers registered with `.trap`.
+///
+/// Empty if neither are needed, so that binaries stay compatible with other LC3 tools.
+pub fn binary_header(traps: &[(u8, u16)]) -> Vec<u16> {
+    let required = Features {
+        stack: stack(),
+        // Strict mode never produces binaries which depend on lace
+        strict: false,
+    };
+    if required.is_empty() && traps.is_empty() {
+        return Vec::new();
+    }
+    let mut header = vec![BINARY_MAGIC[0], BINARY_MAGIC[1], required.bits()];
+    if !traps.is_empty() {
+        header[2] |= TRAP_TABLE;
+        header.push(traps.len() as u16);
+        for &(vector, address) in traps {
+            header.extend([vector as u16, address]);
+        }
+    }
+    header
+}
+
+/// Strip header from a compiled binary, if present, and enable every feature it requires.
+///
+/// Returns the rest of the binary, and the vector and address of each trap handler it registers.
+pub fn load_binary_header(raw: &[u16]) -> Result<(&[u16], TrapTable), String> {
+    match raw {
+        [first, second, bits, rest @ ..] if [*first, *second] == BINARY_MAGIC => {
+            let required = Features::from_bits(*bits & !TRAP_TABLE)?;
+            require(required)
+                .map_err(|reason| format!("Binary requires features '{required}': {reason}"))?;
+            if *bits & TRAP_TABLE == 0 {
+                return Ok((rest, Vec::new()));
+            }
+            let Some((&count, rest)) = rest.split_first() else {
+                return Err("Binary trap table is truncated".to_string());
+            };
+            let Some((table, rest)) = rest.split_at_checked(count as usize * 2) else {
+                return Err("Binary trap table is truncated".to_string());
+            };
+            let traps = table
+                .chunks(2)
+                .map(|pair| match u8::try_from(pair[0]) {
+                    Ok(vector) => Ok((vector, pair[1])),
+                    Err(_) => Err(format!(
+                        "Binary trap table has invalid vector 0x{:04x}",
+                        pair[0]
+                    )),
+                })
+                .collect::<Result<_, _>>()?;
+            Ok((rest, traps))
+        }
+        _ => Ok((raw, Vec::new())),
+    }
+}
+
+fn with_state<F, R>(callback: F) -> R
+where
+    F: Fn(&State) -> R,
+{
+    FEATURES.with(|features| callback(&features.borrow().unwrap_or_default()))
+}
+
 fn with_features<F, R>(callback: F) -> R
 where
     F: Fn(&Features) -> R,
 {
-    FEATURES.with(|features| {
-        let features = features.borrow();
-        let features = features.unwrap_or_else(|| {
-            panic!("tried to access features state before initialization");
-        });
-        callback(&features)
-    })
+    with_state(|state| callback(&state.flags.union(state.pragmas)))
+}
+
+impl Features {
+    fn union(self, other: Features) -> Features {
+        Features {
+            stack: self.stack || other.stack,
+            strict: self.strict || other.strict,
+        }
+    }
+
+    /// Union of both sets, unless it contains conflicting features.
+    pub fn combine(self, other: Features) -> Result<Features, String> {
+        let combined = self.union(other);
+        if combined.stack && combined.strict {
+            return Err("Feature 'stack' cannot be used with 'strict'".to_string());
+        }
+        Ok(combined)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Features::default()
+    }
+
+    /// Encode as a word, for binary headers.
+    pub fn bits(&self) -> u16 {
+        (self.stack as u16) | (self.strict as u16) << 1
+    }
+
+    /// Decode from a word, failing on unknown bits.
+    pub fn from_bits(bits: u16) -> Result<Self, String> {
+        if bits & !0b11 != 0 {
+            return Err(format!("Unknown feature bits 0x{:04x}", bits & !0b11));
+        }
+        Ok(Features {
+            stack: bits & 0b01 != 0,
+            strict: bits & 0b10 != 0,
+        })
+    }
 }
 
 impl FromStr for Features {
@@ -48,6 +208,7 @@ impl FromStr for Features {
             let value = match word {
                 "" => continue,
                 "stack" => &mut features.stack,
+                "strict" => &mut features.strict,
                 _ => return Err(format!("Unknown feature '{}'", word)),
             };
             if *value {
@@ -55,13 +216,16 @@ impl FromStr for Features {
             }
             *value = true;
         }
+        if features.stack && features.strict {
+            return Err("Feature 'stack' cannot be used with 'strict'".to_string());
+        }
         Ok(features)
     }
 }
 
 impl fmt::Display for Features {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let features = [("stack", self.stack)];
+        let features = [("stack", self.stack), ("strict", self.strict)];
         let mut has_any_feature = false;
         for (name, value) in features {
             if !value {
@@ -0,0 +1,308 @@
+//! Syntax-highlighted source, for lecture slides and autograder feedback.
+//!
+//! Source is tokenised losslessly, so highlighted output contains every character of the input,
+//! including whitespace and comments. Lines can be prefixed with their line number and address,
+//! and marked to draw attention to them.
+
+use std::fmt::Write;
+use std::str::FromStr;
+
+use clap::ValueEnum;
+use fxhash::FxHashMap;
+use miette::Result;
+
+use crate::cst::SyntaxTree;
+use crate::doc::escape_html;
+use crate::lexer::TokenKind;
+use crate::AsmParser;
+
+/// Output format of highlighted source.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Terminal escape codes.
+    #[default]
+    Ansi,
+    /// Standalone HTML page.
+    Html,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub format: Format,
+    pub line_numbers: bool,
+    /// Show the address of the first statement on each line. Source must parse without errors.
+    pub addresses: bool,
+    /// Lines to mark, such as the line where a test failed.
+    pub marked: Vec<LineRange>,
+}
+
+/// Inclusive range of one-based line numbers, written as `3` or `5-7`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl LineRange {
+    fn contains(&self, line: usize) -> bool {
+        self.start <= line && line <= self.end
+    }
+}
+
+impl FromStr for LineRange {
+    type Err = String;
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let parse = |number: &str| match number.trim().parse::<usize>() {
+            Ok(number) if number > 0 => Ok(number),
+            _ => Err(format!("Invalid line number '{}'", number.trim())),
+        };
+        let (start, end) = match string.split_once('-') {
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            None => (parse(string)?, parse(string)?),
+        };
+        if start > end {
+            return Err(format!("Line range '{string}' is backwards"));
+        }
+        Ok(LineRange { start, end })
+    }
+}
+
+/// Kind of highlighted token.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Class {
+    Mnemonic,
+    Register,
+    Literal,
+    Label,
+    Directive,
+    Comment,
+}
+
+impl Class {
+    fn of(kind: TokenKind) -> Option<Self> {
+        match kind {
+            TokenKind::Instr(_) | TokenKind::Trap(_) => Some(Class::Mnemonic),
+            TokenKind::Reg(_) => Some(Class::Register),
+            TokenKind::Lit(_) | TokenKind::Byte(_) => Some(Class::Literal),
+            TokenKind::Label => Some(Class::Label),
+            TokenKind::Dir(_) | TokenKind::Breakpoint => Some(Class::Directive),
+            TokenKind::Comment | TokenKind::DocComment => Some(Class::Comment),
+            TokenKind::Whitespace | TokenKind::Eof => None,
+        }
+    }
+
+    fn ansi(&self) -> &'static str {
+        match self {
+            Class::Mnemonic => "\x1b[1;34m",
+            Class::Register => "\x1b[33m",
+            Class::Literal => "\x1b[32m",
+            Class::Label => "\x1b[36m",
+            Class::Directive => "\x1b[35m",
+            Class::Comment => "\x1b[90m",
+        }
+    }
+
+    fn css(&self) -> &'static str {
+        match self {
+            Class::Mnemonic => "mnemonic",
+            Class::Register => "register",
+            Class::Literal => "literal",
+            Class::Label => "label",
+            Class::Directive => "directive",
+            Class::Comment => "comment",
+        }
+    }
+}
+
+const ANSI_RESET: &str = "\x1b[0m";
+const ANSI_MARK: &str = "\x1b[1;31m";
+
+const HTML_STYLE: &str = "pre.lace { background: #fdfdfd; padding: 0.5em; }
+.lace .gutter { color: #999; user-select: none; }
+.lace .marked { background: #fff3b0; display: inline-block; width: 100%; }
+.lace .mnemonic { color: #0044cc; font-weight: bold; }
+.lace .register { color: #a05a00; }
+.lace .literal { color: #1a7f37; }
+.lace .label { color: #007c89; }
+.lace .directive { color: #8250df; }
+.lace .comment { color: #6e7781; font-style: italic; }";
+
+/// Highlight source, tokenised with the features currently enabled.
+///
+/// Symbol table is populated if addresses are shown.
+pub fn highlight(src: &'static str, options: &Options) -> Result<String> {
+    let tree = SyntaxTree::parse(src)?;
+    let addresses = if options.addresses {
+        line_addresses(&tree)?
+    } else {
+        FxHashMap::default()
+    };
+
+    // Highlight each line separately, so gutters can be added
+    let mut lines: Vec<String> = tree
+        .lines()
+        .iter()
+        .map(|line| {
+            let mut out = String::new();
+            for tok in line.tokens() {
+                push_token(
+                    &mut out,
+                    tree.text(tok),
+                    Class::of(tok.kind),
+                    options.format,
+                );
+            }
+            out
+        })
+        .collect();
+    let mut trailing = tree.trailing().split('\n');
+    if let (Some(last), Some(rest)) = (lines.last_mut(), trailing.next()) {
+        push_token(last, rest, None, options.format);
+    }
+    for rest in trailing {
+        let mut out = String::new();
+        push_token(&mut out, rest, None, options.format);
+        lines.push(out);
+    }
+    if src.ends_with('\n') && lines.last().is_some_and(String::is_empty) {
+        lines.pop();
+    }
+
+    let width = lines.len().to_string().len();
+    let mut out = String::new();
+    if options.format == Format::Html {
+        let _ = write!(
+            out,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<style>\n{HTML_STYLE}\n</style>\n</head>\n<body>\n<pre class=\"lace\">"
+        );
+    }
+    for (i, line) in lines.iter().enumerate() {
+        let marked = options.marked.iter().any(|range| range.contains(i + 1));
+        let mut gutter = String::new();
+        if options.line_numbers {
+            let _ = write!(gutter, "{:>width$} ", i + 1);
+        }
+        if options.addresses {
+            match addresses.get(&i) {
+                Some(address) => {
+                    let _ = write!(gutter, "x{address:04X} ");
+                }
+                None => gutter.push_str("      "),
+            }
+        }
+        match options.format {
+            Format::Ansi => {
+                if marked {
+                    let _ = write!(out, "{ANSI_MARK}>{ANSI_RESET} ");
+                } else if !options.marked.is_empty() {
+                    out.push_str("  ");
+                }
+                if !gutter.is_empty() {
+                    let _ = write!(out, "{}{gutter}{ANSI_RESET}", Class::Comment.ansi());
+                }
+                let _ = writeln!(out, "{line}");
+            }
+            Format::Html => {
+                if marked {
+                    out.push_str("<span class=\"marked\">");
+                }
+                if !gutter.is_empty() {
+                    let _ = write!(out, "<span class=\"gutter\">{gutter}</span>");
+                }
+                out.push_str(line);
+                if marked {
+                    out.push_str("</span>");
+                }
+                out.push('\n');
+            }
+        }
+    }
+    if options.format == Format::Html {
+        out.push_str("</pre>\n</body>\n</html>\n");
+    }
+    Ok(out)
+}
+
+fn push_token(out: &mut String, text: &str, class: Option<Class>, format: Format) {
+    match (format, class) {
+        (Format::Ansi, Some(class)) => {
+            let _ = write!(out, "{}{text}{ANSI_RESET}", class.ansi());
+        }
+        (Format::Ansi, None) => out.push_str(text),
+        (Format::Html, Some(class)) => {
+            let _ = write!(
+                out,
+                "<span class=\"{}\">{}</span>",
+                class.css(),
+                escape_html(text)
+            );
+        }
+        (Format::Html, None) => out.push_str(&escape_html(text)),
+    }
+}
+
+/// Address of the first statement on each line, by zero-based line index.
+fn line_addresses(tree: &SyntaxTree) -> Result<FxHashMap<usize, u16>> {
+    let air = AsmParser::from_tree(tree)?.parse()?;
+    let orig = air.orig().unwrap_or(0x3000);
+    let mut addresses = FxHashMap::default();
+    for stmt in &air {
+        if let Some(line) = tree.line_at(stmt.span.offs()) {
+            addresses
+                .entry(line.number())
+                .or_insert(orig.wrapping_add(stmt.line).wrapping_sub(1));
+        }
+    }
+    Ok(addresses)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn line_ranges() {
+        assert_eq!("3".parse(), Ok(LineRange { start: 3, end: 3 }));
+        assert_eq!("5-7".parse(), Ok(LineRange { start: 5, end: 7 }));
+        assert!("7-5".parse::<LineRange>().is_err());
+        assert!("0".parse::<LineRange>().is_err());
+    }
+
+    #[test]
+    fn highlight_html() {
+        let options = Options {
+            format: Format::Html,
+            line_numbers: true,
+            addresses: true,
+            marked: vec![LineRange { start: 2, end: 2 }],
+        };
+        let out = highlight(".orig x3000\nadd r0, r0, #1 ; a < b\n", &options).unwrap();
+        crate::reset_state();
+        assert!(out.contains(
+            "<span class=\"marked\"><span class=\"gutter\">2 x3000 </span><span class=\"mnemonic\">add</span> <span class=\"register\">r0</span>, "
+        ));
+        assert!(out.contains("<span class=\"comment\">; a &lt; b</span></span>\n</pre>"));
+        assert!(out.contains(
+            "<span class=\"gutter\">1       </span><span class=\"directive\">.orig</span>"
+        ));
+    }
+
+    #[test]
+    fn highlight_ansi_is_lossless() {
+        let src = "lea r0, msg ; greet\nputs\nhalt\nmsg .stringz \"hi\"\n.end\ntrailing text\n";
+        let out = highlight(src, &Options::default()).unwrap();
+        let mut plain = out.clone();
+        for class in [
+            Class::Mnemonic,
+            Class::Register,
+            Class::Literal,
+            Class::Label,
+            Class::Directive,
+            Class::Comment,
+        ] {
+            plain = plain.replace(class.ansi(), "");
+        }
+        assert_eq!(plain.replace(ANSI_RESET, ""), src);
+        assert!(out.contains("\x1b[36mmsg\x1b[0m"));
+    }
+}
@@ -0,0 +1,508 @@
+//! Incremental reassembly, for tools which assemble the same file after every edit.
+//!
+//! Each source line is lexed and parsed on its own, and cached by its text. After an edit, only
+//! lines which changed are parsed again, and only statements referring to a label which moved are
+//! backpatched again. Anything which cannot be assembled line by line, such as a statement split
+//! over several lines or any error at all, falls back to assembling the whole file, so results
+//! always match a full assembly.
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use fxhash::FxHashMap;
+use miette::{Report, Severity};
+
+use crate::air::{AirStmt, AsmLine};
+use crate::cst::SyntaxTree;
+use crate::diagnostic::{self, Diagnostic};
+use crate::features::{self, Features};
+use crate::lexer::TokenKind;
+use crate::symbol::{
+    reset_state, with_symbol_table, DirKind, Label, Span, SrcOffset, StaticSource,
+};
+use crate::AsmParser;
+
+/// Assembles a file repeatedly, reusing work from previous versions of it.
+#[derive(Default)]
+pub struct Assembler {
+    /// Lines of the previous version, by text.
+    lines: FxHashMap<String, Rc<CachedLine>>,
+    stats: Stats,
+}
+
+/// Work done by the most recent call to [`Assembler::assemble`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Lines parsed, because they were not cached.
+    pub parsed: usize,
+    /// Lines reused from the cache.
+    pub reused: usize,
+    /// Statements backpatched, because a label they refer to was added or moved.
+    pub patched: usize,
+    /// Whether the whole file was assembled from scratch.
+    pub full: bool,
+}
+
+/// A file which assembled without errors.
+pub struct Assembly {
+    pub orig: u16,
+    pub statements: Vec<Statement>,
+    /// Absolute address of each label.
+    pub addresses: FxHashMap<String, u16>,
+    /// Every label definition and reference, in source order.
+    pub labels: Vec<LabelToken>,
+    /// Names of labels which are the target of `JSR` or `CALL`.
+    pub subroutines: Vec<String>,
+    /// Non-fatal diagnostics.
+    pub warnings: Vec<Diagnostic>,
+}
+
+pub struct Statement {
+    pub span: Span,
+    pub address: u16,
+    pub word: u16,
+}
+
+#[derive(Clone)]
+pub struct LabelToken {
+    pub name: String,
+    pub span: Span,
+    pub is_definition: bool,
+}
+
+/// Parsed line, with spans relative to the start of the line and label references unfilled.
+struct CachedLine {
+    /// Features enabled before and after the line, as `.feature` changes how later lines are lexed
+    before: Features,
+    after: Features,
+    orig: Option<u16>,
+    /// Prefix labels, with their line relative to the first statement of this line
+    definitions: Vec<(String, u16)>,
+    labels: Vec<LabelToken>,
+    stmts: Vec<CachedStmt>,
+    /// Whether the line contains `.end`
+    end: bool,
+}
+
+struct CachedStmt {
+    stmt: AirStmt,
+    span: Span,
+    /// Known when parsing, unless the statement refers to a label by name
+    word: Option<u16>,
+    /// Line, label line and word from the last time the statement was backpatched
+    patched: Cell<Option<(u16, u16, u16)>>,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Assemble the next version of a file, returning every diagnostic if it has errors.
+    pub fn assemble(&mut self, text: &str) -> Result<Assembly, Vec<Diagnostic>> {
+        self.stats = Stats::default();
+        reset_state();
+        // Strict mode checks the layout of the whole file
+        let assembly = if features::strict() {
+            None
+        } else {
+            self.assemble_lines(text)
+        };
+        reset_state();
+        match assembly {
+            Some(assembly) => Ok(assembly),
+            None => {
+                self.stats = Stats {
+                    full: true,
+                    ..Stats::default()
+                };
+                assemble_full(text)
+            }
+        }
+    }
+
+    /// Work done by the most recent assembly.
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    fn assemble_lines(&mut self, text: &str) -> Option<Assembly> {
+        let mut lines: FxHashMap<String, Rc<CachedLine>> = FxHashMap::default();
+        let mut used = Vec::new();
+        let mut offset = 0;
+        for line in text.split_inclusive('\n') {
+            let before = features::enabled();
+            let cached = match lines.get(line) {
+                Some(cached) if cached.before == before => Some(Rc::clone(cached)),
+                _ => match self.lines.remove_entry(line) {
+                    Some((text, cached)) if cached.before == before => {
+                        lines.insert(text, Rc::clone(&cached));
+                        Some(cached)
+                    }
+                    _ => None,
+                },
+            };
+            let cached = match cached {
+                Some(cached) => {
+                    features::require(cached.after).ok()?;
+                    self.stats.reused += 1;
+                    cached
+                }
+                None => {
+                    let cached = Rc::new(CachedLine::parse(line)?);
+                    lines.insert(line.to_string(), Rc::clone(&cached));
+                    self.stats.parsed += 1;
+                    cached
+                }
+            };
+            let end = cached.end;
+            used.push((offset, cached));
+            offset += line.len();
+            if end {
+                break;
+            }
+        }
+        self.lines = lines;
+
+        // Lay out lines to find label addresses
+        let mut orig = None;
+        let mut definitions: Vec<(&str, u16)> = Vec::new();
+        let mut label_lines: FxHashMap<&str, u16> = FxHashMap::default();
+        let mut len: u16 = 0;
+        for (_, cached) in &used {
+            if let Some(value) = cached.orig {
+                if orig.replace(value).is_some() {
+                    return None;
+                }
+            }
+            for (name, line) in &cached.definitions {
+                if label_lines.insert(name, len + line).is_some() {
+                    return None;
+                }
+                definitions.push((name, len + line));
+            }
+            len = len.checked_add(cached.stmts.len() as u16)?;
+        }
+        // Label without a statement to refer to
+        if label_lines.values().any(|line| *line > len) {
+            return None;
+        }
+
+        let orig = orig.unwrap_or(0x3000);
+        let address = |line: u16| orig.wrapping_add(line).wrapping_sub(1);
+        let mut statements = Vec::with_capacity(len as usize);
+        let mut labels = Vec::new();
+        let mut subroutines: Vec<String> = Vec::new();
+        let mut line: u16 = 0;
+        for (offset, cached) in &used {
+            labels.extend(cached.labels.iter().map(|label| LabelToken {
+                span: shift(label.span, *offset),
+                ..label.clone()
+            }));
+            for (i, stmt) in cached.stmts.iter().enumerate() {
+                line += 1;
+                let target = match stmt.stmt.label() {
+                    Some(Label::Unfilled(name, _)) => Some(*label_lines.get(name.as_str())?),
+                    // Relative to the first statement of the cached line
+                    Some(Label::Ref(target)) => Some(target.wrapping_add(line - 1 - i as u16)),
+                    None => None,
+                };
+                let word = match (stmt.word, stmt.patched.get(), target) {
+                    (Some(word), _, _) => word,
+                    (None, Some((prev_line, prev_target, word)), Some(target))
+                        if prev_line == line && prev_target == target =>
+                    {
+                        word
+                    }
+                    (None, _, target) => {
+                        let target = target?;
+                        let mut patched = stmt.stmt.clone();
+                        *patched.label_mut()? = Label::Ref(target);
+                        let word = AsmLine::new(line, patched, stmt.span).emit().ok()?;
+                        stmt.patched.set(Some((line, target, word)));
+                        self.stats.patched += 1;
+                        word
+                    }
+                };
+                statements.push(Statement {
+                    span: shift(stmt.span, *offset),
+                    address: address(line),
+                    word,
+                });
+
+                if matches!(stmt.stmt, AirStmt::JumbSub { .. } | AirStmt::Call { .. }) {
+                    let name = definitions
+                        .iter()
+                        .find(|(_, line)| Some(*line) == target)
+                        .map(|(name, _)| name.to_string());
+                    if let Some(name) = name.filter(|name| !subroutines.contains(name)) {
+                        subroutines.push(name);
+                    }
+                }
+            }
+        }
+
+        Some(Assembly {
+            orig,
+            statements,
+            addresses: definitions
+                .iter()
+                .map(|(name, line)| (name.to_string(), address(*line)))
+                .collect(),
+            labels,
+            subroutines,
+            warnings: Vec::new(),
+        })
+    }
+}
+
+impl CachedLine {
+    fn parse(line: &str) -> Option<Self> {
+        let mut source = StaticSource::new(line.to_string());
+        let cached = Self::parse_src(source.src());
+        // All reports referencing source have been dropped
+        source.reclaim();
+        cached
+    }
+
+    fn parse_src(src: &'static str) -> Option<Self> {
+        let before = features::enabled();
+        let tree = SyntaxTree::parse(src).ok()?;
+        // Directive operands on the following line
+        let last = tree.significant_tokens().last();
+        if last.is_some_and(|tok| tok.kind == TokenKind::Dir(DirKind::Feature)) {
+            return None;
+        }
+        let end = tree
+            .significant_tokens()
+            .any(|tok| tok.kind == TokenKind::Dir(DirKind::End));
+
+        let parser = AsmParser::from_tree(&tree).ok()?;
+        // Warnings are only reported by full assembly
+        if !parser.warnings().is_empty() {
+            return None;
+        }
+        let (air, definitions) = parser.parse_fragment().ok()?;
+        // Trap handlers are only checked by full assembly
+        if !air.traps.is_empty() {
+            return None;
+        }
+
+        let mut stmts = Vec::with_capacity(air.len());
+        for asm in &air {
+            let word = match asm.stmt.label() {
+                Some(Label::Unfilled(..)) => None,
+                // Literal offsets do not depend on the line
+                _ => Some(asm.emit().ok()?),
+            };
+            stmts.push(CachedStmt {
+                stmt: asm.stmt.clone(),
+                span: asm.span,
+                word,
+                patched: Cell::new(None),
+            });
+        }
+
+        Some(CachedLine {
+            before,
+            after: features::enabled(),
+            orig: air.orig(),
+            definitions,
+            labels: label_tokens(&tree),
+            stmts,
+            end,
+        })
+    }
+}
+
+/// Assemble a whole file from scratch.
+fn assemble_full(text: &str) -> Result<Assembly, Vec<Diagnostic>> {
+    let diagnostics = diagnostic::check(text);
+    if diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.severity == Severity::Error)
+    {
+        return Err(diagnostics);
+    }
+
+    let mut source = StaticSource::new(text.to_string());
+    let assembly = assemble_src(source.src(), diagnostics).map_err(|report| vec![(&report).into()]);
+    reset_state();
+    // All reports referencing source have been dropped
+    source.reclaim();
+    assembly
+}
+
+fn assemble_src(src: &'static str, warnings: Vec<Diagnostic>) -> Result<Assembly, Report> {
+    let tree = SyntaxTree::parse(src)?;
+    let mut air = AsmParser::from_tree(&tree)?.parse()?;
+    air.backpatch()?;
+
+    let orig = air.orig().unwrap_or(0x3000);
+    let address = |line: u16| orig.wrapping_add(line).wrapping_sub(1);
+    let mut statements = Vec::with_capacity(air.len());
+    for stmt in &air {
+        statements.push(Statement {
+            span: stmt.span,
+            address: address(stmt.line),
+            word: stmt.emit()?,
+        });
+    }
+    let lines = with_symbol_table(|sym| sym.clone());
+    let labels = label_tokens(&tree);
+
+    let mut subroutines: Vec<String> = Vec::new();
+    for stmt in &air {
+        let target = match &stmt.stmt {
+            AirStmt::JumbSub {
+                dest_label: Label::Ref(line),
+            }
+            | AirStmt::Call {
+                dest_label: Label::Ref(line),
+            } => *line,
+            _ => continue,
+        };
+        // First definition in source order
+        let name = labels
+            .iter()
+            .filter(|label| label.is_definition)
+            .find(|label| lines.get(&label.name) == Some(&target));
+        if let Some(label) = name.filter(|label| !subroutines.contains(&label.name)) {
+            subroutines.push(label.name.clone());
+        }
+    }
+
+    Ok(Assembly {
+        orig,
+        statements,
+        addresses: lines
+            .into_iter()
+            .map(|(name, line)| (name, address(line)))
+            .collect(),
+        labels,
+        subroutines,
+        warnings,
+    })
+}
+
+/// Every label definition and reference in a syntax tree.
+pub(crate) fn label_tokens(tree: &SyntaxTree) -> Vec<LabelToken> {
+    let mut labels = Vec::new();
+    for line in tree.lines() {
+        let definition = line.label();
+        for tok in line.significant_tokens() {
+            if tok.kind == TokenKind::Label {
+                labels.push(LabelToken {
+                    name: tree.text(&tok).to_string(),
+                    span: tok.span,
+                    is_definition: Some(tok) == definition,
+                });
+            }
+        }
+    }
+    labels
+}
+
+fn shift(span: Span, offset: usize) -> Span {
+    Span::new(SrcOffset(span.offs() + offset), span.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SRC: &str =
+        ".orig x3000\nlea r0, msg\njsr print\nhalt\nprint puts\nret\nmsg .stringz \"hi\"\n";
+
+    fn words(assembly: &Assembly) -> Vec<u16> {
+        assembly.statements.iter().map(|stmt| stmt.word).collect()
+    }
+
+    /// Words from assembling the whole file from scratch.
+    fn expected(text: &str) -> Vec<u16> {
+        words(&assemble_full(text).ok().unwrap())
+    }
+
+    #[test]
+    fn matches_full_assembly() {
+        let mut assembler = Assembler::new();
+        let assembly = assembler.assemble(SRC).ok().unwrap();
+        assert!(!assembler.stats().full);
+        assert_eq!(words(&assembly), expected(SRC));
+        assert_eq!(assembly.addresses["msg"], 0x3005);
+        assert_eq!(assembly.subroutines, ["print"]);
+        assert_eq!(assembly.statements[1].span.offs(), SRC.find("jsr").unwrap());
+    }
+
+    #[test]
+    fn reuses_unchanged_lines() {
+        let mut assembler = Assembler::new();
+        assembler.assemble(SRC).ok().unwrap();
+
+        // Changing a line without moving labels parses and patches nothing else
+        let edited = SRC.replace("halt\n", "getc\n");
+        let assembly = assembler.assemble(&edited).ok().unwrap();
+        let stats = assembler.stats();
+        assert_eq!((stats.parsed, stats.reused, stats.patched), (1, 6, 0));
+        assert_eq!(words(&assembly), expected(&edited));
+
+        // Inserting a line moves later labels, so only references to them are patched
+        let edited = edited.replace("ret\nmsg", "ret\nadd r0, r0, #1\nmsg");
+        let assembly = assembler.assemble(&edited).ok().unwrap();
+        let stats = assembler.stats();
+        assert_eq!((stats.parsed, stats.patched), (1, 1));
+        assert_eq!(words(&assembly), expected(&edited));
+    }
+
+    #[test]
+    fn falls_back_to_full_assembly() {
+        let mut assembler = Assembler::new();
+
+        // Operands split over lines
+        let src = "add r0, r0,\n#1\nhalt\n";
+        assert_eq!(
+            words(&assembler.assemble(src).ok().unwrap()),
+            [0x1021, 0xF025]
+        );
+        assert!(assembler.stats().full);
+
+        let diagnostics = assembler.assemble("loop halt\nloop halt\n").err().unwrap();
+        assert_eq!(
+            diagnostics[0].code.as_deref(),
+            Some("parse::duplicate_label")
+        );
+        let diagnostics = assembler.assemble("br nowhere\n").err().unwrap();
+        assert_eq!(
+            diagnostics[0].code.as_deref(),
+            Some("backpatch::label_not_found")
+        );
+        // Trailing label
+        assert!(assembler.assemble("halt\nend\n").is_err());
+    }
+
+    #[test]
+    fn example_programs() {
+        for src in [
+            include_str!("../tests/files/hw.asm"),
+            include_str!("../tests/files/feature_pragma.asm"),
+        ] {
+            let mut assembler = Assembler::new();
+            let assembly = assembler.assemble(src).ok().unwrap();
+            assert!(!assembler.stats().full);
+            assert_eq!(words(&assembly), expected(src));
+        }
+    }
+
+    #[test]
+    fn feature_pragmas() {
+        let mut assembler = Assembler::new();
+        let src = ".feature stack\npush r0\nhalt\n";
+        assembler.assemble(src).ok().unwrap();
+        let assembly = assembler.assemble(src).ok().unwrap();
+        assert_eq!(assembler.stats().reused, 3);
+        assert_eq!(words(&assembly), expected(src));
+        assert!(!features::stack());
+        // Same line lexed without the feature enabled
+        assert!(assembler.assemble("push r0\n").is_err());
+    }
+}
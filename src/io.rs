@@ -0,0 +1,337 @@
+//! Console input and output backends for running programs.
+//!
+//! Traps and the keyboard and display device registers go through an [`Io`] backend, so programs
+//! can be driven and their output captured without spawning `lace`.
+//!
+//! ```
+//! use lace::io::BufferIo;
+//! use lace::RunEnvironment;
+//!
+//! // getc; out; halt
+//! let mut program = RunEnvironment::from_raw(&[0x3000, 0xF020, 0xF021, 0xF025]).unwrap();
+//! let io = BufferIo::new("a");
+//! program.set_io(io.clone());
+//! program.run().unwrap();
+//! assert_eq!(io.output_string(), "a");
+//! ```
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{self, stdin, stdout, IsTerminal, Read, Write},
+    rc::Rc,
+    sync::mpsc::TryRecvError,
+};
+
+use crate::{output::Output, term};
+
+/// Console of a running program.
+pub trait Io {
+    /// Read one byte of input, waiting until it is available.
+    ///
+    /// Returns `None` at end of input.
+    fn read_byte(&mut self) -> Option<u8>;
+
+    /// Read one byte of input, without waiting.
+    ///
+    /// Returns [`TryRecvError::Empty`] if no input is ready yet, and [`TryRecvError::Disconnected`]
+    /// at end of input.
+    fn poll_byte(&mut self) -> Result<u8, TryRecvError> {
+        self.read_byte().ok_or(TryRecvError::Disconnected)
+    }
+
+    /// Write one byte of output.
+    fn write_byte(&mut self, byte: u8);
+
+    /// Write a string of output, such as a number printed by the `putn` trap.
+    fn write_str(&mut self, string: &str) {
+        for byte in string.bytes() {
+            self.write_byte(byte);
+        }
+    }
+
+    /// Flush any buffered output, once a trap has finished printing.
+    fn flush(&mut self) {}
+}
+
+/// Standard input and output, or an interactive terminal. Used by default.
+#[derive(Default)]
+pub struct Stdio {
+    /// Whether the debugger reads its commands from stdin
+    debugger: bool,
+}
+
+impl Stdio {
+    /// Standard input and output, shared with the debugger's commands.
+    pub(crate) fn with_debugger() -> Self {
+        Self { debugger: true }
+    }
+}
+
+impl Io for Stdio {
+    fn read_byte(&mut self) -> Option<u8> {
+        let stdin = stdin();
+        if stdin.is_terminal() {
+            // Terminal input never ends
+            // Non-ASCII characters are read as one non-ASCII byte
+            Some(term::read_byte().unwrap_or(0xFF))
+        } else {
+            read_byte_stdin(stdin)
+        }
+    }
+
+    /// Terminal keys are read without waiting, like `GETC`, so no `Enter` is needed.
+    ///
+    /// Piped input is read like `GETC`, as it is either ready or ended. While debugging, piped
+    /// input holds the debugger's commands, so no key is ever ready.
+    fn poll_byte(&mut self) -> Result<u8, TryRecvError> {
+        let stdin = stdin();
+        if stdin.is_terminal() {
+            term::poll_byte().ok_or(TryRecvError::Empty)
+        } else if self.debugger {
+            Err(TryRecvError::Empty)
+        } else {
+            read_byte_stdin(stdin).ok_or(TryRecvError::Disconnected)
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        Output::Normal.print(byte as char);
+    }
+
+    fn write_str(&mut self, string: &str) {
+        Output::Normal.print(string);
+    }
+
+    fn flush(&mut self) {
+        stdout().flush().unwrap();
+    }
+}
+
+/// Read one byte from stdin.
+///
+/// Returns `None` on `UnexpectedEof`.
+/// Panics on any other error.
+fn read_byte_stdin(mut stdin: io::Stdin) -> Option<u8> {
+    let mut buf = [0; 1];
+    if let Err(err) = stdin.read_exact(&mut buf) {
+        if let io::ErrorKind::UnexpectedEof = err.kind() {
+            return None;
+        } else {
+            panic!("failed to read character from stdin: {:?}", err)
+        }
+    }
+    Some(buf[0])
+}
+
+/// In-memory input and output.
+///
+/// Clones share the same buffers, so output can be read after giving a clone to the program.
+#[derive(Clone, Default)]
+pub struct BufferIo {
+    buffers: Rc<RefCell<Buffers>>,
+}
+
+#[derive(Default)]
+struct Buffers {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+impl BufferIo {
+    /// Buffer with `input`, which ends once it has all been read.
+    pub fn new(input: impl AsRef<[u8]>) -> Self {
+        let io = Self::default();
+        io.push_input(input);
+        io
+    }
+
+    /// Add more input after any unread input.
+    pub fn push_input(&self, input: impl AsRef<[u8]>) {
+        let mut buffers = self.buffers.borrow_mut();
+        buffers.input.extend(input.as_ref());
+    }
+
+    /// Everything written by the program so far.
+    pub fn output(&self) -> Vec<u8> {
+        self.buffers.borrow().output.clone()
+    }
+
+    /// Everything written by the program so far, with invalid UTF-8 replaced.
+    pub fn output_string(&self) -> String {
+        String::from_utf8_lossy(&self.buffers.borrow().output).into_owned()
+    }
+
+    /// Remove and return everything written by the program so far.
+    pub fn take_output(&self) -> Vec<u8> {
+        std::mem::take(&mut self.buffers.borrow_mut().output)
+    }
+}
+
+impl Io for BufferIo {
+    fn read_byte(&mut self) -> Option<u8> {
+        self.buffers.borrow_mut().input.pop_front()
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.buffers.borrow_mut().output.push(byte);
+    }
+}
+
+/// Input which is typed once the program prints a prompt, like a user at a console.
+///
+/// Each line of the script is provided as input once its prompt has been printed, after the prompt
+/// of the previous line. While waiting for a prompt, the keyboard has no key ready, and reading input
+/// ends the input stream, as the program would otherwise wait forever.
+///
+/// Output is captured like [`BufferIo`], and clones share the same script.
+///
+/// ```
+/// # use lace::io::{Io, ScriptedIo};
+/// let mut io = ScriptedIo::new().after("Name: ", "Bob\n");
+/// assert_eq!(io.read_byte(), None);
+/// io.write_str("Name: ");
+/// assert_eq!(io.read_byte(), Some(b'B'));
+/// ```
+#[derive(Clone, Default)]
+pub struct ScriptedIo {
+    script: Rc<RefCell<Script>>,
+}
+
+#[derive(Default)]
+struct Script {
+    /// Prompts which have not been printed yet, each with its input
+    lines: VecDeque<(Vec<u8>, Vec<u8>)>,
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+    /// Start of output which has not been matched against a prompt
+    unmatched: usize,
+}
+
+impl ScriptedIo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Provide `input` straight away, or once the previous prompt has been printed.
+    pub fn input(self, input: impl AsRef<[u8]>) -> Self {
+        self.after("", input)
+    }
+
+    /// Provide `input` once `prompt` has been printed.
+    pub fn after(self, prompt: impl AsRef<[u8]>, input: impl AsRef<[u8]>) -> Self {
+        self.script
+            .borrow_mut()
+            .lines
+            .push_back((prompt.as_ref().to_vec(), input.as_ref().to_vec()));
+        self
+    }
+
+    /// Whether every line of the script has been provided as input.
+    pub fn is_finished(&self) -> bool {
+        let mut script = self.script.borrow_mut();
+        script.advance();
+        script.lines.is_empty()
+    }
+
+    /// Everything written by the program so far.
+    pub fn output(&self) -> Vec<u8> {
+        self.script.borrow().output.clone()
+    }
+
+    /// Everything written by the program so far, with invalid UTF-8 replaced.
+    pub fn output_string(&self) -> String {
+        String::from_utf8_lossy(&self.script.borrow().output).into_owned()
+    }
+}
+
+impl Script {
+    /// Provide input for every prompt which has been printed.
+    fn advance(&mut self) {
+        while let Some((prompt, _)) = self.lines.front() {
+            let unmatched = &self.output[self.unmatched..];
+            let end = if prompt.is_empty() {
+                0
+            } else {
+                match unmatched
+                    .windows(prompt.len())
+                    .position(|window| window == prompt.as_slice())
+                {
+                    Some(start) => start + prompt.len(),
+                    None => return,
+                }
+            };
+            self.unmatched += end;
+            let (_, input) = self.lines.pop_front().unwrap();
+            self.input.extend(input);
+        }
+    }
+}
+
+impl Io for ScriptedIo {
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut script = self.script.borrow_mut();
+        script.advance();
+        script.input.pop_front()
+    }
+
+    fn poll_byte(&mut self) -> Result<u8, TryRecvError> {
+        let mut script = self.script.borrow_mut();
+        script.advance();
+        match script.input.pop_front() {
+            Some(byte) => Ok(byte),
+            None if script.lines.is_empty() => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.script.borrow_mut().output.push(byte);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buffer_io() {
+        let mut io = BufferIo::new("ab");
+        let handle = io.clone();
+        assert_eq!(io.poll_byte(), Ok(b'a'));
+        assert_eq!(io.read_byte(), Some(b'b'));
+        assert_eq!(io.read_byte(), None);
+        assert_eq!(io.poll_byte(), Err(TryRecvError::Disconnected));
+        handle.push_input("c");
+        assert_eq!(io.read_byte(), Some(b'c'));
+
+        io.write_str("Hi");
+        io.write_byte(b'!');
+        assert_eq!(handle.output_string(), "Hi!");
+        assert_eq!(handle.take_output(), b"Hi!");
+        assert!(io.output().is_empty());
+    }
+
+    #[test]
+    fn scripted_io() {
+        let mut io = ScriptedIo::new()
+            .input("1")
+            .after("> ", "2")
+            .after("> ", "3");
+        assert_eq!(io.read_byte(), Some(b'1'));
+        assert_eq!(io.poll_byte(), Err(TryRecvError::Empty));
+        assert_eq!(io.read_byte(), None);
+
+        // Each prompt is only matched once
+        io.write_str("> ");
+        assert_eq!(io.poll_byte(), Ok(b'2'));
+        assert_eq!(io.poll_byte(), Err(TryRecvError::Empty));
+        io.write_str(">");
+        assert_eq!(io.poll_byte(), Err(TryRecvError::Empty));
+        io.write_str(" ");
+        assert!(io.is_finished());
+        assert_eq!(io.read_byte(), Some(b'3'));
+        assert_eq!(io.poll_byte(), Err(TryRecvError::Disconnected));
+        assert_eq!(io.output_string(), "> > ");
+    }
+}
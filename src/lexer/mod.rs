@@ -66,6 +66,8 @@ pub enum TokenKind {
     Breakpoint,
     Whitespace,
     Comment,
+    /// Comment beginning with `;;`, documenting the following label
+    DocComment,
     Eof,
 }
 
@@ -80,6 +82,7 @@ impl Display for TokenKind {
             TokenKind::Reg(_) => "register",
             TokenKind::Whitespace
             | TokenKind::Comment
+            | TokenKind::DocComment
             | TokenKind::Eof
             | TokenKind::Byte(_)
             | TokenKind::Breakpoint => {
@@ -90,6 +93,32 @@ impl Display for TokenKind {
     }
 }
 
+/// Instruction mnemonics recognised by the lexer, including non-standard extensions.
+pub const INSTRUCTIONS: &[&str] = &[
+    "add", "and", "br", "brnzp", "brnz", "brzp", "brnp", "brn", "brz", "brp", "jmp", "jsr", "jsrr",
+    "ld", "ldi", "ldr", "lea", "not", "ret", "rti", "st", "sti", "str", "pop", "push", "call",
+    "rets",
+];
+
+/// Instructions only available with the 'stack' extension.
+pub const STACK_INSTRUCTIONS: &[&str] = &["pop", "push", "call", "rets"];
+
+/// Trap aliases recognised by the lexer, including non-standard extensions.
+pub const TRAPS: &[&str] = &[
+    "trap", "getc", "out", "puts", "in", "putsp", "halt", "putn", "reg",
+];
+
+/// Trap aliases not supported by standard LC3 tools.
+pub const NONSTANDARD_TRAPS: &[&str] = &["putn", "reg"];
+
+/// Directives recognised by the lexer, including non-standard extensions.
+pub const DIRECTIVES: &[&str] = &[
+    ".orig", ".end", ".stringz", ".blkw", ".fill", ".break", ".feature", ".trap",
+];
+
+/// Directives not supported by standard LC3 tools.
+pub const NONSTANDARD_DIRECTIVES: &[&str] = &[".break", ".feature", ".trap"];
+
 /// Test if a character is considered to be whitespace, including commas
 /// or colons but not semicolons
 pub(crate) fn is_whitespace(c: char) -> bool {
@@ -126,8 +155,13 @@ impl Cursor<'_> {
         let token_kind = match first_char {
             // Comment
             ';' => {
+                let kind = if self.first() == ';' {
+                    TokenKind::DocComment
+                } else {
+                    TokenKind::Comment
+                };
                 self.take_while(|c| c != '\n');
-                TokenKind::Comment
+                kind
             }
             // Whitespace
             c if is_whitespace(c) => {
@@ -139,7 +173,16 @@ impl Cursor<'_> {
             '0' => match self.first() {
                 'x' | 'X' => {
                     self.bump();
-                    self.hex()?
+                    let kind = self.hex()?;
+                    if matches!(kind, TokenKind::Lit(_)) && features::strict() {
+                        return Err(error::lex_strict_nonstandard(
+                            "literal prefix",
+                            "0x",
+                            Span::new(SrcOffset(start_pos), self.pos_in_token()),
+                            self.src(),
+                        ));
+                    }
+                    kind
                 }
                 _ => self.ident()?,
             },
@@ -267,13 +310,19 @@ impl Cursor<'_> {
         self.take_while(is_id);
         let dir = self.get_range(start..self.abs_pos()).to_ascii_lowercase();
 
+        let span = Span::new(SrcOffset(start), self.pos_in_token());
         if let Some(token_kind) = self.check_directive(&dir) {
+            if NONSTANDARD_DIRECTIVES.contains(&dir.as_str()) && features::strict() {
+                return Err(error::lex_strict_nonstandard(
+                    "directive",
+                    &dir,
+                    span,
+                    self.src(),
+                ));
+            }
             Ok(token_kind)
         } else {
-            Err(error::lex_invalid_dir(
-                (start..self.abs_pos()).into(),
-                self.src(),
-            ))
+            Err(error::lex_invalid_dir(span, self.src()))
         }
     }
 
@@ -287,6 +336,14 @@ impl Cursor<'_> {
         let mut token_kind = self.check_instruction(&ident, ident_start)?;
         if token_kind == TokenKind::Label {
             token_kind = self.check_trap(&ident);
+            if NONSTANDARD_TRAPS.contains(&ident.as_str()) && features::strict() {
+                return Err(error::lex_strict_nonstandard(
+                    "trap",
+                    &ident,
+                    (ident_start..self.abs_pos()).into(),
+                    self.src(),
+                ));
+            }
         }
         Ok(token_kind)
     }
@@ -302,6 +359,8 @@ impl Cursor<'_> {
             ".blkw" => Some(Dir(Blkw)),
             ".fill" => Some(Dir(Fill)),
             ".break" => Some(Dir(Break)),
+            ".feature" => Some(Dir(Feature)),
+            ".trap" => Some(Dir(Trap)),
             _ => None,
         }
     }
@@ -311,7 +370,15 @@ impl Cursor<'_> {
         use InstrKind::*;
         use TokenKind::Instr;
 
-        if matches!(ident, "pop" | "push" | "call" | "rets") && !features::stack() {
+        if STACK_INSTRUCTIONS.contains(&ident) && features::strict() {
+            return Err(error::lex_strict_nonstandard(
+                "instruction",
+                ident,
+                Span::new(SrcOffset(start_pos), self.pos_in_token()),
+                self.src(),
+            ));
+        }
+        if STACK_INSTRUCTIONS.contains(&ident) && !features::stack() {
             return Err(error::lex_stack_extension_not_enabled(
                 ident,
                 Span::new(SrcOffset(start_pos), self.pos_in_token()),
@@ -546,4 +613,26 @@ mod test {
         );
         assert_eq!(lex.advance_token().unwrap().kind, TokenKind::Whitespace);
     }
+
+    #[test]
+    fn strict_rejects_extensions() {
+        crate::features::init("strict".parse().unwrap());
+        for src in ["putn", "reg", ".break", "push", "0x3000"] {
+            let mut lex = Cursor::new(src);
+            assert!(lex.advance_token().is_err(), "{src} was accepted");
+        }
+        let mut lex = Cursor::new("puts x3000 .fill");
+        assert!(lex.advance_token().is_ok());
+        assert!(lex.advance_real().is_ok());
+        assert!(lex.advance_real().is_ok());
+    }
+
+    #[test]
+    fn doc_comment() {
+        let mut lex = Cursor::new(";; doc\n; plain");
+        let tok = lex.advance_token().unwrap();
+        assert_eq!(tok.kind, TokenKind::DocComment);
+        assert_eq!(tok.span.len(), 6);
+        assert_eq!(lex.advance_real().unwrap().kind, TokenKind::Comment);
+    }
 }
@@ -1,4 +1,5 @@
 // Parsing
+pub mod cst;
 mod parser;
 pub use parser::AsmParser;
 mod air;
@@ -6,7 +7,10 @@ pub use air::Air;
 
 // Running
 mod runtime;
-pub use runtime::RunEnvironment;
+pub use runtime::{ExitReason, RunEnvironment, Runaway, RuntimeError};
+pub mod io;
+pub mod profile;
+pub mod trace;
 #[macro_use]
 pub mod debugger;
 mod output;
@@ -16,9 +20,21 @@ mod term;
 mod symbol;
 pub use symbol::{reset_state, StaticSource};
 
+pub mod diagnostic;
 mod error;
 mod lexer;
 
+pub mod artifacts;
+pub mod doc;
+pub mod explain;
+pub mod highlight;
+pub mod incremental;
+pub mod lsp;
+pub mod optimize;
+pub mod os;
+pub mod project;
+pub mod size;
+
 pub mod features;
 
 /// Amount of lines to show as context, each side of focus line (line containing span).
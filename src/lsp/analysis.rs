@@ -0,0 +1,156 @@
+use fxhash::FxHashMap;
+use lsp_types::{Position, Range};
+
+use crate::cst::SyntaxTree;
+use crate::diagnostic::Diagnostic;
+use crate::incremental::{self, Assembler};
+pub use crate::incremental::{LabelToken, Statement};
+use crate::symbol::{reset_state, Span, StaticSource};
+
+/// Everything the language server knows about a single document.
+///
+/// All data is owned, so the source can be reclaimed once analysis is complete.
+pub struct Analysis {
+    pub line_index: LineIndex,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Every label definition and reference, in source order.
+    pub labels: Vec<LabelToken>,
+    /// Assembled statements, only available if the document has no errors.
+    pub statements: Vec<Statement>,
+    /// Absolute address of each label, only available if the document has no errors.
+    pub addresses: FxHashMap<String, u16>,
+    /// Names of labels which are the target of `JSR` or `CALL`.
+    pub subroutines: Vec<String>,
+}
+
+impl Analysis {
+    /// Analyse the next version of a document, reusing work from previous versions.
+    pub fn with_assembler(text: &str, assembler: &mut Assembler) -> Self {
+        let line_index = LineIndex::new(text);
+        match assembler.assemble(text) {
+            Ok(assembly) => Analysis {
+                line_index,
+                diagnostics: assembly.warnings,
+                labels: assembly.labels,
+                statements: assembly.statements,
+                addresses: assembly.addresses,
+                subroutines: assembly.subroutines,
+            },
+            Err(diagnostics) => {
+                let mut source = StaticSource::new(text.to_string());
+                let labels = SyntaxTree::parse(source.src())
+                    .map(|tree| incremental::label_tokens(&tree))
+                    .unwrap_or_default();
+                reset_state();
+                source.reclaim();
+                Analysis {
+                    line_index,
+                    diagnostics,
+                    labels,
+                    statements: Vec::new(),
+                    addresses: FxHashMap::default(),
+                    subroutines: Vec::new(),
+                }
+            }
+        }
+    }
+
+    /// Label definition or reference at the given offset.
+    pub fn label_at(&self, offset: usize) -> Option<&LabelToken> {
+        self.labels
+            .iter()
+            .find(|label| label.span.offs() <= offset && offset <= label.span.end())
+    }
+
+    pub fn definition(&self, name: &str) -> Option<&LabelToken> {
+        self.labels
+            .iter()
+            .find(|label| label.is_definition && label.name == name)
+    }
+
+    /// All statements whose span contains the given offset.
+    ///
+    /// Multiple statements share a span for `.stringz` and `.blkw`.
+    pub fn statements_at(&self, offset: usize) -> impl Iterator<Item = &Statement> {
+        self.statements
+            .iter()
+            .filter(move |stmt| stmt.span.offs() <= offset && offset < stmt.span.end())
+    }
+}
+
+/// Conversion between byte offsets and LSP positions (UTF-16 code units).
+pub struct LineIndex {
+    text: String,
+    /// Byte offset of the start of each line.
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(text: &str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        LineIndex {
+            text: text.to_string(),
+            line_starts,
+        }
+    }
+
+    pub fn position(&self, offset: usize) -> Position {
+        let offset = offset.min(self.text.len());
+        let line = self.line_starts.partition_point(|start| *start <= offset) - 1;
+        let start = self.line_starts[line];
+        let character = self.text[start..offset].encode_utf16().count();
+        Position::new(line as u32, character as u32)
+    }
+
+    pub fn offset(&self, position: Position) -> usize {
+        let Some(start) = self.line_starts.get(position.line as usize) else {
+            return self.text.len();
+        };
+        let mut units = 0;
+        for (i, c) in self.text[*start..].char_indices() {
+            if units >= position.character as usize || c == '\n' {
+                return start + i;
+            }
+            units += c.len_utf16();
+        }
+        self.text.len()
+    }
+
+    pub fn range(&self, span: Span) -> Range {
+        Range::new(self.position(span.offs()), self.position(span.end()))
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn line_index() {
+        let index = LineIndex::new("ab\n€d\n");
+        assert_eq!(index.position(0), Position::new(0, 0));
+        assert_eq!(index.position(3), Position::new(1, 0));
+        // '€' is 3 bytes but a single UTF-16 code unit
+        assert_eq!(index.position(6), Position::new(1, 1));
+        assert_eq!(index.offset(Position::new(1, 1)), 6);
+        assert_eq!(index.offset(Position::new(2, 0)), 8);
+    }
+
+    #[test]
+    fn labels_and_addresses() {
+        let text = ".orig x3000\njsr sub\nhalt\nsub add r0 r0 #1\nret\n";
+        let analysis = Analysis::with_assembler(text, &mut Assembler::new());
+        assert!(analysis.diagnostics.is_empty());
+        assert_eq!(analysis.labels.len(), 2);
+        assert!(analysis.definition("sub").unwrap().is_definition);
+        assert_eq!(analysis.addresses["sub"], 0x3002);
+        assert_eq!(analysis.subroutines, ["sub"]);
+        assert_eq!(analysis.statements[0].word, 0x4801);
+    }
+}
@@ -0,0 +1,353 @@
+//! Language server, communicating over stdio.
+//!
+//! Documents are re-analysed on every change. Lines which did not change are not parsed again, see
+//! [`incremental`](crate::incremental).
+
+mod analysis;
+
+use fxhash::FxHashMap;
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification as _,
+    PublishDiagnostics,
+};
+use lsp_types::request::{
+    Completion, DocumentSymbolRequest, GotoDefinition, HoverRequest, References, Request as _,
+};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionParams, CompletionResponse,
+    DiagnosticRelatedInformation, DiagnosticSeverity, DocumentSymbol, DocumentSymbolParams,
+    DocumentSymbolResponse, GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents,
+    HoverParams, HoverProviderCapability, Location, MarkupContent, MarkupKind, NumberOrString,
+    OneOf, PublishDiagnosticsParams, ReferenceParams, ServerCapabilities, SymbolKind,
+    TextDocumentPositionParams, TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+use miette::{IntoDiagnostic, Result, Severity};
+use serde_json::Value;
+
+use self::analysis::Analysis;
+use crate::diagnostic::Diagnostic;
+use crate::features;
+use crate::incremental::Assembler;
+use crate::lexer::{
+    DIRECTIVES, INSTRUCTIONS, NONSTANDARD_DIRECTIVES, NONSTANDARD_TRAPS, STACK_INSTRUCTIONS, TRAPS,
+};
+use crate::symbol::Span;
+
+/// Run language server until client requests shutdown.
+pub fn run() -> Result<()> {
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = serde_json::to_value(capabilities()).into_diagnostic()?;
+    connection.initialize(capabilities).into_diagnostic()?;
+
+    let mut server = Server {
+        connection: &connection,
+        documents: FxHashMap::default(),
+        assemblers: FxHashMap::default(),
+    };
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request).into_diagnostic()? {
+                    break;
+                }
+                let response = server.handle_request(request);
+                server.send(Message::Response(response))?;
+            }
+            Message::Notification(notification) => server.handle_notification(notification)?,
+            Message::Response(_) => (),
+        }
+    }
+
+    // Writer thread only finishes once every sender is dropped
+    drop(connection);
+    io_threads.join().into_diagnostic()
+}
+
+fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        completion_provider: Some(Default::default()),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        ..Default::default()
+    }
+}
+
+struct Server<'a> {
+    connection: &'a Connection,
+    documents: FxHashMap<Url, Analysis>,
+    assemblers: FxHashMap<Url, Assembler>,
+}
+
+impl Server<'_> {
+    fn send(&self, message: Message) -> Result<()> {
+        self.connection.sender.send(message).into_diagnostic()
+    }
+
+    fn handle_notification(&mut self, notification: Notification) -> Result<()> {
+        match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: lsp_types::DidOpenTextDocumentParams =
+                    serde_json::from_value(notification.params).into_diagnostic()?;
+                self.update(params.text_document.uri, &params.text_document.text)
+            }
+            DidChangeTextDocument::METHOD => {
+                let params: lsp_types::DidChangeTextDocumentParams =
+                    serde_json::from_value(notification.params).into_diagnostic()?;
+                // Full document sync: last change contains entire text
+                match params.content_changes.last() {
+                    Some(change) => self.update(params.text_document.uri, &change.text),
+                    None => Ok(()),
+                }
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: lsp_types::DidCloseTextDocumentParams =
+                    serde_json::from_value(notification.params).into_diagnostic()?;
+                self.documents.remove(&params.text_document.uri);
+                self.assemblers.remove(&params.text_document.uri);
+                self.publish(params.text_document.uri, Vec::new())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Re-analyse document and publish diagnostics.
+    fn update(&mut self, uri: Url, text: &str) -> Result<()> {
+        let assembler = self.assemblers.entry(uri.clone()).or_default();
+        let analysis = Analysis::with_assembler(text, assembler);
+        let diagnostics = analysis
+            .diagnostics
+            .iter()
+            .map(|diagnostic| to_lsp_diagnostic(&uri, &analysis, diagnostic))
+            .collect();
+        self.documents.insert(uri.clone(), analysis);
+        self.publish(uri, diagnostics)
+    }
+
+    fn publish(&self, uri: Url, diagnostics: Vec<lsp_types::Diagnostic>) -> Result<()> {
+        let params = PublishDiagnosticsParams::new(uri, diagnostics, None);
+        self.send(Message::Notification(Notification::new(
+            PublishDiagnostics::METHOD.to_string(),
+            params,
+        )))
+    }
+
+    fn handle_request(&self, request: Request) -> Response {
+        match request.method.as_str() {
+            HoverRequest::METHOD => self.respond(request, Self::hover),
+            GotoDefinition::METHOD => self.respond(request, Self::definition),
+            References::METHOD => self.respond(request, Self::references),
+            Completion::METHOD => self.respond(request, Self::completion),
+            DocumentSymbolRequest::METHOD => self.respond(request, Self::document_symbols),
+            _ => Response::new_err(
+                request.id,
+                ErrorCode::MethodNotFound as i32,
+                format!("unsupported request '{}'", request.method),
+            ),
+        }
+    }
+
+    fn respond<P, R>(&self, request: Request, handler: fn(&Self, P) -> Option<R>) -> Response
+    where
+        P: serde::de::DeserializeOwned,
+        R: serde::Serialize,
+    {
+        match serde_json::from_value::<P>(request.params) {
+            Ok(params) => match handler(self, params) {
+                Some(result) => Response::new_ok(request.id, result),
+                None => Response::new_ok(request.id, Value::Null),
+            },
+            Err(err) => {
+                Response::new_err(request.id, ErrorCode::InvalidParams as i32, err.to_string())
+            }
+        }
+    }
+
+    /// Get document and byte offset for a position.
+    fn locate(&self, params: &TextDocumentPositionParams) -> Option<(&Analysis, usize)> {
+        let analysis = self.documents.get(&params.text_document.uri)?;
+        let offset = analysis.line_index.offset(params.position);
+        Some((analysis, offset))
+    }
+
+    fn hover(&self, params: HoverParams) -> Option<Hover> {
+        let (analysis, offset) = self.locate(&params.text_document_position_params)?;
+
+        let (span, value) = if let Some(label) = analysis.label_at(offset) {
+            let value = match analysis.addresses.get(&label.name) {
+                Some(address) => format!("label `{}` at address `x{:04X}`", label.name, address),
+                None => format!("label `{}`", label.name),
+            };
+            (label.span, value)
+        } else {
+            let mut statements = analysis.statements_at(offset);
+            let first = statements.next()?;
+            let count = 1 + statements.count();
+            let value = if count == 1 {
+                format!(
+                    "address `x{:04X}`  \nencoded `x{:04X}` (`{:016b}`)",
+                    first.address, first.word, first.word
+                )
+            } else {
+                format!("address `x{:04X}`  \n{} words", first.address, count)
+            };
+            (first.span, value)
+        };
+
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: Some(analysis.line_index.range(span)),
+        })
+    }
+
+    fn definition(&self, params: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+        let position = &params.text_document_position_params;
+        let (analysis, offset) = self.locate(position)?;
+        let label = analysis.label_at(offset)?;
+        let definition = analysis.definition(&label.name)?;
+        Some(GotoDefinitionResponse::Scalar(Location::new(
+            position.text_document.uri.clone(),
+            analysis.line_index.range(definition.span),
+        )))
+    }
+
+    fn references(&self, params: ReferenceParams) -> Option<Vec<Location>> {
+        let position = &params.text_document_position;
+        let (analysis, offset) = self.locate(position)?;
+        let name = &analysis.label_at(offset)?.name;
+        let locations = analysis
+            .labels
+            .iter()
+            .filter(|label| &label.name == name)
+            .filter(|label| params.context.include_declaration || !label.is_definition)
+            .map(|label| {
+                Location::new(
+                    position.text_document.uri.clone(),
+                    analysis.line_index.range(label.span),
+                )
+            })
+            .collect();
+        Some(locations)
+    }
+
+    fn completion(&self, params: CompletionParams) -> Option<CompletionResponse> {
+        let uri = &params.text_document_position.text_document.uri;
+        let analysis = self.documents.get(uri)?;
+
+        let item = |label: &str, kind| CompletionItem {
+            label: label.to_string(),
+            kind: Some(kind),
+            ..Default::default()
+        };
+        let mut items: Vec<_> = INSTRUCTIONS
+            .iter()
+            .filter(|instr| features::stack() || !STACK_INSTRUCTIONS.contains(instr))
+            .map(|instr| item(instr, CompletionItemKind::KEYWORD))
+            .chain(
+                TRAPS
+                    .iter()
+                    .filter(|trap| !features::strict() || !NONSTANDARD_TRAPS.contains(trap))
+                    .map(|trap| item(trap, CompletionItemKind::FUNCTION)),
+            )
+            .chain(
+                DIRECTIVES
+                    .iter()
+                    .filter(|dir| !features::strict() || !NONSTANDARD_DIRECTIVES.contains(dir))
+                    .map(|dir| item(dir, CompletionItemKind::KEYWORD)),
+            )
+            .collect();
+
+        for label in analysis.labels.iter().filter(|label| label.is_definition) {
+            let kind = if analysis.subroutines.contains(&label.name) {
+                CompletionItemKind::FUNCTION
+            } else {
+                CompletionItemKind::VARIABLE
+            };
+            items.push(item(&label.name, kind));
+        }
+        Some(CompletionResponse::Array(items))
+    }
+
+    fn document_symbols(&self, params: DocumentSymbolParams) -> Option<DocumentSymbolResponse> {
+        let analysis = self.documents.get(&params.text_document.uri)?;
+        let definitions: Vec<_> = analysis
+            .labels
+            .iter()
+            .filter(|label| label.is_definition && analysis.subroutines.contains(&label.name))
+            .collect();
+
+        let end_of_file = analysis.line_index.text().len();
+        #[allow(deprecated)] // `deprecated` field must be given
+        let symbols = definitions
+            .iter()
+            .enumerate()
+            .map(|(i, label)| {
+                // Subroutine continues until the next one begins
+                let end = definitions
+                    .get(i + 1)
+                    .map(|next| next.span.offs())
+                    .unwrap_or(end_of_file);
+                DocumentSymbol {
+                    name: label.name.clone(),
+                    detail: analysis
+                        .addresses
+                        .get(&label.name)
+                        .map(|address| format!("x{address:04X}")),
+                    kind: SymbolKind::FUNCTION,
+                    tags: None,
+                    deprecated: None,
+                    range: analysis.line_index.range((label.span.offs()..end).into()),
+                    selection_range: analysis.line_index.range(label.span),
+                    children: None,
+                }
+            })
+            .collect();
+        Some(DocumentSymbolResponse::Nested(symbols))
+    }
+}
+
+fn to_lsp_diagnostic(
+    uri: &Url,
+    analysis: &Analysis,
+    diagnostic: &Diagnostic,
+) -> lsp_types::Diagnostic {
+    let range = analysis
+        .line_index
+        .range(diagnostic.span().unwrap_or(Span::dummy()));
+    let severity = match diagnostic.severity {
+        Severity::Error => DiagnosticSeverity::ERROR,
+        Severity::Warning => DiagnosticSeverity::WARNING,
+        Severity::Advice => DiagnosticSeverity::HINT,
+    };
+    let mut message = diagnostic.message.clone();
+    if let Some(help) = &diagnostic.help {
+        message.push_str("\nhelp: ");
+        message.push_str(help);
+    }
+    let related = diagnostic
+        .labels
+        .iter()
+        .filter_map(|label| {
+            Some(DiagnosticRelatedInformation {
+                location: Location::new(uri.clone(), analysis.line_index.range(label.span)),
+                message: label.label.clone()?,
+            })
+        })
+        .collect();
+
+    lsp_types::Diagnostic {
+        range,
+        severity: Some(severity),
+        code: diagnostic.code.clone().map(NumberOrString::String),
+        source: Some("lace".to_string()),
+        message,
+        related_information: Some(related),
+        ..Default::default()
+    }
+}
@@ -1,6 +1,12 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::{Read, Write};
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread::sleep;
 use std::time::Duration;
 
@@ -13,9 +19,18 @@ use hotwatch::{
 };
 use miette::{bail, IntoDiagnostic, Result};
 
+use lace::artifacts::{self, Artifacts};
+use lace::diagnostic::{self, MessageFormat};
+use lace::doc;
 use lace::features::Features;
+use lace::highlight;
+use lace::incremental::Assembler;
+use lace::profile::Profiler;
+use lace::project::{self, Entry, OutputFormat, Project};
+use lace::size;
+use lace::trace::{TraceFilter, TraceFormat, Tracer};
 use lace::{debugger, reset_state};
-use lace::{Air, RunEnvironment, StaticSource};
+use lace::{Air, ExitReason, RunEnvironment, StaticSource};
 
 /// Lace is a complete & convenient assembler toolchain for the LC3 assembly language.
 #[derive(Parser)]
@@ -37,11 +52,43 @@ struct Args {
 enum Command {
     /// Run text `.asm` or binary `.lc3` file directly and output to terminal
     Run {
-        /// `.asm` or `.lc3` file to run
-        name: PathBuf,
+        /// `.asm` or `.lc3` file to run, instead of the first entry in `lace.toml`
+        name: Option<PathBuf>,
         /// Produce minimal output, suited for blackbox tests
         #[arg(short, long)]
         minimal: bool,
+        /// Stop program after executing this many instructions
+        #[arg(long)]
+        max_steps: Option<u64>,
+        /// Stop program after running for this many seconds
+        #[arg(long, value_name = "SECS", value_parser = parse_timeout)]
+        timeout: Option<Duration>,
+        /// Load an operating system (`.asm` or `.lc3`, or the bundled one if omitted) and run traps
+        /// through the trap vector table
+        #[arg(long, value_name = "IMAGE", num_args = 0..=1)]
+        os: Option<Option<PathBuf>>,
+        /// Write a trace of every executed instruction to this file
+        #[arg(long, value_name = "FILE")]
+        trace: Option<PathBuf>,
+        /// Format of the trace: aligned text or JSON lines
+        #[arg(long, value_enum, default_value_t, requires = "trace")]
+        trace_format: TraceFormat,
+        /// Only trace instructions at addresses in this inclusive range, such as `x3000-x3010`
+        #[arg(long, value_name = "START-END", value_parser = parse_address_range, requires = "trace")]
+        trace_range: Option<RangeInclusive<u16>>,
+        /// Only trace instructions executed during calls to the subroutine at this label
+        #[arg(long, value_name = "LABEL", requires = "trace")]
+        trace_subroutine: Option<String>,
+        /// Print instructions executed and calls made in each label, and the hottest lines
+        #[arg(long)]
+        profile: bool,
+        /// Write instructions executed in each call stack to this file, as folded stacks for
+        /// flamegraph tools
+        #[arg(long, value_name = "FILE", requires = "profile")]
+        profile_folded: Option<PathBuf>,
+        /// Format of diagnostics: human-readable reports, JSON lines or a SARIF log
+        #[arg(long, value_enum, default_value_t)]
+        message_format: MessageFormat,
         #[command(flatten)]
         run_options: RunOptions,
     },
@@ -59,6 +106,16 @@ enum Command {
         /// Produce minimal output, suited for blackbox tests
         #[arg(short, long)]
         minimal: bool,
+        /// Pause program after executing this many instructions since the last command
+        #[arg(long)]
+        max_steps: Option<u64>,
+        /// Pause program after running for this many seconds since the last command
+        #[arg(long, value_name = "SECS", value_parser = parse_timeout)]
+        timeout: Option<Duration>,
+        /// Load an operating system (`.asm` or `.lc3`, or the bundled one if omitted) and run traps
+        /// through the trap vector table
+        #[arg(long, value_name = "IMAGE", num_args = 0..=1)]
+        os: Option<Option<PathBuf>>,
         #[command(flatten)]
         run_options: RunOptions,
         /// Print information on debugger commands (without reading any file)
@@ -71,8 +128,27 @@ enum Command {
     Compile {
         /// `.asm` file to compile
         name: PathBuf,
-        /// Destination to output .lc3 file
+        /// Destination to output .lc3 file, instead of the artifacts directory
         dest: Option<PathBuf>,
+        /// Directory to store build artifacts in
+        #[arg(long, default_value = artifacts::DEFAULT_OUT_DIR)]
+        out_dir: PathBuf,
+        /// Run peephole optimizations, reporting each rewrite
+        #[arg(short = 'O', long)]
+        optimize: bool,
+        /// Format of diagnostics: human-readable reports, JSON lines or a SARIF log
+        #[arg(long, value_enum, default_value_t)]
+        message_format: MessageFormat,
+        #[command(flatten)]
+        run_options: RunOptions,
+    },
+    /// Build every entry declared in `lace.toml`
+    Build {
+        #[command(flatten)]
+        run_options: RunOptions,
+    },
+    /// Run the test cases declared in `lace.toml`
+    Test {
         #[command(flatten)]
         run_options: RunOptions,
     },
@@ -80,16 +156,97 @@ enum Command {
     Check {
         /// File to check
         name: PathBuf,
+        /// Format of diagnostics: human-readable reports, JSON lines or a SARIF log
+        #[arg(long, value_enum, default_value_t)]
+        message_format: MessageFormat,
+        #[command(flatten)]
+        run_options: RunOptions,
     },
     /// Remove compilation artifacts for specified source
+    #[clap(group(ArgGroup::new("name_or_all").required(true)))]
     Clean {
         /// `.asm` file to try remove artifacts for
-        name: PathBuf,
+        #[arg(group("name_or_all"))]
+        name: Option<PathBuf>,
+        /// Remove artifacts for every source
+        #[arg(short, long, group("name_or_all"))]
+        all: bool,
+        /// Directory build artifacts are stored in
+        #[arg(long, default_value = artifacts::DEFAULT_OUT_DIR)]
+        out_dir: PathBuf,
     },
     /// Place a watch on a `.asm` file to receive constant assembler updates
     Watch {
-        /// `.asm` file to watch
+        /// `.asm` file to watch, instead of every input declared in `lace.toml`
+        name: Option<PathBuf>,
+        /// Run program after each change, instead of only checking it
+        #[arg(long, conflicts_with = "test")]
+        run: bool,
+        /// Run test cases declared in `lace.toml` after each change
+        #[arg(long)]
+        test: bool,
+        /// File to use as console input when running with `--run`
+        #[arg(long, requires = "run")]
+        input: Option<PathBuf>,
+        /// Stop program after executing this many instructions when running with `--run`
+        #[arg(long, default_value_t = 1_000_000)]
+        max_steps: u64,
+        /// Format of diagnostics: human-readable reports, JSON lines or a SARIF log
+        #[arg(long, value_enum, default_value_t)]
+        message_format: MessageFormat,
+        #[command(flatten)]
+        run_options: RunOptions,
+    },
+    /// Generate reference documentation from `;;` comments above subroutines
+    Doc {
+        /// `.asm` file to document
         name: PathBuf,
+        /// Destination to output documentation, instead of the artifacts directory
+        dest: Option<PathBuf>,
+        /// Output format of documentation
+        #[arg(long, value_enum, default_value_t)]
+        format: doc::Format,
+        /// Directory to store build artifacts in
+        #[arg(long, default_value = artifacts::DEFAULT_OUT_DIR)]
+        out_dir: PathBuf,
+        #[command(flatten)]
+        run_options: RunOptions,
+    },
+    /// Report label addresses, code and data sizes, and references near their offset limit
+    Size {
+        /// `.asm` file to measure
+        name: PathBuf,
+        #[command(flatten)]
+        run_options: RunOptions,
+    },
+    /// Print `.asm` file with syntax highlighting, for slides and feedback
+    Highlight {
+        /// `.asm` file to highlight
+        name: PathBuf,
+        /// Output format: terminal colours or a standalone HTML page
+        #[arg(long, value_enum, default_value_t)]
+        format: highlight::Format,
+        /// Prefix each line with its line number
+        #[arg(short = 'n', long)]
+        line_numbers: bool,
+        /// Prefix each line with its address, which requires the file to parse
+        #[arg(short, long)]
+        addresses: bool,
+        /// Lines to mark, such as `3` or `5-7`, separated by commas
+        #[arg(long, value_delimiter = ',')]
+        mark: Vec<highlight::LineRange>,
+        #[command(flatten)]
+        run_options: RunOptions,
+    },
+    /// Print an extended explanation of a diagnostic code, such as `parse::lit_range`
+    Explain {
+        /// Code to explain, or list every code if omitted
+        code: Option<String>,
+    },
+    /// Start a language server, communicating over stdio
+    Lsp {
+        #[command(flatten)]
+        run_options: RunOptions,
     },
     /// Format `.asm` file to adhere to recommended style
     Fmt {
@@ -102,7 +259,7 @@ enum Command {
 struct RunOptions {
     /// Feature flags to enable non-standard extensions to the LC3 specification
     ///
-    /// Available flags: 'stack'
+    /// Available flags: 'stack', 'strict'
     #[arg(
         short,
         long,
@@ -128,7 +285,11 @@ fn main() -> miette::Result<()> {
         None => {
             if let Some(path) = args.path {
                 lace::features::init(args.run_options.features);
-                run(&path, None, args.minimal)?;
+                let options = RunCommandOptions {
+                    minimal: args.minimal,
+                    ..Default::default()
+                };
+                run(&path, &[], None, options)?;
                 Ok(())
             } else {
                 println!("\n~ lace v{VERSION} - Copyright (c) 2024 Artemis Rosman ~");
@@ -140,21 +301,69 @@ fn main() -> miette::Result<()> {
         Some(Command::Run {
             name,
             minimal,
+            max_steps,
+            timeout,
+            os,
+            trace,
+            trace_format,
+            trace_range,
+            trace_subroutine,
+            profile,
+            profile_folded,
+            message_format,
             run_options: RunOptions { features },
         }) => {
-            lace::features::init(features);
-            run(&name, None, minimal)
+            let trace = trace.map(|path| {
+                let filter = TraceFilter {
+                    range: trace_range,
+                    subroutine: trace_subroutine,
+                };
+                (path, trace_format, filter)
+            });
+            let options = RunCommandOptions {
+                minimal,
+                max_steps,
+                timeout,
+                os,
+                trace,
+                profile: profile.then_some(profile_folded),
+                message_format,
+            };
+            match name {
+                Some(name) => {
+                    lace::features::init(features);
+                    run(&name, &[], None, options)
+                }
+                None => {
+                    let project = Project::discover()?;
+                    project.configure(features)?;
+                    let name = project.entry_path(project.entry(None)?);
+                    let include_dirs = project.include_dirs();
+                    run(&name, &include_dirs, None, options)
+                }
+            }
         }
         Some(Command::Debug {
             name,
             command,
             minimal,
+            max_steps,
+            timeout,
+            os,
             run_options: RunOptions { features },
             print_help,
         }) => match (name, print_help) {
             (Some(name), false) => {
                 lace::features::init(features);
-                run(&name, Some(debugger::Options { command }), minimal)
+                let debugger_opts = Some(debugger::Options { command });
+                let options = RunCommandOptions {
+                    minimal,
+                    max_steps,
+                    timeout,
+                    os,
+                    ..Default::default()
+                };
+                run(&name, &[], debugger_opts, options)
             }
             (None, true) => {
                 lace::set_minimal(minimal);
@@ -167,99 +376,237 @@ fn main() -> miette::Result<()> {
         Some(Command::Compile {
             name,
             dest,
+            out_dir,
+            optimize,
+            message_format,
             run_options: RunOptions { features },
         }) => {
             lace::features::init(features);
+            set_message_format(message_format);
             file_message(Green, "Assembling", &name);
-            let contents = StaticSource::new(fs::read_to_string(&name).into_diagnostic()?);
-            let air = assemble(&contents)?;
-
-            let out_file_name =
-                dest.unwrap_or(name.with_extension("lc3").file_name().unwrap().into());
-            let mut file = File::create(&out_file_name).unwrap();
-
-            // Deal with .orig
-            if let Some(orig) = air.orig() {
-                let _ = file.write(&orig.to_be_bytes());
-            } else {
-                let _ = file.write(&0x3000u16.to_be_bytes());
+            let contents = read_source(&name, &[])?;
+            let mut air = assemble_as(&contents, &name, message_format)?;
+            if optimize {
+                let rewrites = lace::optimize::optimize(&mut air);
+                let reports: Vec<_> = rewrites
+                    .iter()
+                    .map(|rewrite| rewrite.report(contents.src()))
+                    .collect();
+                if message_format == MessageFormat::Human {
+                    for report in &reports {
+                        println!("{:?}", report);
+                    }
+                } else {
+                    let diagnostics: Vec<_> = reports.iter().map(Into::into).collect();
+                    print_diagnostics(&name, contents.src(), &diagnostics, message_format);
+                }
+                message(
+                    Green,
+                    "Optimized",
+                    &format!(
+                        "{} statement{} removed",
+                        rewrites.len(),
+                        if rewrites.len() == 1 { "" } else { "s" }
+                    ),
+                );
             }
 
-            // Write lines
-            for stmt in &air {
-                let _ = file.write(&stmt.emit()?.to_be_bytes());
-            }
+            let out_file_name = match dest {
+                Some(dest) => dest,
+                None => {
+                    let mut artifacts = Artifacts::open(out_dir)?;
+                    let path = artifacts.create(&name, "lc3")?;
+                    artifacts.save()?;
+                    path
+                }
+            };
+            write_binary(&air, &out_file_name)?;
 
             message(Green, "Finished", "emit binary");
             file_message(Green, "Saved", &out_file_name);
             Ok(())
         }
-        Some(Command::Check { name }) => {
+        Some(Command::Build {
+            run_options: RunOptions { features },
+        }) => {
+            let project = Project::discover()?;
+            project.configure(features)?;
+            let mut artifacts = Artifacts::open(project.out_dir())?;
+            for entry in &project.manifest.entries {
+                for (_, path) in build_entry(&project, entry, &entry.formats, &mut artifacts)? {
+                    file_message(Green, "Saved", &path);
+                }
+            }
+            artifacts.save()?;
+            message(
+                Green,
+                "Finished",
+                &format!("{} entries built", project.manifest.entries.len()),
+            );
+            Ok(())
+        }
+        Some(Command::Test {
+            run_options: RunOptions { features },
+        }) => {
+            let project = Project::discover()?;
+            project.configure(features)?;
+            run_tests(&project)
+        }
+        Some(Command::Check {
+            name,
+            message_format,
+            run_options: RunOptions { features },
+        }) => {
+            lace::features::init(features);
+            set_message_format(message_format);
             file_message(Green, "Checking", &name);
-            let contents = StaticSource::new(fs::read_to_string(&name).into_diagnostic()?);
-            let _ = assemble(&contents)?;
+            let contents = read_source(&name, &[])?;
+            let _ = assemble_as(&contents, &name, message_format)?;
             message(Green, "Success", "no errors found!");
             Ok(())
         }
-        Some(Command::Clean { name: _ }) => todo!("There are no debug files implemented to clean!"),
-        Some(Command::Watch { name }) => {
-            if !name.exists() {
-                bail!("File does not exist. Exiting...")
+        Some(Command::Clean { name, all, out_dir }) => {
+            let mut artifacts = Artifacts::open(out_dir)?;
+            let removed = match (name, all) {
+                (Some(name), false) => {
+                    file_message(Green, "Cleaning", &name);
+                    let removed = artifacts.clean(&name)?;
+                    artifacts.save()?;
+                    removed
+                }
+                (None, true) => {
+                    file_message(Green, "Cleaning", artifacts.dir());
+                    artifacts.clean_all()?
+                }
+                // Should never happen due to argument group
+                _ => panic!("command-line parsing is broken. expected `name` XOR `--all`."),
+            };
+            for path in &removed {
+                file_message(Green, "Removed", path);
             }
-            // Vim breaks if watching a single file
-            let folder_path = match name.parent() {
-                Some(pth) if pth.is_dir() => pth.to_path_buf(),
-                _ => Path::new(".").to_path_buf(),
+            if removed.is_empty() {
+                message(Cyan, "Finished", "no artifacts found");
+            } else {
+                message(Green, "Finished", "artifacts removed");
+            }
+            Ok(())
+        }
+        Some(Command::Watch {
+            name,
+            run,
+            test,
+            input,
+            max_steps,
+            message_format,
+            run_options: RunOptions { features },
+        }) => {
+            let target = match name {
+                Some(name) => {
+                    if !name.exists() {
+                        bail!("File does not exist. Exiting...")
+                    }
+                    lace::features::init(features);
+                    WatchTarget::File(name)
+                }
+                None => {
+                    let project = Project::discover()?;
+                    project.configure(features)?;
+                    WatchTarget::Project(project)
+                }
             };
+            let action = match (run, test) {
+                (true, _) => WatchAction::Run { input, max_steps },
+                (_, true) => WatchAction::Test,
+                _ => WatchAction::Check,
+            };
+            if action == WatchAction::Test && !matches!(target, WatchTarget::Project(_)) {
+                bail!("Watching with `--test` requires a `lace.toml` manifest, instead of a file");
+            }
+            set_message_format(message_format);
+            watch(target, action, message_format)
+        }
+        Some(Command::Doc {
+            name,
+            dest,
+            format,
+            out_dir,
+            run_options: RunOptions { features },
+        }) => {
+            lace::features::init(features);
+            file_message(Green, "Documenting", &name);
+            let contents = read_source(&name, &[])?;
+            let subroutines = doc::document(contents.src())?;
 
-            // Clear screen and move cursor to top left
-            print!("\x1B[2J\x1B[2;1H");
-            file_message(Green, "Watching", &name);
-            message(Cyan, "Help", "press CTRL+C to exit");
-
-            let mut watcher =
-                Hotwatch::new_with_custom_delay(Duration::from_millis(500)).into_diagnostic()?;
-
-            watcher
-                .watch(folder_path, move |event: Event| match event.kind {
-                    // Watch remove for vim changes
-                    EventKind::Modify(_) | EventKind::Remove(_) => {
-                        // Clear screen
-                        print!("\x1B[2J\x1B[2;1H");
-                        file_message(Green, "Watching", &name);
-                        message(Green, "Re-checking", "file change detected");
-                        message(Cyan, "Help", "press CTRL+C to exit");
-
-                        // Now we are developing software (makes reruns more obvious)
-                        sleep(Duration::from_millis(50));
-
-                        let mut contents = StaticSource::new(match fs::read_to_string(&name) {
-                            Ok(cts) => cts,
-                            Err(e) => {
-                                eprintln!("{e}. Exiting...");
-                                std::process::exit(1)
-                            }
-                        });
-                        match assemble(&contents) {
-                            Ok(_) => {
-                                message(Green, "Success", "no errors found!");
-                            }
-                            Err(e) => {
-                                println!("\n{:?}", e);
-                            }
-                        };
-
-                        reset_state();
-                        // To avoid leaking memory
-                        contents.reclaim();
-                        Flow::Continue
-                    }
-                    _ => Flow::Continue,
-                })
+            let title = name
+                .file_name()
+                .map(|file| file.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let out_file_name = match dest {
+                Some(dest) => dest,
+                None => {
+                    let mut artifacts = Artifacts::open(out_dir)?;
+                    let path = artifacts.create(&name, format.extension())?;
+                    artifacts.save()?;
+                    path
+                }
+            };
+            fs::write(&out_file_name, doc::render(&title, &subroutines, format))
                 .into_diagnostic()?;
-            watcher.run();
+
+            message(
+                Green,
+                "Finished",
+                &format!("{} subroutines documented", subroutines.len()),
+            );
+            file_message(Green, "Saved", &out_file_name);
             Ok(())
         }
+        Some(Command::Size {
+            name,
+            run_options: RunOptions { features },
+        }) => {
+            lace::features::init(features);
+            let contents = read_source(&name, &[])?;
+            print!("{}", size::render(&size::measure(contents.src())?));
+            Ok(())
+        }
+        Some(Command::Highlight {
+            name,
+            format,
+            line_numbers,
+            addresses,
+            mark,
+            run_options: RunOptions { features },
+        }) => {
+            lace::features::init(features);
+            let contents = read_source(&name, &[])?;
+            let options = highlight::Options {
+                format,
+                line_numbers,
+                addresses,
+                marked: mark,
+            };
+            print!("{}", highlight::highlight(contents.src(), &options)?);
+            Ok(())
+        }
+        Some(Command::Explain { code }) => {
+            match code {
+                Some(code) => print!("{}", lace::explain::explain(&code)?),
+                None => {
+                    for (code, explanation) in lace::explain::EXPLANATIONS {
+                        let summary = explanation.lines().next().unwrap_or_default();
+                        println!("{:<34} {summary}", code.cyan());
+                    }
+                }
+            }
+            Ok(())
+        }
+        Some(Command::Lsp {
+            run_options: RunOptions { features },
+        }) => {
+            lace::features::init(features);
+            lace::lsp::run()
+        }
         Some(Command::Fmt { name: _ }) => todo!("Formatting is not currently implemented"),
     }
 }
@@ -271,6 +618,20 @@ enum MsgColor {
     Red,
 }
 
+/// Whether status messages are hidden, to keep machine-readable output parsable.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+fn set_message_format(format: MessageFormat) {
+    QUIET.store(format != MessageFormat::Human, Ordering::Relaxed);
+}
+
+fn clear_screen() {
+    if !QUIET.load(Ordering::Relaxed) {
+        // Clear screen and move cursor to top left
+        print!("\x1B[2J\x1B[2;1H");
+    }
+}
+
 fn file_message(color: MsgColor, left: &str, right: &Path) {
     let right = format!("target {}", right.to_str().unwrap());
     message(color, left, &right);
@@ -280,6 +641,9 @@ fn message<S>(color: MsgColor, left: S, right: S)
 where
     S: Colorize + std::fmt::Display,
 {
+    if QUIET.load(Ordering::Relaxed) {
+        return;
+    }
     let left = match color {
         MsgColor::Green => left.green(),
         MsgColor::Cyan => left.cyan(),
@@ -288,7 +652,42 @@ where
     println!("{left:>12} {right}");
 }
 
-fn run(name: &PathBuf, debugger_opts: Option<debugger::Options>, minimal: bool) -> Result<()> {
+/// Options for the `run` command, which are not needed by the debugger.
+#[derive(Default)]
+struct RunCommandOptions {
+    minimal: bool,
+    max_steps: Option<u64>,
+    timeout: Option<Duration>,
+    /// Operating system image, or the bundled one if `Some(None)`
+    os: Option<Option<PathBuf>>,
+    /// File to write trace to
+    trace: Option<(PathBuf, TraceFormat, TraceFilter)>,
+    /// Whether to profile, and file to write folded stacks to
+    profile: Option<Option<PathBuf>>,
+    message_format: MessageFormat,
+}
+
+fn run(
+    name: &Path,
+    include_dirs: &[PathBuf],
+    debugger_opts: Option<debugger::Options>,
+    options: RunCommandOptions,
+) -> Result<()> {
+    let RunCommandOptions {
+        minimal,
+        max_steps,
+        timeout,
+        os,
+        trace,
+        profile,
+        message_format,
+    } = options;
+    set_message_format(message_format);
+    // Assembled before the program, as the symbol table is reset afterwards
+    let os = match os {
+        Some(os) => Some(os_image(os.as_deref())?),
+        None => None,
+    };
     file_message(MsgColor::Green, "Assembling", name);
     let mut program = if let Some(ext) = name.extension() {
         match ext.to_str().unwrap() {
@@ -297,25 +696,11 @@ fn run(name: &PathBuf, debugger_opts: Option<debugger::Options>, minimal: bool)
                     bail!("Cannot use debugger on non-assembly file");
                 }
 
-                // Read to byte buffer
-                let mut file = File::open(name).into_diagnostic()?;
-                let f_size = file.metadata().unwrap().len();
-                let mut buffer = Vec::with_capacity(f_size as usize);
-                file.read_to_end(&mut buffer).into_diagnostic()?;
-
-                if buffer.len() % 2 != 0 {
-                    bail!("File is not aligned to 16 bits")
-                }
-
-                let u16_buf: Vec<u16> = buffer
-                    .chunks_exact(2)
-                    .map(|word| u16::from_be_bytes([word[0], word[1]]))
-                    .collect();
-                RunEnvironment::from_raw(&u16_buf)?
+                RunEnvironment::from_raw(&read_binary(name)?)?
             }
             "asm" => {
-                let contents = StaticSource::new(fs::read_to_string(name).into_diagnostic()?);
-                let air = assemble(&contents)?;
+                let contents = read_source(name, include_dirs)?;
+                let air = assemble_as(&contents, name, message_format)?;
                 RunEnvironment::try_from(air, debugger_opts)?
             }
             _ => {
@@ -326,23 +711,594 @@ fn run(name: &PathBuf, debugger_opts: Option<debugger::Options>, minimal: bool)
         bail!("File has no extension. Exiting...");
     };
 
+    if let Some(os) = os {
+        program.load_os(&os)?;
+    }
     lace::set_minimal(minimal);
+    program.set_max_steps(max_steps);
+    program.set_timeout(timeout);
+    if let Some((path, format, filter)) = trace {
+        let file = File::create(&path).into_diagnostic()?;
+        program.set_tracer(Tracer::new(file, format, filter))?;
+    }
+    if profile.is_some() {
+        program.set_profiler(Profiler::new());
+    }
 
     message(MsgColor::Green, "Running", "emitted binary");
-    program.run();
+    let result = program.run();
+    match &result {
+        Ok(ExitReason::Halted) => println!("\n{:>12}", "Halted".cyan()),
+        Ok(ExitReason::Exited) => (),
+        Err(err) => eprintln!("{}", err.exit_message()),
+    }
+    // Profile runaway and faulting programs too
+    if let Some(report) = program.profile_report() {
+        eprintln!("\n{report}");
+    }
+    if let (Some(Some(path)), Some(folded)) = (&profile, program.profile_folded()) {
+        fs::write(path, folded).into_diagnostic()?;
+    }
+    if let Err(err) = result {
+        std::process::exit(err.exit_code());
+    }
 
     file_message(MsgColor::Green, "Completed", name);
     Ok(())
 }
 
+/// Parse `--timeout` as a non-negative number of seconds.
+fn parse_timeout(secs: &str) -> std::result::Result<Duration, String> {
+    let secs: f64 = secs.parse().map_err(|err| format!("{err}"))?;
+    Duration::try_from_secs_f64(secs).map_err(|_| "must be a non-negative number of seconds".into())
+}
+
+/// Parse `--trace-range` as two addresses, in hex (`x3000` or `0x3000`) or decimal.
+fn parse_address_range(range: &str) -> std::result::Result<RangeInclusive<u16>, String> {
+    let parse = |address: &str| {
+        let address = address.trim();
+        let hex = address
+            .strip_prefix("0x")
+            .or_else(|| address.strip_prefix('x'));
+        match hex {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => address.parse(),
+        }
+        .map_err(|err| format!("invalid address `{address}`: {err}"))
+    };
+    let Some((start, end)) = range.split_once('-') else {
+        return Err("expected a range of addresses, such as `x3000-x3010`".into());
+    };
+    Ok(parse(start)?..=parse(end)?)
+}
+
+/// Read binary file as big-endian words.
+fn read_binary(name: &Path) -> Result<Vec<u16>> {
+    let mut file = File::open(name).into_diagnostic()?;
+    let f_size = file.metadata().unwrap().len();
+    let mut buffer = Vec::with_capacity(f_size as usize);
+    file.read_to_end(&mut buffer).into_diagnostic()?;
+
+    if buffer.len() % 2 != 0 {
+        bail!("File is not aligned to 16 bits")
+    }
+
+    Ok(buffer
+        .chunks_exact(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]))
+        .collect())
+}
+
+/// Read or assemble operating system image, or assemble the bundled one.
+fn os_image(path: Option<&Path>) -> Result<Vec<u16>> {
+    let Some(path) = path else {
+        return lace::os::default_image();
+    };
+    file_message(MsgColor::Green, "Loading", path);
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("lc3" | "obj") => read_binary(path),
+        Some("asm") => lace::os::assemble(read_source(path, &[])?.src()),
+        _ => bail!("Operating system has unknown extension. Exiting..."),
+    }
+}
+
 /// Return assembly intermediate representation of source file for further processing
 fn assemble(contents: &StaticSource) -> Result<Air> {
     let parser = lace::AsmParser::new(contents.src())?;
+    for warning in parser.warnings() {
+        println!("{:?}", warning);
+    }
     let mut air = parser.parse()?;
     air.backpatch()?;
     Ok(air)
 }
 
+/// Read source file, expanding `.include` directives.
+fn read_source(name: &Path, include_dirs: &[PathBuf]) -> Result<StaticSource> {
+    Ok(StaticSource::new(project::read_source(name, include_dirs)?))
+}
+
+/// Write binary, prefixed with its origin and any features or trap handlers it requires.
+fn write_binary(air: &Air, path: &Path) -> Result<()> {
+    let mut file = File::create(path).into_diagnostic()?;
+
+    // Record features required to run binary, and trap handlers to install
+    let traps: Vec<_> = air.trap_handlers().collect();
+    for word in lace::features::binary_header(&traps) {
+        let _ = file.write(&word.to_be_bytes());
+    }
+
+    // Deal with .orig
+    if let Some(orig) = air.orig() {
+        let _ = file.write(&orig.to_be_bytes());
+    } else {
+        let _ = file.write(&0x3000u16.to_be_bytes());
+    }
+
+    // Write lines
+    for stmt in air {
+        let _ = file.write(&stmt.emit()?.to_be_bytes());
+    }
+    Ok(())
+}
+
+/// What `watch` is watching.
+enum WatchTarget {
+    File(PathBuf),
+    Project(Project),
+}
+
+/// What `watch` does after each change.
+#[derive(Clone, PartialEq)]
+enum WatchAction {
+    Check,
+    Run {
+        input: Option<PathBuf>,
+        max_steps: u64,
+    },
+    Test,
+}
+
+impl WatchTarget {
+    /// Source files to assemble, and the directories searched by their `.include` directives.
+    fn sources(&self) -> (Vec<PathBuf>, Vec<PathBuf>) {
+        match self {
+            WatchTarget::File(name) => (vec![name.clone()], Vec::new()),
+            WatchTarget::Project(project) => {
+                let sources = project
+                    .manifest
+                    .entries
+                    .iter()
+                    .map(|entry| project.entry_path(entry))
+                    .collect();
+                (sources, project.include_dirs())
+            }
+        }
+    }
+
+    /// Every file which should trigger an update when changed.
+    ///
+    /// Included files are found again after each change, as `.include` directives may change.
+    fn inputs(&self) -> Vec<PathBuf> {
+        let mut inputs = Vec::new();
+        if let WatchTarget::Project(project) = self {
+            inputs.push(project.root.join(project::MANIFEST_NAME));
+        }
+        inputs.extend(self.source_files());
+        inputs
+            .iter()
+            .filter_map(|input| watch_path(input))
+            .collect()
+    }
+
+    /// Every source file, followed by the files included by each of them.
+    fn source_files(&self) -> Vec<PathBuf> {
+        let (sources, include_dirs) = self.sources();
+        let mut files = Vec::new();
+        for source in &sources {
+            // Missing or broken includes are reported when assembling
+            match project::source_files(source, &include_dirs) {
+                Ok(included) => files.extend(included),
+                Err(_) => files.push(source.clone()),
+            }
+        }
+        files
+    }
+
+    /// Directories to place watches on: the folder of every source and included file, and every
+    /// include directory.
+    fn folders(&self) -> Vec<PathBuf> {
+        let (_, include_dirs) = self.sources();
+        let mut folders: Vec<PathBuf> = Vec::new();
+        let parents = self
+            .source_files()
+            .into_iter()
+            .map(|file| match file.parent() {
+                Some(pth) if pth.is_dir() => pth.to_path_buf(),
+                _ => Path::new(".").to_path_buf(),
+            })
+            .chain(include_dirs.into_iter().filter(|dir| dir.is_dir()));
+        for folder in parents {
+            if !folders.contains(&folder) {
+                folders.push(folder);
+            }
+        }
+        if let WatchTarget::Project(project) = self {
+            if !folders.contains(&project.root) {
+                folders.push(project.root.clone());
+            }
+        }
+        folders
+    }
+}
+
+/// Path as reported by watch events: canonical parent directory, followed by file name.
+///
+/// File itself is not canonicalized, as editors may briefly remove it while saving.
+fn watch_path(path: &Path) -> Option<PathBuf> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    Some(fs::canonicalize(parent).ok()?.join(path.file_name()?))
+}
+
+fn watch(target: WatchTarget, action: WatchAction, message_format: MessageFormat) -> Result<()> {
+    let (sources, _) = target.sources();
+    let header = move || {
+        clear_screen();
+        for source in &sources {
+            file_message(MsgColor::Green, "Watching", source);
+        }
+    };
+    header();
+    message(MsgColor::Cyan, "Help", "press CTRL+C to exit");
+
+    let mut watcher =
+        Hotwatch::new_with_custom_delay(Duration::from_millis(500)).into_diagnostic()?;
+
+    let folders = target.folders();
+    let inputs = Rc::new(RefCell::new(target.inputs()));
+    let assemblers = Rc::new(RefCell::new(HashMap::new()));
+    let target = Rc::new(target);
+    // Vim breaks if watching a single file, so watch folders and filter events instead
+    for folder in folders {
+        let target = Rc::clone(&target);
+        let inputs = Rc::clone(&inputs);
+        let assemblers = Rc::clone(&assemblers);
+        let action = action.clone();
+        let header = header.clone();
+        watcher
+            .watch(folder, move |event: Event| match event.kind {
+                // Watch create and remove for editors which save by replacing files
+                EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) => {
+                    let changed = event
+                        .paths
+                        .iter()
+                        .any(|path| inputs.borrow().contains(path));
+                    if !changed {
+                        return Flow::Continue;
+                    }
+                    header();
+                    message(MsgColor::Green, "Re-checking", "file change detected");
+                    message(MsgColor::Cyan, "Help", "press CTRL+C to exit");
+
+                    // Now we are developing software (makes reruns more obvious)
+                    sleep(Duration::from_millis(50));
+
+                    update(
+                        &target,
+                        &action,
+                        message_format,
+                        &mut assemblers.borrow_mut(),
+                    );
+                    *inputs.borrow_mut() = target.inputs();
+                    Flow::Continue
+                }
+                _ => Flow::Continue,
+            })
+            .into_diagnostic()?;
+    }
+    watcher.run();
+    Ok(())
+}
+
+/// Check, run or test files again after a change, printing any errors.
+///
+/// Checks reuse the assembler for each source, so only changed lines are parsed again.
+fn update(
+    target: &WatchTarget,
+    action: &WatchAction,
+    message_format: MessageFormat,
+    assemblers: &mut HashMap<PathBuf, Assembler>,
+) {
+    let result = match (action, target) {
+        (WatchAction::Check, _) => {
+            let (sources, include_dirs) = target.sources();
+            for source in &sources {
+                let assembler = assemblers.entry(source.clone()).or_default();
+                recheck(source, &include_dirs, message_format, assembler);
+            }
+            Ok(())
+        }
+        (WatchAction::Run { input, max_steps }, _) => rerun(target, input.as_deref(), *max_steps),
+        (WatchAction::Test, WatchTarget::Project(project)) => {
+            // Test cases may have changed, but features cannot be changed while running
+            Project::load(&project.root.join(project::MANIFEST_NAME))
+                .and_then(|project| run_tests(&project))
+        }
+        (WatchAction::Test, WatchTarget::File(_)) => unreachable!("tests require a project"),
+    };
+    if let Err(e) = result {
+        println!("\n{:?}", e);
+    }
+}
+
+/// Check file again for `watch`, printing any errors.
+fn recheck(
+    name: &Path,
+    include_dirs: &[PathBuf],
+    message_format: MessageFormat,
+    assembler: &mut Assembler,
+) {
+    let mut contents = match read_source(name, include_dirs) {
+        Ok(contents) => contents,
+        Err(e) => {
+            println!("\n{:?}", e);
+            return;
+        }
+    };
+    let result = assembler.assemble(contents.src());
+    if message_format == MessageFormat::Human {
+        match result {
+            Ok(assembly) if assembly.warnings.is_empty() => {
+                file_message(MsgColor::Green, "Success", name);
+            }
+            // Assemble again for graphical reports
+            _ => match assemble(&contents) {
+                Ok(_) => {
+                    file_message(MsgColor::Green, "Success", name);
+                }
+                Err(e) => {
+                    println!("\n{:?}", e);
+                }
+            },
+        };
+    } else {
+        let diagnostics = match result {
+            Ok(assembly) => assembly.warnings,
+            Err(diagnostics) => diagnostics,
+        };
+        print_diagnostics(name, contents.src(), &diagnostics, message_format);
+    }
+
+    reset_state();
+    // To avoid leaking memory
+    contents.reclaim();
+}
+
+/// Assemble and run program again for `watch`, in a separate process so it can be stopped.
+fn rerun(target: &WatchTarget, input: Option<&Path>, max_steps: u64) -> Result<()> {
+    let binary = match target {
+        WatchTarget::File(name) => {
+            let mut artifacts = Artifacts::open(artifacts::DEFAULT_OUT_DIR)?;
+            let mut contents = read_source(name, &[])?;
+            let air = assemble(&contents)?;
+            let binary = artifacts.create(name, "lc3")?;
+            artifacts.save()?;
+            write_binary(&air, &binary)?;
+            reset_state();
+            contents.reclaim();
+            binary
+        }
+        WatchTarget::Project(project) => {
+            let mut artifacts = Artifacts::open(project.out_dir())?;
+            let entry = project.entry(None)?;
+            let built = build_entry(project, entry, &[OutputFormat::Lc3], &mut artifacts)?;
+            artifacts.save()?;
+            built[0].1.clone()
+        }
+    };
+
+    let stdin = match input {
+        Some(input) => Stdio::from(File::open(input).into_diagnostic()?),
+        None => Stdio::null(),
+    };
+    message(MsgColor::Green, "Running", "emitted binary");
+    let status = run_binary(&binary, max_steps)
+        .stdin(stdin)
+        .status()
+        .into_diagnostic()?;
+    if !status.success() {
+        message(MsgColor::Red, "Failed", "program exited with an error");
+    }
+    Ok(())
+}
+
+/// Command to run a compiled binary in a separate process, with the current features.
+fn run_binary(binary: &Path, max_steps: u64) -> std::process::Command {
+    // Executable is always available, unless it was deleted while running
+    let exe = std::env::current_exe().expect("could not find lace executable");
+    let mut cmd = std::process::Command::new(exe);
+    cmd.arg("run")
+        .arg("--minimal")
+        .arg("--message-format=json")
+        .arg(format!("--max-steps={max_steps}"))
+        .arg(binary);
+    let features = lace::features::enabled();
+    if !features.is_empty() {
+        cmd.arg(format!("--features={features}"));
+    }
+    cmd
+}
+
+/// Assemble entry of a project into each of `formats`, returning paths of every artifact.
+fn build_entry(
+    project: &Project,
+    entry: &Entry,
+    formats: &[OutputFormat],
+    artifacts: &mut Artifacts,
+) -> Result<Vec<(OutputFormat, PathBuf)>> {
+    let path = project.entry_path(entry);
+    file_message(MsgColor::Green, "Assembling", &path);
+    let mut contents = read_source(&path, &project.include_dirs())?;
+
+    let mut built = Vec::new();
+    for &format in formats {
+        let out_file_name = artifacts.create(&path, format.extension())?;
+        // Creates artifacts directory
+        artifacts.save()?;
+        match format {
+            OutputFormat::Lc3 => write_binary(&assemble(&contents)?, &out_file_name)?,
+            OutputFormat::Markdown | OutputFormat::Html => {
+                let format = match format {
+                    OutputFormat::Html => doc::Format::Html,
+                    _ => doc::Format::Markdown,
+                };
+                let subroutines = doc::document(contents.src())?;
+                let page = doc::render(&entry.name(), &subroutines, format);
+                fs::write(&out_file_name, page).into_diagnostic()?;
+            }
+        }
+        // Each format assembles the source again
+        reset_state();
+        built.push((format, out_file_name));
+    }
+    contents.reclaim();
+    Ok(built)
+}
+
+/// Run every test case of a project, in a separate process so console input can be provided.
+fn run_tests(project: &Project) -> Result<()> {
+    let mut artifacts = Artifacts::open(project.out_dir())?;
+    let mut binaries: Vec<(String, PathBuf)> = Vec::new();
+
+    let mut failed = 0;
+    for test in &project.manifest.tests {
+        let entry = project.entry(test.entry.as_deref())?;
+        let binary = match binaries.iter().find(|(name, _)| *name == entry.name()) {
+            Some((_, binary)) => binary.clone(),
+            None => {
+                let built = build_entry(project, entry, &[OutputFormat::Lc3], &mut artifacts)?;
+                let binary = built[0].1.clone();
+                binaries.push((entry.name(), binary.clone()));
+                binary
+            }
+        };
+
+        let mut child = run_binary(&binary, TEST_MAX_STEPS)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .into_diagnostic()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // Program may exit without reading all input
+            let _ = stdin.write_all(test.input.as_bytes());
+        }
+        let output = child.wait_with_output().into_diagnostic()?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let actual = program_output(&stdout);
+
+        if output.status.success() && actual == test.output.trim() {
+            message(MsgColor::Green, "Passed", &test.name);
+            continue;
+        }
+        failed += 1;
+        message(MsgColor::Red, "Failed", &test.name);
+        print_output_diff(test.output.trim(), actual);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !stderr.trim().is_empty() {
+            println!("error output:\n{}", stderr.trim());
+        }
+    }
+    artifacts.save()?;
+
+    let total = project.manifest.tests.len();
+    if failed > 0 {
+        bail!("{failed} of {total} tests failed");
+    }
+    message(
+        MsgColor::Green,
+        "Finished",
+        &format!("{total} tests passed"),
+    );
+    Ok(())
+}
+
+/// Print lines of expected and actual output which differ.
+fn print_output_diff(expected: &str, actual: &str) {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(left), Some(right)) if left == right => println!("  {left}"),
+            (left, right) => {
+                if let Some(left) = left {
+                    println!("{}", format!("- {left}").red());
+                }
+                if let Some(right) = right {
+                    println!("{}", format!("+ {right}").green());
+                }
+            }
+        }
+    }
+}
+
+/// Console output of a program, without the message printed on halt.
+fn program_output(stdout: &str) -> &str {
+    let stdout = stdout.trim_end();
+    stdout.strip_suffix("Halted").unwrap_or(stdout).trim()
+}
+
+/// Assemble source file, reporting diagnostics in the requested format.
+fn assemble_as(contents: &StaticSource, name: &Path, format: MessageFormat) -> Result<Air> {
+    if format == MessageFormat::Human {
+        return assemble(contents);
+    }
+    // Collect every diagnostic first, as the assembler stops at the first error
+    let diagnostics = diagnostic::check(contents.src());
+    print_diagnostics(name, contents.src(), &diagnostics, format);
+
+    let errors = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == miette::Severity::Error)
+        .count();
+    if errors > 0 {
+        bail!(
+            "Could not assemble {} due to {errors} errors",
+            name.display()
+        );
+    }
+    let mut air = lace::AsmParser::new(contents.src())?.parse()?;
+    air.backpatch()?;
+    Ok(air)
+}
+
+fn print_diagnostics(
+    name: &Path,
+    src: &str,
+    diagnostics: &[diagnostic::Diagnostic],
+    format: MessageFormat,
+) {
+    let file = name.to_string_lossy();
+    match format {
+        MessageFormat::Human => (),
+        MessageFormat::Json => {
+            for diagnostic in diagnostics {
+                println!("{}", diagnostic.to_json(&file, src));
+            }
+        }
+        MessageFormat::Sarif => {
+            let log = diagnostic::sarif(&file, src, diagnostics);
+            println!("{log:#}");
+        }
+    }
+}
+
+/// Instruction limit for test cases, so runaway programs fail instead of hanging.
+const TEST_MAX_STEPS: u64 = 10_000_000;
+
 const LOGO: &str = r#"
       ..                                  
 x .d88"                                   
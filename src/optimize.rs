@@ -0,0 +1,323 @@
+//! Peephole optimizer, run over backpatched [`Air`] by `lace compile --optimize`.
+//!
+//! Every rewrite removes one statement, so labels, label references and breakpoints after it are
+//! renumbered. Statements which a label points at are never removed, as they may be read as data.
+
+use fxhash::FxHashSet;
+use miette::Report;
+
+use crate::air::{Air, AirStmt, AsmLine, ImmediateOrReg, RawWord};
+use crate::error;
+use crate::symbol::{with_symbol_table, Label, Register, Span};
+
+/// A rewrite made by the optimizer, for reporting to the user.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rewrite {
+    pub kind: RewriteKind,
+    /// Location of every statement involved
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RewriteKind {
+    /// `ADD Rx, Rx, #0` followed by an instruction which sets the condition codes
+    NopAdd,
+    /// `BR` to the instruction directly after it
+    BranchNext,
+    /// Two immediate `ADD`s to the same register, replaced by a single `ADD`
+    FoldAdd {
+        dest: Register,
+        src_reg: Register,
+        imm: i16,
+    },
+    /// `ST` to a label which is never read
+    DeadStore { label: String },
+}
+
+impl Rewrite {
+    pub fn report(&self, src: &'static str) -> Report {
+        match &self.kind {
+            RewriteKind::NopAdd => error::opt_nop_add(self.span, src),
+            RewriteKind::BranchNext => error::opt_branch_next(self.span, src),
+            RewriteKind::FoldAdd { dest, src_reg, imm } => {
+                let replacement = format!("add r{}, r{}, #{imm}", *dest as u16, *src_reg as u16);
+                error::opt_fold_add(self.span, src, &replacement)
+            }
+            RewriteKind::DeadStore { label } => error::opt_dead_store(self.span, src, label),
+        }
+    }
+}
+
+/// Rewrite statements until no more rewrites apply.
+///
+/// Air must be backpatched. Symbol table is updated to match.
+pub fn optimize(air: &mut Air) -> Vec<Rewrite> {
+    let mut rewrites = Vec::new();
+    while let Some(rewrite) = rewrite_once(air) {
+        rewrites.push(rewrite);
+    }
+    rewrites
+}
+
+/// Apply the first rewrite found, if any.
+fn rewrite_once(air: &mut Air) -> Option<Rewrite> {
+    let labeled: FxHashSet<u16> = with_symbol_table(|sym| sym.values().copied().collect());
+    let reads_indirectly = reads_indirectly(air);
+
+    for i in 0..air.len() {
+        let stmt = &air.ast[i];
+        if labeled.contains(&stmt.line) {
+            continue;
+        }
+        let next = air.ast.get(i + 1);
+        match stmt.stmt {
+            AirStmt::Add {
+                dest,
+                src_reg,
+                src_reg_imm: ImmediateOrReg::Imm5(0),
+            } if dest == src_reg && next.is_some_and(sets_flags) => {
+                let span = stmt.span;
+                remove(air, i);
+                return Some(Rewrite {
+                    kind: RewriteKind::NopAdd,
+                    span,
+                });
+            }
+            AirStmt::Branch {
+                dest_label: Label::Ref(target),
+                ..
+            } if target == stmt.line + 1 => {
+                let span = stmt.span;
+                remove(air, i);
+                return Some(Rewrite {
+                    kind: RewriteKind::BranchNext,
+                    span,
+                });
+            }
+            AirStmt::Add {
+                dest,
+                src_reg,
+                src_reg_imm: ImmediateOrReg::Imm5(first),
+            } => {
+                let Some(next) = next.filter(|next| !labeled.contains(&next.line)) else {
+                    continue;
+                };
+                let AirStmt::Add {
+                    dest: next_dest,
+                    src_reg: next_src,
+                    src_reg_imm: ImmediateOrReg::Imm5(second),
+                } = next.stmt
+                else {
+                    continue;
+                };
+                let imm = imm5(first) + imm5(second);
+                if next_dest != dest || next_src != dest || !(-16..=15).contains(&imm) {
+                    continue;
+                }
+                let span = stmt.span.join(next.span);
+                air.ast[i].stmt = AirStmt::Add {
+                    dest,
+                    src_reg,
+                    src_reg_imm: ImmediateOrReg::Imm5(imm as u8 & 0b11111),
+                };
+                air.ast[i].span = span;
+                remove(air, i + 1);
+                return Some(Rewrite {
+                    kind: RewriteKind::FoldAdd { dest, src_reg, imm },
+                    span,
+                });
+            }
+            AirStmt::Store {
+                dest_label: Label::Ref(target),
+                ..
+            } if !reads_indirectly && is_dead(air, target) => {
+                let label = with_symbol_table(|sym| {
+                    sym.iter()
+                        .filter(|(_, line)| **line == target)
+                        .map(|(name, _)| name.clone())
+                        .min()
+                })
+                .unwrap_or_else(|| format!("line {target}"));
+                let span = stmt.span;
+                remove(air, i);
+                return Some(Rewrite {
+                    kind: RewriteKind::DeadStore { label },
+                    span,
+                });
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+fn sets_flags(stmt: &AsmLine) -> bool {
+    matches!(
+        stmt.stmt,
+        AirStmt::Add { .. }
+            | AirStmt::And { .. }
+            | AirStmt::Not { .. }
+            | AirStmt::Load { .. }
+            | AirStmt::LoadInd { .. }
+            | AirStmt::LoadOffs { .. }
+            | AirStmt::LoadEAddr { .. }
+    )
+}
+
+/// Whether the program may read memory without referring to a label, so that no store can be
+/// proven dead.
+///
+/// Pointer loads and stores may access any address, string traps read past the label they are
+/// given, and a `.fill` holding an address within the program stops pointing at the same word once
+/// a statement before it is removed.
+fn reads_indirectly(air: &Air) -> bool {
+    let orig = air.orig().unwrap_or(0x3000);
+    let end = orig.wrapping_add(air.len() as u16);
+    air.ast.iter().any(|stmt| match stmt.stmt {
+        AirStmt::LoadInd { .. }
+        | AirStmt::StoreInd { .. }
+        | AirStmt::LoadOffs { .. }
+        | AirStmt::StoreOffs { .. } => true,
+        // puts, putsp
+        AirStmt::Trap {
+            trap_vect: 0x22 | 0x24,
+        } => true,
+        AirStmt::RawWord { val: RawWord(val) } => (orig..end).contains(&val),
+        _ => false,
+    })
+}
+
+/// Whether the data word at `line` is only ever stored to.
+fn is_dead(air: &Air, line: u16) -> bool {
+    let is_data = line
+        .checked_sub(1)
+        .and_then(|i| air.ast.get(i as usize))
+        .is_some_and(|stmt| matches!(stmt.stmt, AirStmt::RawWord { .. }));
+    is_data
+        && air.ast.iter().all(|stmt| {
+            matches!(stmt.stmt, AirStmt::Store { .. })
+                || stmt.stmt.label() != Some(&Label::Ref(line))
+        })
+}
+
+/// Sign-extend a 5-bit immediate.
+fn imm5(val: u8) -> i16 {
+    ((val << 3) as i8 >> 3) as i16
+}
+
+/// Remove statement at `index`, renumbering everything after it.
+///
+/// References to the removed statement now refer to the statement after it.
+fn remove(air: &mut Air, index: usize) {
+    let removed = air.ast.remove(index).line;
+    let renumber = |line: &mut u16| {
+        if *line > removed {
+            *line -= 1;
+        }
+    };
+    for stmt in air.ast.iter_mut() {
+        renumber(&mut stmt.line);
+        if let Some(Label::Ref(line)) = stmt.stmt.label_mut() {
+            renumber(line);
+        }
+    }
+    for trap in air.traps.iter_mut() {
+        if let Label::Ref(line) = &mut trap.label {
+            renumber(line);
+        }
+    }
+    with_symbol_table(|sym| sym.values_mut().for_each(renumber));
+    for breakpoint in air.breakpoints.iter_mut() {
+        if breakpoint.address as usize > index {
+            breakpoint.address -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::AsmParser;
+
+    fn optimized(src: &'static str) -> (Vec<u16>, Vec<RewriteKind>) {
+        let mut air = AsmParser::new(src).unwrap().parse().unwrap();
+        air.backpatch().unwrap();
+        let rewrites = optimize(&mut air);
+        crate::reset_state();
+        let words = air.ast.iter().map(|stmt| stmt.emit().unwrap()).collect();
+        (
+            words,
+            rewrites.into_iter().map(|rewrite| rewrite.kind).collect(),
+        )
+    }
+
+    #[test]
+    fn removes_nops() {
+        let (words, rewrites) = optimized("add r1, r1, #0\nand r0, r0, #0\nbr next\nnext halt\n");
+        assert_eq!(words, [0x5020, 0xF025]);
+        assert_eq!(rewrites, [RewriteKind::NopAdd, RewriteKind::BranchNext]);
+
+        // Flags are read by the branch
+        let (words, rewrites) = optimized("loop add r1, r1, #0\nbrp loop\nhalt\n");
+        assert_eq!(words.len(), 3);
+        assert!(rewrites.is_empty());
+    }
+
+    #[test]
+    fn folds_adds() {
+        let (words, rewrites) =
+            optimized("add r1, r2, #5\nadd r1, r1, #-3\nadd r1, r1, #-2\nbrz done\ndone halt\n");
+        assert_eq!(
+            rewrites,
+            [
+                RewriteKind::FoldAdd {
+                    dest: Register::R1,
+                    src_reg: Register::R2,
+                    imm: 2,
+                },
+                RewriteKind::FoldAdd {
+                    dest: Register::R1,
+                    src_reg: Register::R2,
+                    imm: 0,
+                },
+                RewriteKind::BranchNext,
+            ]
+        );
+        // `add r1, r2, #0` copies a register, so it is kept
+        assert_eq!(words, [0x12A0, 0xF025]);
+    }
+
+    #[test]
+    fn removes_dead_stores() {
+        let (words, rewrites) = optimized(
+            "st r0, unused\nst r0, used\nld r1, used\nhalt\nunused .fill #0\nused .fill #0\n",
+        );
+        assert_eq!(
+            rewrites,
+            [RewriteKind::DeadStore {
+                label: "unused".to_string()
+            }]
+        );
+        // References are renumbered
+        assert_eq!(words, [0x3003, 0x2202, 0xF025, 0, 0]);
+    }
+
+    #[test]
+    fn keeps_stores_read_indirectly() {
+        // `buf` is read through `ptr`, which would point past it once the store is removed
+        let (_, rewrites) = optimized(
+            ".orig x3000\nld r0, char\nst r0, buf\nldi r0, ptr\nout\nhalt\nchar .fill x41\nptr .fill x3007\nbuf .fill #0\n",
+        );
+        assert!(rewrites.is_empty());
+
+        // `puts` reads past `msg` into `buf`
+        let (_, rewrites) =
+            optimized("st r0, buf\nlea r0, msg\nputs\nhalt\nmsg .fill x41\nbuf .fill #0\n");
+        assert!(rewrites.is_empty());
+
+        // `.fill` holds the address of `buf`, which is read through a register
+        let (_, rewrites) =
+            optimized(".orig x3000\nst r0, buf\nld r1, ptr\nhalt\nptr .fill x3004\nbuf .fill #0\n");
+        assert!(rewrites.is_empty());
+    }
+}
@@ -0,0 +1,130 @@
+; Default operating system image, loaded by `lace run --os`.
+;
+; Trap service routines run in supervisor mode, poll the device registers and return with `rti`.
+; They behave like the traps handled natively by lace, so programs print the same output either way.
+; Vectors without a routine are left as zero, and calling them raises an exception.
+.orig x0000
+
+; Trap vector table (x0000-x00FF)
+; Addresses must match the routines below
+.blkw #32
+.fill x0200                     ; x20 trap_getc
+.fill x0204                     ; x21 trap_out
+.fill x020A                     ; x22 trap_puts
+.fill x0219                     ; x23 trap_in
+.fill x0222                     ; x24 trap_putsp
+.fill x0248                     ; x25 trap_halt
+.blkw #218
+
+; Interrupt vector table (x0100-x01FF), filled in by programs
+.blkw #256
+
+; Read one character into r0, without echoing it
+trap_getc ldi r0, os_kbsr
+    brzp trap_getc
+    ldi r0, os_kbdr
+    rti
+
+; Print character in r0
+trap_out st r1, save_r1
+out_poll ldi r1, os_dsr
+    brzp out_poll
+    sti r0, os_ddr
+    ld r1, save_r1
+    rti
+
+; Print null-terminated string starting at address in r0, one character per word
+trap_puts st r0, save_r0
+    st r1, save_r1
+    st r2, save_r2
+    add r1, r0, #0
+puts_next ldr r0, r1, #0
+    brz puts_done
+puts_poll ldi r2, os_dsr
+    brzp puts_poll
+    sti r0, os_ddr
+    add r1, r1, #1
+    br puts_next
+puts_done ld r0, save_r0
+    ld r1, save_r1
+    ld r2, save_r2
+    rti
+
+; Read one character into r0, and echo it
+trap_in st r1, save_r1
+in_key ldi r0, os_kbsr
+    brzp in_key
+    ldi r0, os_kbdr
+in_poll ldi r1, os_dsr
+    brzp in_poll
+    sti r0, os_ddr
+    ld r1, save_r1
+    rti
+
+; Print null-terminated string starting at address in r0, two characters per word
+; High byte is printed first, like the native trap
+trap_putsp st r0, save_r0
+    st r1, save_r1
+    st r2, save_r2
+    st r3, save_r3
+    st r4, save_r4
+    st r5, save_r5
+    add r1, r0, #0
+putsp_next ldr r3, r1, #0
+    ; Shift high byte of r3 into r2
+    and r2, r2, #0
+    ld r4, high_bit
+    and r5, r5, #0
+    add r5, r5, #1
+putsp_shift and r0, r3, r4
+    brz putsp_skip
+    add r2, r2, r5
+putsp_skip add r5, r5, r5
+    add r4, r4, r4
+    brnp putsp_shift
+    add r2, r2, #0
+    brz putsp_done
+putsp_high ldi r0, os_dsr
+    brzp putsp_high
+    sti r2, os_ddr
+    ld r0, low_byte
+    and r2, r3, r0
+    brz putsp_done
+putsp_low ldi r0, os_dsr
+    brzp putsp_low
+    sti r2, os_ddr
+    add r1, r1, #1
+    br putsp_next
+putsp_done ld r0, save_r0
+    ld r1, save_r1
+    ld r2, save_r2
+    ld r3, save_r3
+    ld r4, save_r4
+    ld r5, save_r5
+    rti
+
+; Stop the clock, by clearing bit 15 of the machine control register
+trap_halt ldi r0, os_mcr
+    ld r1, clock_mask
+    and r0, r0, r1
+    sti r0, os_mcr
+    brnzp trap_halt
+
+; Device registers
+os_kbsr .fill xFE00
+os_kbdr .fill xFE02
+os_dsr .fill xFE04
+os_ddr .fill xFE06
+os_mcr .fill xFFFE
+
+high_bit .fill x0100
+low_byte .fill x00FF
+clock_mask .fill x7FFF
+
+save_r0 .fill #0
+save_r1 .fill #0
+save_r2 .fill #0
+save_r3 .fill #0
+save_r4 .fill #0
+save_r5 .fill #0
+.end
@@ -0,0 +1,63 @@
+//! Operating system images, for `lace run --os`.
+//!
+//! With an operating system loaded, `TRAP` jumps through the trap vector table in supervisor mode,
+//! instead of being handled natively, so students can write and debug their own service routines.
+
+use miette::Result;
+
+use crate::AsmParser;
+
+/// Source of the bundled operating system, with a routine for every standard trap.
+pub const DEFAULT_OS: &str = include_str!("os.asm");
+
+/// Assemble the bundled operating system.
+pub fn default_image() -> Result<Vec<u16>> {
+    assemble(DEFAULT_OS)
+}
+
+/// Assemble operating system source into an image, starting with its origin.
+///
+/// Symbol table is reset afterwards, so a program can be assembled next.
+pub fn assemble(src: &'static str) -> Result<Vec<u16>> {
+    let image = assemble_inner(src);
+    crate::reset_state();
+    image
+}
+
+fn assemble_inner(src: &'static str) -> Result<Vec<u16>> {
+    let mut air = AsmParser::new(src)?.parse()?;
+    air.backpatch()?;
+    let mut image = Vec::with_capacity(air.len() + 1);
+    image.push(air.orig().unwrap_or(0));
+    for stmt in &air {
+        image.push(stmt.emit()?);
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::symbol::with_symbol_table;
+
+    #[test]
+    fn trap_table_matches_routines() {
+        let image = default_image().unwrap();
+        assert_eq!(image[0], 0x0000);
+        // Symbol table was reset
+        AsmParser::new(DEFAULT_OS).unwrap().parse().unwrap();
+        for (vector, name) in [
+            (0x20, "trap_getc"),
+            (0x21, "trap_out"),
+            (0x22, "trap_puts"),
+            (0x23, "trap_in"),
+            (0x24, "trap_putsp"),
+            (0x25, "trap_halt"),
+        ] {
+            let line = with_symbol_table(|sym| sym.get(name).copied()).unwrap();
+            // Image is prefixed with origin, and lines are one-based
+            assert_eq!(image[vector + 1], line - 1, "{name}");
+        }
+        crate::reset_state();
+    }
+}
@@ -143,6 +143,8 @@ impl Output {
     thread_local! {
         /// Only access using [`Output::is_minimal`] and [`Output::set_minimal`].
         static IS_MINIMAL: RefCell<bool> = const { RefCell::new(false) };
+        /// Only access using [`Output::capture`].
+        static CAPTURED: RefCell<Option<String>> = const { RefCell::new(None) };
     }
     /// Whether output willl be printed 'minimally'.
     ///
@@ -157,6 +159,17 @@ impl Output {
         Self::IS_MINIMAL.with(|value| value.replace(new_value));
     }
 
+    /// Return everything printed to [`Output::Normal`] by `print`, instead of writing to `stdout`.
+    ///
+    /// Used to write formatted output through a program's [`crate::io::Io`] backend.
+    pub fn capture(print: impl FnOnce()) -> String {
+        Self::CAPTURED.with(|captured| captured.replace(Some(String::new())));
+        print();
+        Self::CAPTURED
+            .with(|captured| captured.take())
+            .expect("output should still be captured")
+    }
+
     /// If cursor is NOT at the start of a line, then start a new line (ie. print '\n').
     ///
     /// Relies on previously-printed strings to keep track of cursor position. This is done
@@ -215,7 +228,7 @@ impl Output {
                 self.print(format_args!("R{} x{:04x}\n", i, state.reg(i)));
             }
             self.print(format_args!("PC x{:04x}\n", state.pc()));
-            self.print(format_args!("CC {:03b}\n", state.flag() as u8));
+            self.print(format_args!("CC {:03b}\n", state.flag()));
             return;
         }
 
@@ -237,7 +250,7 @@ impl Output {
         self.print(format_args!(" 0x{:04x}", state.pc()));
         self.print("\x1b[2m    │    \x1b[0m");
         self.print(" \x1b[1mCC\x1b[0m");
-        self.print(format_args!("  {:03b}", state.flag() as u8));
+        self.print(format_args!("  {:03b}", state.flag()));
         self.print("     \x1b[2m│\x1b[0m\n");
 
         self.print("\x1b[2m└─────────────────┴─────────────────┘\x1b[0m\n");
@@ -453,6 +466,22 @@ struct NormalWriter {
 impl fmt::Write for NormalWriter {
     /// Must never fail.
     fn write_str(&mut self, string: &str) -> fmt::Result {
+        let captured = Output::CAPTURED.with(|captured| {
+            let mut captured = captured.borrow_mut();
+            let Some(captured) = captured.as_mut() else {
+                return false;
+            };
+            if self.minimal {
+                write!(captured, "{}", Decolored::new(string))
+                    .expect("writing to `String` should never fail");
+            } else {
+                captured.push_str(string);
+            }
+            true
+        });
+        if captured {
+            return Ok(());
+        }
         if self.minimal {
             print!("{}", Decolored::new(string));
         } else {
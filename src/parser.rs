@@ -1,28 +1,52 @@
 use std::{borrow::Cow, fmt::Display, iter::Peekable, vec::IntoIter};
 
-use miette::Result;
+use miette::{Report, Result};
 
 use crate::{
-    air::{Air, AirStmt, ImmediateOrReg, RawWord},
+    air::{Air, AirStmt, ImmediateOrReg, RawWord, TrapHandler},
+    cst::SyntaxTree,
     debugger::Breakpoint,
-    error,
-    lexer::{cursor::Cursor, LiteralKind, Token, TokenKind},
-    symbol::{DirKind, InstrKind, Label, Register, Span, SrcOffset, TrapKind},
+    error, features,
+    lexer::{
+        cursor::Cursor, LiteralKind, Token, TokenKind, INSTRUCTIONS, STACK_INSTRUCTIONS, TRAPS,
+    },
+    symbol::{self, DirKind, InstrKind, Label, Register, Span, SrcOffset, TrapKind},
 };
 
+/// Lower a lossless syntax tree into the preprocessed token stream.
+/// Whitespace and comments are discarded.
+///
 /// Replaces raw value directives .fill, .blkw, .stringz with equivalent raw bytes
 /// Returns a 'final' vector of tokens. This is easier than working with an iterator that can
 /// either return a single token or a Vec of tokens.
-pub fn preprocess(src: &'static str) -> Result<Vec<Token>> {
+///
+/// Non-fatal diagnostics are appended to `warnings`.
+pub fn lower(tree: &SyntaxTree, warnings: &mut Vec<Report>) -> Result<Vec<Token>> {
+    let src = tree.src();
+    if features::strict() {
+        check_strict_bounds(tree)?;
+    }
     let mut res: Vec<Token> = Vec::new();
-    let mut cur = Cursor::new(src);
+    let mut toks = tree.significant_tokens();
+    let mut next = || {
+        let tok = toks
+            .next()
+            .unwrap_or(Token::new(TokenKind::Eof, Span::dummy()));
+        // Substitute constants from project manifest
+        match symbol::define(&src[tok.span.as_range()]) {
+            Some(value) if tok.kind == TokenKind::Label => {
+                Token::new(TokenKind::Lit(LiteralKind::Hex(value)), tok.span)
+            }
+            _ => tok,
+        }
+    };
 
     loop {
-        let dir = cur.advance_real()?;
+        let dir = next();
         match dir.kind {
             // Into raw word with the next literal as value
             TokenKind::Dir(DirKind::Fill) => {
-                let val = cur.advance_real()?;
+                let val = next();
                 // Span entire directive name and integer literal
                 let span = dir.span.join(val.span);
                 match val.kind {
@@ -37,7 +61,7 @@ pub fn preprocess(src: &'static str) -> Result<Vec<Token>> {
             }
             // Into a series of raw null words
             TokenKind::Dir(DirKind::Blkw) => {
-                let val = cur.advance_real()?;
+                let val = next();
                 let span = dir.span.join(val.span);
                 match val.kind {
                     TokenKind::Lit(LiteralKind::Hex(lit)) => {
@@ -47,7 +71,7 @@ pub fn preprocess(src: &'static str) -> Result<Vec<Token>> {
                     }
                     TokenKind::Lit(LiteralKind::Dec(lit)) => {
                         if lit < 0 {
-                            println!("{:?}", error::preproc_bad_lit(val.span, src, true));
+                            warnings.push(error::preproc_bad_lit(val.span, src, true));
                         }
                         for _ in 0..lit as u16 {
                             res.push(Token::nullbyte(span));
@@ -58,10 +82,10 @@ pub fn preprocess(src: &'static str) -> Result<Vec<Token>> {
             }
             // str into a sequence of bytes corresponding to a literal + null terminator
             TokenKind::Dir(DirKind::Stringz) => {
-                let val = cur.advance_real()?;
+                let val = next();
                 match val.kind {
                     TokenKind::Lit(LiteralKind::Str) => {
-                        let str_raw = cur.get_range(val.span.into());
+                        let str_raw = &src[val.span.as_range()];
                         let span = dir.span.join(val.span);
                         // Get rid of quotation marks
                         for c in unescape(&str_raw[1..str_raw.len() - 1]).chars() {
@@ -72,20 +96,47 @@ pub fn preprocess(src: &'static str) -> Result<Vec<Token>> {
                     _ => return Err(error::preproc_no_str(val.span, src)),
                 }
             }
+            // Feature was already enabled when building syntax tree
+            TokenKind::Dir(DirKind::Feature) => {
+                let name = next();
+                match name.kind {
+                    TokenKind::Label => (),
+                    TokenKind::Eof => return Err(error::parse_eof(src)),
+                    _ => return Err(error::parse_generic_unexpected(src, "feature name", name)),
+                }
+            }
             TokenKind::Dir(DirKind::Break) => {
                 // Note that this span will never be used
                 // Since breakpoints don't push bytes
                 res.push(Token::breakpoint(dir.span));
             }
-            // Eliminated during preprocessing
-            TokenKind::Comment | TokenKind::Whitespace => continue,
             TokenKind::Eof | TokenKind::Dir(DirKind::End) => break,
+            // Eliminated when building significant token stream
+            TokenKind::Comment | TokenKind::DocComment | TokenKind::Whitespace => {
+                unreachable!("Found whitespace/comment in significant token stream")
+            }
             _ => res.push(dir),
         }
     }
     Ok(res)
 }
 
+/// Strict mode requires programs to begin with `.orig` and finish with `.end`.
+fn check_strict_bounds(tree: &SyntaxTree) -> Result<()> {
+    let src = tree.src();
+    let mut toks = tree.significant_tokens();
+    match toks.next() {
+        Some(tok) if tok.kind == TokenKind::Dir(DirKind::Orig) => (),
+        Some(tok) => return Err(error::preproc_strict_no_orig(tok.span, src)),
+        None => return Err(error::preproc_strict_no_orig((0..0).into(), src)),
+    }
+    // Lexing stops after `.end`, so it must be the final token
+    match toks.last() {
+        Some(tok) if tok.kind == TokenKind::Dir(DirKind::End) => Ok(()),
+        _ => Err(error::preproc_strict_no_end(src)),
+    }
+}
+
 fn preprocess_simple(src: &'static str) -> Result<Vec<Token>> {
     let mut res: Vec<Token> = Vec::new();
     let mut cur = Cursor::new(src);
@@ -95,7 +146,7 @@ fn preprocess_simple(src: &'static str) -> Result<Vec<Token>> {
         match token.kind {
             TokenKind::Byte(_) => unreachable!("Found byte in stream"),
             TokenKind::Breakpoint => unreachable!("Found breakpoint in stream"),
-            TokenKind::Comment | TokenKind::Whitespace => continue,
+            TokenKind::Comment | TokenKind::DocComment | TokenKind::Whitespace => continue,
             TokenKind::Eof => break,
 
             _ => res.push(token),
@@ -105,7 +156,7 @@ fn preprocess_simple(src: &'static str) -> Result<Vec<Token>> {
     Ok(res)
 }
 
-fn unescape(s: &str) -> Cow<str> {
+fn unescape(s: &str) -> Cow<'_, str> {
     if s.find('\\').is_none() {
         return Cow::Borrowed(s);
     }
@@ -148,31 +199,49 @@ pub struct AsmParser {
     line: u16,
 
     tok_end: usize,
+    /// Non-fatal diagnostics found while preprocessing
+    warnings: Vec<Report>,
+    /// Prefix labels and their line, when parsing a fragment of a file.
+    /// `None` when parsing a whole file, where labels go straight into the symbol table.
+    fragment_labels: Option<Vec<(String, u16)>>,
 }
 
 impl AsmParser {
     /// Preprocesses tokens, otherwise will go into unreachable code. Input should
     /// contain no whitespace or comments.
     pub fn new(src: &'static str) -> Result<Self> {
-        let toks = preprocess(src)?;
-        Ok(AsmParser {
+        Self::from_tree(&SyntaxTree::parse(src)?)
+    }
+
+    /// Create parser from an existing lossless syntax tree.
+    pub fn from_tree(tree: &SyntaxTree) -> Result<Self> {
+        let mut warnings = Vec::new();
+        let toks = lower(tree, &mut warnings)?;
+        let mut parser = Self::from_tokens(tree.src(), toks);
+        parser.warnings = warnings;
+        Ok(parser)
+    }
+
+    fn from_tokens(src: &'static str, toks: Vec<Token>) -> Self {
+        AsmParser {
             src,
             toks: toks.into_iter().peekable(),
             air: Air::new(src),
             line: 1,
             tok_end: 0,
-        })
+            warnings: Vec::new(),
+            fragment_labels: None,
+        }
     }
 
     pub fn new_simple(src: &'static str) -> Result<Self> {
         let toks = preprocess_simple(src)?;
-        Ok(AsmParser {
-            src,
-            toks: toks.into_iter().peekable(),
-            air: Air::new(src),
-            line: 1,
-            tok_end: 0,
-        })
+        Ok(Self::from_tokens(src, toks))
+    }
+
+    /// Non-fatal diagnostics found while preprocessing, such as negative `.blkw` sizes.
+    pub fn warnings(&self) -> &[Report] {
+        &self.warnings
     }
 
     fn get_span(&self, span: Span) -> &str {
@@ -181,14 +250,31 @@ impl AsmParser {
 
     /// Create AIR out of token stream
     pub fn parse(mut self) -> Result<Air> {
+        self.parse_lines()?;
+        Ok(self.air)
+    }
+
+    /// Create AIR out of part of a file, such as a single line.
+    ///
+    /// Prefix labels are returned rather than added to the symbol table, so label references are
+    /// left unfilled. A trailing prefix label is allowed, as it may belong to a statement later in
+    /// the file.
+    pub(crate) fn parse_fragment(mut self) -> Result<(Air, Vec<(String, u16)>)> {
+        self.fragment_labels = Some(Vec::new());
+        self.parse_lines()?;
+        Ok((self.air, self.fragment_labels.unwrap_or_default()))
+    }
+
+    fn parse_lines(&mut self) -> Result<()> {
         loop {
-            let mut labeled_line = false;
             // Add prefix label to symbol table if exists
-            if let Some(label) = self.optional_label() {
-                labeled_line = true;
-                match Label::insert(self.get_span(label.span), self.line) {
-                    Ok(_) => (),
-                    Err(_) => return Err(error::parse_duplicate_label(label.span, self.src)),
+            let prefix = self.optional_label();
+            if let Some(label) = prefix {
+                let name = self.get_span(label.span).to_string();
+                if let Some(labels) = &mut self.fragment_labels {
+                    labels.push((name, self.line));
+                } else if Label::insert(&name, self.line).is_err() {
+                    return Err(error::parse_duplicate_label(label.span, self.src));
                 }
             }
 
@@ -197,16 +283,16 @@ impl AsmParser {
                 let stmt = match tok.kind {
                     // Lines should not start with these tokens
                     TokenKind::Label | TokenKind::Lit(_) | TokenKind::Reg(_) => {
-                        return Err(error::parse_generic_unexpected(
-                            self.src,
-                            "directive/instruction/trap",
-                            tok,
-                        ))
+                        return Err(self.unexpected_line_start(prefix, tok))
+                    }
+                    TokenKind::Dir(DirKind::Trap) => {
+                        self.parse_trap_handler(tok.span)?;
+                        continue;
                     }
                     TokenKind::Dir(dir) => {
                         assert!(dir == DirKind::Orig);
                         let orig = self.expect_lit(Bits::Unsigned(16))?;
-                        self.air.set_orig(orig)?;
+                        self.air.set_orig(orig, tok.span)?;
                         continue;
                     }
                     TokenKind::Breakpoint => {
@@ -221,7 +307,10 @@ impl AsmParser {
                     TokenKind::Trap(trap_kind) => self.parse_trap(trap_kind)?,
                     TokenKind::Byte(val) => self.parse_byte(val),
                     // Does not exist in preprocessed token stream
-                    TokenKind::Whitespace | TokenKind::Comment | TokenKind::Eof => {
+                    TokenKind::Whitespace
+                    | TokenKind::Comment
+                    | TokenKind::DocComment
+                    | TokenKind::Eof => {
                         unreachable!("Found whitespace/comment/eof in preprocessed stream")
                     }
                 };
@@ -234,15 +323,18 @@ impl AsmParser {
                 let span = Span::new(SrcOffset(tok.span.offs()), len);
                 self.air.add_stmt(stmt, span);
             } else {
-                if labeled_line {
-                    return Err(error::parse_eof(self.src));
+                if let Some(label) = prefix.filter(|_| self.fragment_labels.is_none()) {
+                    return Err(match self.misspelled_mnemonic(label) {
+                        Some(err) => err,
+                        None => error::parse_eof(self.src),
+                    });
                 }
                 break;
             }
 
             self.line += 1;
         }
-        Ok(self.air)
+        Ok(())
     }
 
     pub fn parse_simple(&mut self) -> Result<AirStmt> {
@@ -255,15 +347,15 @@ impl AsmParser {
             TokenKind::Trap(trap_kind) => self.parse_trap(trap_kind)?,
 
             TokenKind::Dir(_) | TokenKind::Label | TokenKind::Lit(_) | TokenKind::Reg(_) => {
-                return Err(error::parse_generic_unexpected(
-                    self.src,
-                    "instruction",
-                    tok,
-                ))
+                return Err(match self.misspelled_mnemonic(tok) {
+                    Some(err) => err,
+                    None => error::parse_generic_unexpected(self.src, "instruction", tok),
+                })
             }
 
             // Does not exist in preprocessed token stream
             TokenKind::Comment
+            | TokenKind::DocComment
             | TokenKind::Whitespace
             | TokenKind::Eof
             | TokenKind::Byte(_)
@@ -277,6 +369,34 @@ impl AsmParser {
         Ok(stmt)
     }
 
+    /// Error for a line which does not begin with an instruction, trap or directive.
+    fn unexpected_line_start(&self, prefix: Option<Token>, found: Token) -> Report {
+        // Misspelled mnemonics are lexed as labels
+        [Some(found), prefix]
+            .into_iter()
+            .flatten()
+            .find_map(|tok| self.misspelled_mnemonic(tok))
+            .unwrap_or_else(|| {
+                error::parse_generic_unexpected(self.src, "directive/instruction/trap", found)
+            })
+    }
+
+    /// Error suggesting a mnemonic, if the label token looks like a misspelled one.
+    fn misspelled_mnemonic(&self, tok: Token) -> Option<Report> {
+        if tok.kind != TokenKind::Label {
+            return None;
+        }
+        let mnemonics = INSTRUCTIONS
+            .iter()
+            .chain(TRAPS)
+            .filter(|mnemonic| features::stack() || !STACK_INSTRUCTIONS.contains(mnemonic))
+            .copied();
+        let suggestion = error::closest_match(self.get_span(tok.span), mnemonics)?;
+        Some(error::parse_unknown_mnemonic(
+            tok.span, self.src, suggestion,
+        ))
+    }
+
     /// Return label or leave iter untouched and return None
     fn optional_label(&mut self) -> Option<Token> {
         match self.toks.peek() {
@@ -299,7 +419,7 @@ impl AsmParser {
             }
             InstrKind::Call => {
                 let label_tok = self.expect(TokenKind::Label)?;
-                let dest_label = Label::try_fill(self.get_span(label_tok.span));
+                let dest_label = Label::try_fill(self.get_span(label_tok.span), label_tok.span);
                 Ok(AirStmt::Call { dest_label })
             }
             InstrKind::Rets => Ok(AirStmt::Rets),
@@ -417,6 +537,27 @@ impl AsmParser {
         Ok(AirStmt::Trap { trap_vect })
     }
 
+    /// Register handler for an unused trap vector, such as `.trap x30, handler`.
+    fn parse_trap_handler(&mut self, dir_span: Span) -> Result<()> {
+        let vector_span = self.toks.peek().map(|tok| tok.span);
+        let vector = self.expect_lit(Bits::Unsigned(8))? as u8;
+        if (0x20..=0x27).contains(&vector) {
+            return Err(error::parse_trap_native(
+                vector_span.unwrap_or(dir_span),
+                self.src,
+                vector,
+            ));
+        }
+        let label_tok = self.expect(TokenKind::Label)?;
+        let label = Label::try_fill(self.get_span(label_tok.span), label_tok.span);
+        self.air.traps.push(TrapHandler {
+            vector,
+            label,
+            span: dir_span.join(label_tok.span),
+        });
+        Ok(())
+    }
+
     fn parse_byte(&mut self, val: u16) -> AirStmt {
         AirStmt::RawWord { val: RawWord(val) }
     }
@@ -524,7 +665,7 @@ impl AsmParser {
             Some(tok) => match tok.kind {
                 TokenKind::Label => {
                     let label_tok = self.expect(TokenKind::Label)?;
-                    let label = Label::try_fill(self.get_span(label_tok.span));
+                    let label = Label::try_fill(self.get_span(label_tok.span), label_tok.span);
                     Ok(label)
                 }
                 TokenKind::Lit(_) => {
@@ -570,7 +711,50 @@ mod test {
         symbol::{Flag, Register},
     };
 
+    fn preprocess(src: &'static str) -> Result<Vec<Token>> {
+        lower(&SyntaxTree::parse(src)?, &mut Vec::new())
+    }
+
+    #[test]
+    fn feature_pragma_without_init() {
+        let air = AsmParser::new(".feature stack\npush r0\nhalt\n")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(air.len(), 2);
+        assert!(crate::features::stack());
+    }
+
     // .FILL TEST
+    #[test]
+    fn misspelled_mnemonic() {
+        for (src, suggestion) in [
+            ("addd r0 r0 r1", "add"),
+            ("label haltt", "halt"),
+            ("lable ADDD r0 r0 r1", "add"),
+            ("hlt", "halt"),
+        ] {
+            let Err(report) = AsmParser::new(src).unwrap().parse() else {
+                panic!("{src} was accepted");
+            };
+            assert_eq!(
+                report.help().unwrap().to_string(),
+                format!("did you mean `{suggestion}`?"),
+                "{src}"
+            );
+            crate::reset_state();
+        }
+    }
+
+    #[test]
+    fn strict_requires_orig_and_end() {
+        crate::features::init("strict".parse().unwrap());
+        assert!(preprocess(".orig x3000\nhalt\n.end").is_ok());
+        assert!(preprocess("halt\n.end").is_err());
+        assert!(preprocess(".orig x3000\nhalt").is_err());
+        assert!(preprocess("").is_err());
+    }
+
     #[test]
     fn preproc_fill() {
         let res = preprocess("temp .fill x3000").unwrap();
@@ -783,7 +967,7 @@ mod test {
                 line: 1,
                 stmt: AirStmt::Branch {
                     flag: Flag::Nzp,
-                    dest_label: Label::empty("label")
+                    dest_label: Label::empty("label", Span::new(SrcOffset(3), "label".len()))
                 },
                 span: Span::new(SrcOffset(0), "br label".len())
             }
@@ -962,7 +1146,19 @@ mod test {
                 line: 3,
                 stmt: AirStmt::Branch {
                     flag: Flag::Nzp,
-                    dest_label: Label::empty("not_existing")
+                    dest_label: Label::empty(
+                        "not_existing",
+                        Span::new(
+                            SrcOffset(
+                                r#"
+        label add r0 r0 r0
+              br label
+              br "#
+                                    .len()
+                            ),
+                            "not_existing".len()
+                        )
+                    )
                 },
                 span: Span::new(
                     SrcOffset(
@@ -998,4 +1194,26 @@ mod test {
             }
         );
     }
+
+    #[test]
+    fn parse_trap_handler() {
+        let mut air =
+            AsmParser::new(".orig x3000\n.trap x30, handler\ntrap x30\nhalt\nhandler ret\n")
+                .unwrap()
+                .parse()
+                .unwrap();
+        air.backpatch().unwrap();
+        crate::reset_state();
+        assert_eq!(air.len(), 3);
+        assert_eq!(air.trap_handlers().collect::<Vec<_>>(), [(0x30, 0x3002)]);
+
+        let Err(report) = AsmParser::new(".trap x21, handler\nhandler ret\n")
+            .unwrap()
+            .parse()
+        else {
+            panic!("native vector was accepted");
+        };
+        crate::reset_state();
+        assert_eq!(report.code().unwrap().to_string(), "parse::trap_native");
+    }
 }
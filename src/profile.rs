@@ -0,0 +1,311 @@
+//! Instruction-level profiler, for `lace run --profile`.
+//!
+//! Counts how often each address is executed and each subroutine is called, and tracks the call
+//! stack so the time spent in each subroutine can be written as folded stacks for flamegraphs.
+
+use std::fmt::{self, Write as _};
+
+use fxhash::FxHashMap;
+
+use crate::runtime::{nearest_label, HALT_ADDRESS, MEMORY_MAX};
+use crate::symbol::Span;
+use crate::trace::{disassemble, is_call, is_return};
+
+/// Amount of lines listed in the hottest lines of a report.
+const HOTTEST_LINES: usize = 10;
+
+/// Name of the frame at the bottom of every call stack.
+const ROOT_FRAME: &str = "main";
+
+/// Counts executed instructions and subroutine calls.
+pub struct Profiler {
+    /// Executions of each address
+    counts: Vec<u64>,
+    /// Calls of each subroutine address
+    calls: FxHashMap<u16, u64>,
+    /// Every call stack which has been entered, as its parent and subroutine address
+    /// Root stack has no parent
+    stacks: Vec<(Option<usize>, u16)>,
+    /// Index of each stack, by its parent and subroutine address
+    children: FxHashMap<(usize, u16), usize>,
+    /// Instructions executed while each stack was current
+    stack_counts: Vec<u64>,
+    /// Current call stack
+    stack: usize,
+    /// Call stack to return to, and return address, of each active call
+    frames: Vec<(usize, u16)>,
+    total: u64,
+}
+
+/// Source code of a program, to show the hottest lines.
+pub(crate) struct ProgramSource {
+    pub src: &'static str,
+    pub orig: u16,
+    /// Span of each word of the program
+    pub spans: Vec<Span>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; MEMORY_MAX],
+            calls: FxHashMap::default(),
+            stacks: vec![(None, 0)],
+            children: FxHashMap::default(),
+            stack_counts: vec![0],
+            stack: 0,
+            frames: Vec::new(),
+            total: 0,
+        }
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count instruction at `pc`, which jumped to `next_pc`.
+    pub(crate) fn record(&mut self, pc: u16, instr: u16, next_pc: u16) {
+        self.counts[pc as usize] += 1;
+        self.stack_counts[self.stack] += 1;
+        self.total += 1;
+
+        let return_addr = pc.wrapping_add(1);
+        // Native traps return straight away, or halt
+        if is_call(instr) && next_pc != return_addr && next_pc != HALT_ADDRESS {
+            *self.calls.entry(next_pc).or_default() += 1;
+            self.frames.push((self.stack, return_addr));
+            let next_stack = self.stacks.len();
+            self.stack = *self
+                .children
+                .entry((self.stack, next_pc))
+                .or_insert_with(|| {
+                    self.stacks.push((Some(self.stack), next_pc));
+                    self.stack_counts.push(0);
+                    next_stack
+                });
+        } else if is_return(instr) {
+            // Unwind any calls which never returned
+            if let Some(i) = self.frames.iter().rposition(|(_, addr)| *addr == next_pc) {
+                self.stack = self.frames[i].0;
+                self.frames.truncate(i);
+            }
+        }
+    }
+
+    /// Report of instructions executed and calls made in each labelled section, and the hottest
+    /// source lines.
+    ///
+    /// Code outside of `source` (or a binary without source) is shown as disassembly of `mem`.
+    pub(crate) fn report(
+        &self,
+        labels: &[(u16, String)],
+        source: Option<&ProgramSource>,
+        mem: &[u16],
+    ) -> String {
+        let mut report = String::new();
+        let total = self.total.max(1) as f64;
+        let percent = |count: u64| format!("{:.1}%", count as f64 * 100.0 / total);
+        let executed = || {
+            self.counts
+                .iter()
+                .enumerate()
+                .filter(|(_, count)| **count > 0)
+                .map(|(addr, count)| (addr as u16, *count))
+        };
+
+        // Instructions and calls of each label
+        let mut sections: FxHashMap<&str, (u64, u64)> = FxHashMap::default();
+        for (addr, count) in executed() {
+            sections.entry(section_name(labels, addr)).or_default().0 += count;
+        }
+        for (addr, calls) in &self.calls {
+            sections.entry(section_name(labels, *addr)).or_default().1 += calls;
+        }
+        let mut sections: Vec<_> = sections.into_iter().collect();
+        sections.sort_by(|(a_name, (a, _)), (b_name, (b, _))| b.cmp(a).then(a_name.cmp(b_name)));
+
+        let write = &mut report;
+        writeln!(write, "Profile of {} instructions", self.total).unwrap();
+        writeln!(write).unwrap();
+        writeln!(
+            write,
+            "{:<20} {:>12} {:>7} {:>8}",
+            "Label", "Instructions", "%", "Calls"
+        )
+        .unwrap();
+        for (name, (count, calls)) in &sections {
+            let calls = match calls {
+                0 => String::new(),
+                calls => calls.to_string(),
+            };
+            let row = format!(
+                "{:<20} {:>12} {:>7} {:>8}",
+                name,
+                count,
+                percent(*count),
+                calls
+            );
+            writeln!(write, "{}", row.trim_end()).unwrap();
+        }
+
+        // Hottest lines, or addresses outside of source
+        let line_starts = source.map(|source| line_starts(source.src));
+        let line_of = |addr: u16| {
+            let (source, line_starts) = source.zip(line_starts.as_deref())?;
+            source_line(source, line_starts, addr)
+        };
+        let mut lines: FxHashMap<Location, (u64, String)> = FxHashMap::default();
+        for (addr, count) in executed() {
+            let (location, text) = match line_of(addr) {
+                Some((line, text)) => (Location::Line(line), text),
+                None => (
+                    Location::Address(addr),
+                    disassemble(addr, mem[addr as usize]),
+                ),
+            };
+            lines.entry(location).or_insert((0, text)).0 += count;
+        }
+        let mut lines: Vec<_> = lines.into_iter().collect();
+        lines.sort_by(|(a_location, (a, _)), (b_location, (b, _))| {
+            b.cmp(a).then(a_location.cmp(b_location))
+        });
+
+        writeln!(write).unwrap();
+        writeln!(write, "Hottest lines").unwrap();
+        writeln!(
+            write,
+            "{:>6} {:>12} {:>7}  Source",
+            "Line", "Executions", "%"
+        )
+        .unwrap();
+        for (location, (count, text)) in lines.iter().take(HOTTEST_LINES) {
+            writeln!(
+                write,
+                "{:>6} {:>12} {:>7}  {}",
+                location.to_string(),
+                count,
+                percent(*count),
+                text
+            )
+            .unwrap();
+        }
+        report
+    }
+
+    /// Instructions executed in each call stack, in the folded format read by flamegraph tools.
+    ///
+    /// Each line has the frames of a stack separated by `;`, and a count.
+    pub(crate) fn folded(&self, labels: &[(u16, String)]) -> String {
+        let mut folded = String::new();
+        for (stack, count) in self.stack_counts.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            let mut frames = Vec::new();
+            let mut next = Some(stack);
+            while let Some(stack) = next {
+                let (parent, addr) = self.stacks[stack];
+                frames.push(match parent {
+                    Some(_) => frame_name(labels, addr),
+                    None => ROOT_FRAME.to_string(),
+                });
+                next = parent;
+            }
+            frames.reverse();
+            writeln!(folded, "{} {count}", frames.join(";")).unwrap();
+        }
+        folded
+    }
+}
+
+/// Source line (starting at 1), or address of code outside of the source.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Location {
+    Line(usize),
+    Address(u16),
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Line(line) => write!(f, "{line}"),
+            Self::Address(addr) => write!(f, "x{addr:04x}"),
+        }
+    }
+}
+
+/// Label of the section containing `addr`.
+fn section_name(labels: &[(u16, String)], addr: u16) -> &str {
+    nearest_label(labels, addr).map_or("(no label)", |(label, _)| label)
+}
+
+/// Name of subroutine at `addr`, with offset if it is not at a label.
+fn frame_name(labels: &[(u16, String)], addr: u16) -> String {
+    match nearest_label(labels, addr) {
+        Some((label, 0)) => label.to_string(),
+        Some((label, offset)) => format!("{label}+{offset}"),
+        None => format!("x{addr:04x}"),
+    }
+}
+
+/// Offset of the start of each line in `src`.
+fn line_starts(src: &str) -> Vec<usize> {
+    let newlines = src.match_indices('\n').map(|(i, _)| i + 1);
+    std::iter::once(0).chain(newlines).collect()
+}
+
+/// Line number (starting at 1) and trimmed text of the source line containing `addr`.
+fn source_line(
+    source: &ProgramSource,
+    line_starts: &[usize],
+    addr: u16,
+) -> Option<(usize, String)> {
+    let span = source.spans.get(addr.checked_sub(source.orig)? as usize)?;
+    let line = line_starts.partition_point(|start| *start <= span.offs());
+    let start = line_starts[line - 1];
+    let end = line_starts
+        .get(line)
+        .map_or(source.src.len(), |end| end - 1);
+    Some((line, source.src[start..end].trim().to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn call_stacks() {
+        let labels = vec![
+            (0x3000, "main".to_string()),
+            (0x3010, "outer".to_string()),
+            (0x3020, "inner".to_string()),
+        ];
+        let mut profiler = Profiler::new();
+        // main: jsr outer; outer: jsr inner; inner: ret; outer: ret; main: out; halt
+        for (pc, instr, next_pc) in [
+            (0x3000, 0x480F, 0x3010),
+            (0x3010, 0x480F, 0x3020),
+            (0x3020, 0xC1C0, 0x3011),
+            (0x3011, 0xC1C0, 0x3001),
+            (0x3001, 0xF021, 0x3002),
+            (0x3002, 0xF025, HALT_ADDRESS),
+        ] {
+            profiler.record(pc, instr, next_pc);
+        }
+        assert_eq!(
+            profiler.folded(&labels),
+            "main 3\nmain;outer 2\nmain;outer;inner 1\n"
+        );
+
+        let mem = vec![0; MEMORY_MAX];
+        let report = profiler.report(&labels, None, &mem);
+        assert!(report.starts_with("Profile of 6 instructions\n"));
+        assert!(report.contains("\nmain                            3   50.0%\n"));
+        assert!(report.contains("\nouter                           2   33.3%        1\n"));
+        assert!(report.contains("\ninner                           1   16.7%        1\n"));
+        // Without source, addresses are shown
+        assert!(report.contains("\n x3000            1   16.7%  nop\n"));
+    }
+}
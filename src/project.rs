@@ -0,0 +1,416 @@
+//! Project manifests (`lace.toml`), describing how to build and test a multi-file program.
+//!
+//! ```toml
+//! features = ["stack"]
+//! include = ["lib"]
+//!
+//! [defines]
+//! SCREEN_WIDTH = 40
+//! SCREEN = "xFE00"
+//!
+//! [[entry]]
+//! path = "src/main.asm"
+//! formats = ["lc3", "markdown"]
+//!
+//! [[test]]
+//! name = "greets user"
+//! input = "Ada\n"
+//! output = "Hello, Ada!"
+//! ```
+//!
+//! Every path is relative to the directory containing the manifest.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use fxhash::FxHashMap;
+use miette::{bail, miette, IntoDiagnostic, Result};
+use serde::Deserialize;
+
+use crate::artifacts::DEFAULT_OUT_DIR;
+use crate::doc;
+use crate::features::{self, Features};
+use crate::symbol;
+
+pub const MANIFEST_NAME: &str = "lace.toml";
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Manifest {
+    /// Feature flags, in addition to any given on the command line.
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// Directories searched by `.include` directives.
+    #[serde(default)]
+    pub include: Vec<PathBuf>,
+    /// Constants which replace labels of the same name.
+    #[serde(default)]
+    pub defines: BTreeMap<String, Define>,
+    /// Directory to store build artifacts in.
+    pub out_dir: Option<PathBuf>,
+    #[serde(default, rename = "entry")]
+    pub entries: Vec<Entry>,
+    #[serde(default, rename = "test")]
+    pub tests: Vec<TestCase>,
+}
+
+/// Value of a define: an integer, or a literal such as `"x4000"` or `"#-1"`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Define {
+    Int(i64),
+    Literal(String),
+}
+
+/// A source file to assemble.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+    /// Name used to refer to entry from tests, defaulting to the file stem.
+    pub name: Option<String>,
+    pub path: PathBuf,
+    #[serde(default = "default_formats")]
+    pub formats: Vec<OutputFormat>,
+}
+
+/// Artifact produced by `lace build`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Binary to run later.
+    Lc3,
+    /// Reference documentation, see [`doc`].
+    Markdown,
+    Html,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Lc3 => "lc3",
+            OutputFormat::Markdown => doc::Format::Markdown.extension(),
+            OutputFormat::Html => doc::Format::Html.extension(),
+        }
+    }
+}
+
+fn default_formats() -> Vec<OutputFormat> {
+    vec![OutputFormat::Lc3]
+}
+
+/// Program run by `lace test`, along with its expected output.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestCase {
+    pub name: String,
+    /// Name of entry to run, defaulting to the first.
+    pub entry: Option<String>,
+    /// Text given to the program as console input.
+    #[serde(default)]
+    pub input: String,
+    /// Expected console output. Leading and trailing whitespace is ignored.
+    pub output: String,
+}
+
+/// A manifest, along with the directory containing it.
+#[derive(Clone, Debug)]
+pub struct Project {
+    pub root: PathBuf,
+    pub manifest: Manifest,
+}
+
+impl Project {
+    /// Find manifest in `dir` or any of its ancestors.
+    pub fn find(dir: &Path) -> Result<Option<Project>> {
+        let dir = std::path::absolute(dir).into_diagnostic()?;
+        for ancestor in dir.ancestors() {
+            let path = ancestor.join(MANIFEST_NAME);
+            if path.is_file() {
+                return Project::load(&path).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Find manifest from the working directory, failing if there is none.
+    pub fn discover() -> Result<Project> {
+        let dir = std::env::current_dir().into_diagnostic()?;
+        match Project::find(&dir)? {
+            Some(project) => Ok(project),
+            None => Err(miette!(
+                help = "pass a file to use, or create a `lace.toml` manifest",
+                "Could not find `{MANIFEST_NAME}` in this directory or any parent"
+            )),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Project> {
+        let text = fs::read_to_string(path).into_diagnostic()?;
+        let manifest = Manifest::parse(&text)
+            .map_err(|report| report.wrap_err(format!("Invalid manifest {}", path.display())))?;
+        let root = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+        Ok(Project { root, manifest })
+    }
+
+    /// Initialize features state and defines for assembling this project.
+    ///
+    /// `extra` are features given on the command line.
+    pub fn configure(&self, extra: Features) -> Result<()> {
+        let features = self
+            .manifest
+            .features()?
+            .combine(extra)
+            .map_err(|reason| miette!("{reason}"))?;
+        features::init(features);
+        symbol::set_defines(self.manifest.defines()?);
+        Ok(())
+    }
+
+    pub fn out_dir(&self) -> PathBuf {
+        self.root.join(
+            self.manifest
+                .out_dir
+                .as_deref()
+                .unwrap_or(Path::new(DEFAULT_OUT_DIR)),
+        )
+    }
+
+    pub fn include_dirs(&self) -> Vec<PathBuf> {
+        self.manifest
+            .include
+            .iter()
+            .map(|dir| self.root.join(dir))
+            .collect()
+    }
+
+    pub fn entry_path(&self, entry: &Entry) -> PathBuf {
+        self.root.join(&entry.path)
+    }
+
+    /// Find entry by name, or the first entry if no name is given.
+    pub fn entry(&self, name: Option<&str>) -> Result<&Entry> {
+        let entries = &self.manifest.entries;
+        let entry = match name {
+            Some(name) => entries.iter().find(|entry| entry.name() == name),
+            None => entries.first(),
+        };
+        entry.ok_or_else(|| match name {
+            Some(name) => miette!("No entry named '{name}' in {MANIFEST_NAME}"),
+            None => miette!(
+                help = "add an entry like `[[entry]]\\npath = \"main.asm\"`",
+                "No entries declared in {MANIFEST_NAME}"
+            ),
+        })
+    }
+}
+
+impl Manifest {
+    pub fn parse(text: &str) -> Result<Manifest> {
+        toml::from_str(text).map_err(|err| miette!("{}", err.message()))
+    }
+
+    pub fn features(&self) -> Result<Features> {
+        self.features
+            .join(",")
+            .parse()
+            .map_err(|reason: String| miette!("{reason}"))
+    }
+
+    pub fn defines(&self) -> Result<FxHashMap<String, u16>> {
+        self.defines
+            .iter()
+            .map(|(name, define)| Ok((name.clone(), define.value(name)?)))
+            .collect()
+    }
+}
+
+impl Define {
+    fn value(&self, name: &str) -> Result<u16> {
+        let value = match self {
+            Define::Int(value) => Some(*value),
+            Define::Literal(literal) => parse_literal(literal),
+        };
+        match value {
+            Some(value) if (i16::MIN as i64..=u16::MAX as i64).contains(&value) => Ok(value as u16),
+            _ => Err(miette!(
+                help = "defines must be integers, or literals like \"x4000\" or \"#-1\", that fit in 16 bits",
+                "Invalid value for define '{name}'"
+            )),
+        }
+    }
+}
+
+fn parse_literal(literal: &str) -> Option<i64> {
+    let literal = literal.trim();
+    if let Some(hex) = literal
+        .strip_prefix("0x")
+        .or_else(|| literal.strip_prefix(['x', 'X']))
+    {
+        i64::from_str_radix(hex, 16).ok()
+    } else {
+        literal.strip_prefix('#').unwrap_or(literal).parse().ok()
+    }
+}
+
+impl Entry {
+    pub fn name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => self
+                .path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Read source file, replacing each `.include "file"` line with the contents of that file.
+///
+/// Included paths are resolved relative to the including file, then each of `include_dirs`.
+/// Every file is included at most once, so shared subroutines can be included by several files.
+/// Fails on any `.include` in strict mode.
+pub fn read_source(path: &Path, include_dirs: &[PathBuf]) -> Result<String> {
+    let mut included = Vec::new();
+    expand(path, include_dirs, &mut included)
+}
+
+/// Source file and every file it includes, as canonical paths.
+pub fn source_files(path: &Path, include_dirs: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut included = Vec::new();
+    expand(path, include_dirs, &mut included)?;
+    Ok(included)
+}
+
+fn expand(path: &Path, include_dirs: &[PathBuf], included: &mut Vec<PathBuf>) -> Result<String> {
+    let text = fs::read_to_string(path)
+        .into_diagnostic()
+        .map_err(|report| report.wrap_err(format!("Could not read {}", path.display())))?;
+    included.push(fs::canonicalize(path).into_diagnostic()?);
+
+    let mut out = String::with_capacity(text.len());
+    for (number, line) in text.split_inclusive('\n').enumerate() {
+        let Some(name) = include_directive(line) else {
+            out.push_str(line);
+            continue;
+        };
+        let location = format!("{}:{}", path.display(), number + 1);
+        if features::strict() {
+            bail!("Non-standard .include directive used in strict mode, at {location}");
+        }
+        let Some(name) = name else {
+            bail!(
+                help = ".include requires a file name like \"lib.asm\"",
+                "Expected a string literal, at {location}"
+            );
+        };
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let resolved = std::iter::once(dir)
+            .chain(include_dirs.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(name))
+            .find(|candidate| candidate.is_file());
+        let Some(resolved) = resolved else {
+            bail!(
+                help = "files are searched for next to the including file, then in include paths",
+                "Could not find included file '{name}', at {location}"
+            );
+        };
+        if included.contains(&fs::canonicalize(&resolved).into_diagnostic()?) {
+            // Keep line numbering of remaining lines intact
+            out.push('\n');
+            continue;
+        }
+        let mut contents = expand(&resolved, include_dirs, included)?;
+        if !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        out.push_str(&contents);
+    }
+    Ok(out)
+}
+
+/// Parse an `.include` line, returning the quoted file name, if valid.
+fn include_directive(line: &str) -> Option<Option<&str>> {
+    let line = line.trim_start();
+    let directive = line.get(..".include".len())?;
+    if !directive.eq_ignore_ascii_case(".include") {
+        return None;
+    }
+    let rest = &line[directive.len()..];
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let name = rest
+        .trim_start()
+        .strip_prefix('"')
+        .and_then(|rest| rest.split_once('"'))
+        .filter(|(_, after)| {
+            let after = after.trim_start();
+            after.is_empty() || after.starts_with(';')
+        })
+        .map(|(name, _)| name);
+    Some(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_manifest() {
+        let manifest = Manifest::parse(
+            r##"
+            features = ["stack"]
+            include = ["lib"]
+
+            [defines]
+            WIDTH = 40
+            SCREEN = "xFE00"
+            NEG = "#-1"
+
+            [[entry]]
+            path = "src/main.asm"
+            formats = ["lc3", "html"]
+
+            [[test]]
+            name = "prints"
+            output = "hi"
+            "##,
+        )
+        .unwrap();
+        assert_eq!(manifest.features().unwrap(), "stack".parse().unwrap());
+        let defines = manifest.defines().unwrap();
+        assert_eq!(defines["WIDTH"], 40);
+        assert_eq!(defines["SCREEN"], 0xFE00);
+        assert_eq!(defines["NEG"], 0xFFFF);
+        assert_eq!(manifest.entries[0].name(), "main");
+        assert_eq!(
+            manifest.entries[0].formats,
+            [OutputFormat::Lc3, OutputFormat::Html]
+        );
+        assert_eq!(manifest.tests[0].input, "");
+    }
+
+    #[test]
+    fn reject_unknown_keys() {
+        assert!(Manifest::parse("feature = [\"stack\"]").is_err());
+        let manifest = Manifest::parse("[defines]\nBIG = 70000").unwrap();
+        assert!(manifest.defines().is_err());
+    }
+
+    #[test]
+    fn include_directives() {
+        assert_eq!(
+            include_directive(".include \"a.asm\"\n"),
+            Some(Some("a.asm"))
+        );
+        assert_eq!(
+            include_directive("  .INCLUDE \"a.asm\" ; lib\n"),
+            Some(Some("a.asm"))
+        );
+        assert_eq!(include_directive(".include a.asm\n"), Some(None));
+        assert_eq!(include_directive(".includes \"a.asm\"\n"), None);
+        assert_eq!(include_directive("lea r0, msg\n"), None);
+    }
+}
@@ -1,41 +1,258 @@
 use std::{
+    cell::{Cell, RefCell},
     cmp::Ordering,
-    io::{self, stdin, stdout, IsTerminal, Read, Write},
+    fmt,
+    rc::Rc,
+    sync::mpsc::TryRecvError,
+    time::{Duration, Instant},
 };
 
+use crate::features;
+use crate::io::{Io, Stdio};
+use crate::profile::{Profiler, ProgramSource};
+use crate::symbol::with_symbol_table;
+use crate::trace::{TraceStep, Tracer};
 use crate::{
     debugger::{Action, Debugger, Options, SignificantInstr},
     dprintln,
     output::{Condition, Output},
     Air,
 };
-use crate::{features, term};
-use colored::Colorize;
-use miette::Result;
+use miette::{bail, miette, Result};
 
 /// First address which is out of bounds of user memory.
 pub const USER_MEMORY_END: u16 = 0xFE00;
 /// Sentinel value, which the PC is set to when a `HALT` is encountered.
 pub const HALT_ADDRESS: u16 = 0xFFFF;
 
-/// CPU exception.
-/// A fatal error has occurred in the program, such as an invalid instruction.
-macro_rules! exception {
-    ( $fmt:literal $($tt:tt)* ) => {{
-        eprintln!(
-            concat!("exception: ", $fmt, ", exiting")
-            $($tt)*
-        );
-        std::process::exit(0xEE);
-    }};
+/// PSR bit which is set while running in user mode.
+const PSR_USER: u16 = 0x8000;
+/// PSR bits holding the priority level.
+const PSR_PRIORITY: u16 = 0x0700;
+/// PSR bits holding the condition codes.
+const PSR_CC: u16 = 0x0007;
+/// Initial supervisor stack pointer. Stack grows down, below user memory.
+const SUPERVISOR_STACK: u16 = 0x3000;
+
+/// Start of interrupt vector table, which holds the address of each interrupt handler.
+const INTERRUPT_VECTOR_TABLE: u16 = 0x0100;
+
+// Device registers, which instructions access through the device bus rather than plain memory
+/// Keyboard status register. Bit 15 is set when a key is ready, and bit 14 enables interrupts.
+const KBSR: u16 = 0xFE00;
+/// Keyboard data register, holding the last key pressed.
+const KBDR: u16 = 0xFE02;
+/// Display status register. Bit 15 is set when the display is ready.
+const DSR: u16 = 0xFE04;
+/// Display data register. Writing prints the low byte as a character.
+const DDR: u16 = 0xFE06;
+/// Machine control register. Clearing bit 15 stops the clock, halting the machine.
+const MCR: u16 = 0xFFFE;
+const KBSR_READY: u16 = 0x8000;
+const KBSR_INTERRUPT_ENABLE: u16 = 0x4000;
+const DSR_READY: u16 = 0x8000;
+const MCR_CLOCK_ENABLE: u16 = 0x8000;
+const KEYBOARD_VECTOR: u16 = 0x80;
+const KEYBOARD_PRIORITY: u16 = 4;
+
+/// Amount of executed addresses to report when a runaway program is stopped.
+const RECENT_STEPS: usize = 8;
+/// Amount of instructions between checks of the timeout, as reading the clock is slow.
+const TIMEOUT_CHECK_STEPS: u64 = 0x400;
+
+/// How a program stopped running, without a fault.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitReason {
+    /// `HALT` was called, or the MCR clock was stopped
+    Halted,
+    /// Program was exited from the debugger
+    Exited,
+}
+
+/// CPU exception, or other fault which stops a program.
+///
+/// `pc` is the address of the instruction which caused it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuntimeError {
+    /// Program counter left user memory while in user mode, passing `limit`
+    ProtectedMemory { pc: u16, limit: u16 },
+    /// Trap vector with no routine
+    UnknownTrap { pc: u16, vector: u8 },
+    /// Trap which is not supported by standard LC3 tools, called in strict mode
+    NonstandardTrap { pc: u16, vector: u8 },
+    /// Reserved opcode, without the stack extension enabled
+    ReservedOpcode { pc: u16 },
+    /// `RTI` executed in user mode
+    PrivilegeViolation { pc: u16 },
+    /// Stack extension pushed into the program, or popped from an empty stack
+    StackFault { pc: u16, overflow: bool },
+    /// Program read input after the end of stdin
+    InputEof { pc: u16 },
+    /// Program executed more instructions than allowed by `--max-steps`
+    StepLimit {
+        pc: u16,
+        steps: u64,
+        runaway: Runaway,
+    },
+    /// Program ran for longer than allowed by `--timeout`
+    Timeout {
+        pc: u16,
+        timeout: Duration,
+        runaway: Runaway,
+    },
+}
+
+/// Where a runaway program was stopped, by a step limit or timeout.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Runaway {
+    /// Nearest label at or before the PC, and the offset from it
+    pub label: Option<(String, u16)>,
+    /// Addresses of the last few instructions executed, oldest first
+    pub recent: Vec<u16>,
+}
+
+impl RuntimeError {
+    pub fn pc(&self) -> u16 {
+        match *self {
+            Self::ProtectedMemory { pc, .. }
+            | Self::UnknownTrap { pc, .. }
+            | Self::NonstandardTrap { pc, .. }
+            | Self::ReservedOpcode { pc }
+            | Self::PrivilegeViolation { pc }
+            | Self::StackFault { pc, .. }
+            | Self::InputEof { pc }
+            | Self::StepLimit { pc, .. }
+            | Self::Timeout { pc, .. } => pc,
+        }
+    }
+
+    /// Message printed by the command line before exiting.
+    pub fn exit_message(&self) -> String {
+        match self {
+            // Errors with the emulator or its configuration, not the CPU
+            Self::InputEof { .. } => "unexpected end of input file stream.".to_string(),
+            Self::ReservedOpcode { .. } => "\
+                You called a reserved instruction.\n\
+                Note: Run with `-f stack` to enable stack extension feature.\n\
+                Halting...\
+                "
+            .to_string(),
+            Self::StepLimit { runaway, .. } | Self::Timeout { runaway, .. } => {
+                let recent: Vec<String> = runaway
+                    .recent
+                    .iter()
+                    .map(|addr| format!("0x{addr:04x}"))
+                    .collect();
+                format!(
+                    "exception: {self}, exiting\nlast executed: {}",
+                    recent.join(" ")
+                )
+            }
+            _ => format!("exception: {self}, exiting"),
+        }
+    }
+
+    /// Status the command line exits with.
+    ///
+    /// Runaway programs exit with 124, like `timeout(1)`, so graders can tell them apart.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::InputEof { .. } | Self::ReservedOpcode { .. } => 1,
+            Self::StepLimit { .. } | Self::Timeout { .. } => 124,
+            _ => 0xEE,
+        }
+    }
 }
 
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ProtectedMemory { pc, limit } if pc < limit => {
+                write!(f, "entered protected memory area < 0x{limit:04x}")
+            }
+            Self::ProtectedMemory { limit, .. } => {
+                write!(f, "entered protected memory area >= 0x{limit:04x}")
+            }
+            Self::UnknownTrap { vector, .. } => {
+                write!(f, "called a trap with an unknown vector of 0x{vector:02x}")
+            }
+            Self::NonstandardTrap { vector, .. } => write!(
+                f,
+                "called non-standard trap with vector of 0x{vector:02x} in strict mode"
+            ),
+            Self::ReservedOpcode { pc } => {
+                write!(f, "called a reserved instruction at pc 0x{pc:04x}")
+            }
+            Self::PrivilegeViolation { pc } => write!(
+                f,
+                "privilege mode violation: RTI executed in user mode at pc 0x{pc:04x}"
+            ),
+            Self::StackFault { pc, overflow: true } => {
+                write!(f, "stack overflow at pc 0x{pc:04x}")
+            }
+            Self::StackFault {
+                pc,
+                overflow: false,
+            } => {
+                write!(f, "stack underflow at pc 0x{pc:04x}")
+            }
+            Self::InputEof { pc } => write!(f, "unexpected end of input at pc 0x{pc:04x}"),
+            Self::StepLimit { pc, steps, runaway } => {
+                write!(
+                    f,
+                    "exceeded limit of {steps} instructions at pc 0x{pc:04x}{runaway}"
+                )
+            }
+            Self::Timeout {
+                pc,
+                timeout,
+                runaway,
+            } => {
+                write!(
+                    f,
+                    "exceeded time limit of {timeout:?} at pc 0x{pc:04x}{runaway}"
+                )
+            }
+        }
+    }
+}
+
+impl fmt::Display for Runaway {
+    /// Nearest label, if any, such as ` (loop+2)`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.label {
+            Some((label, 0)) => write!(f, " ({label})"),
+            Some((label, offset)) => write!(f, " ({label}+{offset})"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// Result of executing a single instruction.
+type Step = std::result::Result<(), RuntimeError>;
+
 /// LC3 can address 128KB of memory.
 pub(crate) const MEMORY_MAX: usize = 0x10000;
 
 pub struct RunEnvironment {
     state: RunState,
     debugger: Option<Debugger>,
+    /// Amount of instructions to execute before stopping a runaway program
+    max_steps: Option<u64>,
+    /// How long to run before stopping a runaway program
+    timeout: Option<Duration>,
+    /// Addresses of the last few instructions executed, indexed by step count
+    recent: [u16; RECENT_STEPS],
+    /// Address of each label in the program, sorted, to report where a runaway program stopped
+    labels: Vec<(u16, String)>,
+    /// Writes a trace of every executed instruction
+    tracer: Option<Tracer>,
+    /// Counts executed instructions for a profile
+    profiler: Option<Profiler>,
+    /// Source of assembled program, to report hottest lines of a profile
+    source: Option<ProgramSource>,
 }
 
 /// Represents complete program state during runtime.
@@ -48,12 +265,27 @@ pub(super) struct RunState {
     pc: u16,
     /// 8x 16-bit registers
     reg: [u16; 8],
-    /// Condition code
-    flag: RunFlag,
-    /// Processor status register
-    _psr: u16,
+    /// Processor status register: privilege mode, priority level and condition codes
+    psr: u16,
+    /// Supervisor stack pointer, while running in user mode
+    saved_ssp: u16,
+    /// User stack pointer, while running in supervisor mode
+    saved_usp: u16,
+    /// Whether traps jump through the trap vector table, rather than being handled natively
+    os: bool,
+    /// Return address of each active call to a trap handler registered by the program, while
+    /// traps are handled natively
+    handler_returns: Vec<u16>,
     /// Origin address (usually 0x3000)
     orig: u16,
+    /// Address after the last word of the program
+    end: u16,
+    /// Address and value of each memory write by the current instruction, while tracing
+    writes: Option<Vec<(u16, u16)>>,
+    /// Console input and output, shared with clones kept by the debugger
+    io: Rc<RefCell<dyn Io>>,
+    /// Whether console output is at the start of a line
+    line_start: Cell<bool>,
 }
 
 #[derive(Clone, Copy)]
@@ -61,7 +293,6 @@ pub(super) enum RunFlag {
     N = 0b100,
     Z = 0b010,
     P = 0b001,
-    Uninit = 0b000,
 }
 
 impl RunEnvironment {
@@ -77,8 +308,22 @@ impl RunEnvironment {
         }
 
         let mut env = RunEnvironment::from_raw(air_array.as_slice())?;
+        env.state.install_trap_handlers(air.trap_handlers());
+        // Symbol table holds the line of each label, starting at 1
+        env.labels = with_symbol_table(|sym| {
+            sym.iter()
+                .map(|(label, line)| (orig.wrapping_add(*line).wrapping_sub(1), label.clone()))
+                .collect()
+        });
+        env.labels.sort();
+        env.source = Some(ProgramSource {
+            src: air.src,
+            orig,
+            spans: air.ast.iter().map(|line| line.span).collect(),
+        });
 
         if let Some(debugger_opts) = debugger_opts {
+            env.state.io = Rc::new(RefCell::new(Stdio::with_debugger()));
             env.debugger = Some(Debugger::new(
                 debugger_opts,
                 env.state.clone(),
@@ -92,40 +337,182 @@ impl RunEnvironment {
     }
 
     pub fn from_raw(raw: &[u16]) -> Result<RunEnvironment> {
+        let (raw, traps) = features::load_binary_header(raw).map_err(|reason| {
+            miette!(
+                help = "this binary was compiled with `.feature` directives or `--features`",
+                "{reason}"
+            )
+        })?;
         if raw.is_empty() {
-            exception!("provided file is empty");
+            bail!("Provided file is empty");
         }
 
         let orig = raw[0] as usize;
         if orig + raw.len() > MEMORY_MAX {
-            exception!("assembly file is too long and cannot fit in memory");
+            bail!("Assembly file is too long and cannot fit in memory");
         }
 
         let mut mem = [0; MEMORY_MAX];
         let raw = &raw[1..];
 
         mem[orig..orig + raw.len()].clone_from_slice(raw);
+        mem[DSR as usize] = DSR_READY;
+        mem[MCR as usize] = MCR_CLOCK_ENABLE;
         // Add `HALT` at end of code and data
         // Prevents PC running through no-ops to the end of memory
         mem[orig + raw.len()] = 0xF025;
 
-        Ok(RunEnvironment {
+        let mut env = RunEnvironment {
             state: RunState {
                 mem: Box::new(mem),
                 pc: orig as u16,
                 // Stack pointer (R7) initalized to last address in user memory
                 reg: [0, 0, 0, 0, 0, 0, 0, USER_MEMORY_END - 1],
-                flag: RunFlag::Uninit,
-                _psr: 0,
+                // User mode, priority 0, no condition codes set
+                psr: PSR_USER,
+                saved_ssp: SUPERVISOR_STACK,
+                saved_usp: 0,
+                os: false,
+                handler_returns: Vec::new(),
                 orig: orig as u16,
+                end: (orig + raw.len()) as u16,
+                writes: None,
+                io: Rc::new(RefCell::new(Stdio::default())),
+                line_start: Cell::new(true),
             },
             debugger: None,
-        })
+            max_steps: None,
+            timeout: None,
+            recent: [0; RECENT_STEPS],
+            labels: Vec::new(),
+            tracer: None,
+            profiler: None,
+            source: None,
+        };
+        env.state.install_trap_handlers(traps);
+        Ok(env)
     }
 
-    /// Run with preset memory
-    pub fn run(&mut self) {
+    /// Load operating system image, starting with its origin, below the program.
+    ///
+    /// Traps then jump through the trap vector table in supervisor mode, instead of being handled
+    /// natively. Handlers already installed in the trap vector table, such as with `.trap`, are
+    /// kept, and return with `RTI` like the operating system's own routines.
+    pub fn load_os(&mut self, image: &[u16]) -> Result<()> {
+        let (image, traps) = features::load_binary_header(image).map_err(|reason| {
+            miette!(
+                help =
+                    "this operating system was compiled with `.feature` directives or `--features`",
+                "{reason}"
+            )
+        })?;
+        let Some((&orig, image)) = image.split_first() else {
+            bail!("Operating system image is empty");
+        };
+        if orig as usize + image.len() > self.state.orig as usize {
+            bail!(
+                "Operating system image overlaps program at 0x{:04x}",
+                self.state.orig
+            );
+        }
+        for (address, &word) in (orig..).zip(image) {
+            // Trap vector table ends where the interrupt vector table starts
+            if address < INTERRUPT_VECTOR_TABLE && self.state.mem(address) != 0 {
+                continue;
+            }
+            *self.state.mem_mut(address) = word;
+        }
+        // Handlers registered by the program take precedence here too
+        let traps = traps
+            .into_iter()
+            .filter(|&(vector, _)| self.state.mem(vector as u16) == 0);
+        self.state.install_trap_handlers(traps.collect::<Vec<_>>());
+        self.state.os = true;
+
+        if let Some(debugger) = &mut self.debugger {
+            debugger.set_initial_state(self.state.clone());
+        }
+        Ok(())
+    }
+
+    /// Read console input from, and write program output to, `io` instead of stdin and stdout.
+    pub fn set_io(&mut self, io: impl Io + 'static) {
+        self.state.io = Rc::new(RefCell::new(io));
+        if let Some(debugger) = &mut self.debugger {
+            debugger.set_initial_state(self.state.clone());
+        }
+    }
+
+    /// Stop program with an exception after executing `max_steps` instructions.
+    pub fn set_max_steps(&mut self, max_steps: Option<u64>) {
+        self.max_steps = max_steps;
+    }
+
+    /// Stop program with an exception after running for `timeout`.
+    ///
+    /// Checked between instructions, so a program waiting for input is not stopped.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Write a trace of every executed instruction.
+    ///
+    /// Errors if the trace is restricted to a subroutine which is not a label in the program.
+    pub fn set_tracer(&mut self, mut tracer: Tracer) -> Result<()> {
+        if let Some(subroutine) = tracer.subroutine_label() {
+            let Some((address, _)) = self.labels.iter().find(|(_, label)| label == subroutine)
+            else {
+                bail!(
+                    "Cannot trace subroutine `{subroutine}`, as there is no label with that name"
+                );
+            };
+            tracer.set_subroutine_address(*address);
+        }
+        self.tracer = Some(tracer);
+        Ok(())
+    }
+
+    /// Count executed instructions and subroutine calls, for [`RunEnvironment::profile_report`].
+    pub fn set_profiler(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    /// Instructions executed and calls made in each label, and the hottest source lines, if
+    /// profiling.
+    pub fn profile_report(&self) -> Option<String> {
+        let profiler = self.profiler.as_ref()?;
+        Some(profiler.report(&self.labels, self.source.as_ref(), &self.state.mem[..]))
+    }
+
+    /// Instructions executed in each call stack, as folded stacks for flamegraph tools, if
+    /// profiling.
+    pub fn profile_folded(&self) -> Option<String> {
+        let profiler = self.profiler.as_ref()?;
+        Some(profiler.folded(&self.labels))
+    }
+
+    /// Run with preset memory, until the program halts or faults.
+    ///
+    /// If the debugger is active, faults pause execution at the faulting instruction instead.
+    pub fn run(&mut self) -> std::result::Result<ExitReason, RuntimeError> {
+        let result = self.run_loop();
+        if let Some(tracer) = &mut self.tracer {
+            if let Err(err) = tracer.flush() {
+                eprintln!("failed to write trace: {err}");
+            }
+        }
+        result
+    }
+
+    fn run_loop(&mut self) -> std::result::Result<ExitReason, RuntimeError> {
+        let mut steps: u64 = 0;
+        let mut started = Instant::now();
         loop {
+            // Interrupts are taken between instructions
+            if self.state.pc != HALT_ADDRESS {
+                self.state.check_interrupts();
+            }
+
             if let Some(debugger) = &mut self.debugger {
                 Output::Debugger(Condition::Always, Default::default()).start_new_line();
 
@@ -139,7 +526,7 @@ impl RunEnvironment {
                     }
                     Action::ExitProgram => {
                         dprintln!(Sometimes, Warning, "Exiting program.");
-                        return;
+                        return Ok(ExitReason::Exited);
                     }
                 }
 
@@ -155,47 +542,147 @@ impl RunEnvironment {
                 if self.state.check_pc_bounds() != Ordering::Equal {
                     continue;
                 }
+                // Limits apply to each command which resumes execution
+                if debugger.instruction_count() == 0 {
+                    steps = 0;
+                    started = Instant::now();
+                }
                 // From this point, next instruction will always be executed
                 // (Unless debugger is "quit", making this counter irrelevant anyway)
                 debugger.increment_instruction_count();
             }
 
-            if self.state.pc == u16::MAX {
-                debug_assert!(
-                    self.debugger.is_none(),
-                    "halt should be caught if debugger is active",
-                );
-                break; // Halt was triggered
+            // Halt was triggered
+            // Debugger never executes `HALT`, but may still execute a write to MCR
+            if self.state.pc == HALT_ADDRESS {
+                break;
             }
 
-            // Debugger should have already checked these (if currently active)
-            match self.state.check_pc_bounds() {
-                Ordering::Less => {
-                    exception!("entered protected memory area < 0x{:04x}", self.state.orig)
-                }
-                Ordering::Greater => {
-                    exception!("entered protected memory area >= 0x{:04x}", USER_MEMORY_END)
-                }
-                _ => (),
+            if let Err(err) = self.step(&mut steps, started) {
+                let Some(debugger) = &mut self.debugger else {
+                    return Err(err);
+                };
+                // Faulting instruction has no effect, so it can be inspected before continuing
+                self.state.pc = err.pc();
+                debugger.pause_on_error(&err);
             }
-
-            let instr = self.state.mem[self.state.pc as usize];
-            // PC incremented before instruction is performed
-            self.state.pc += 1;
-            self.state.execute(instr);
         }
 
         Output::Normal.start_new_line();
+        Ok(ExitReason::Halted)
+    }
+
+    /// Execute the next instruction, unless the step limit or timeout has been reached.
+    fn step(&mut self, steps: &mut u64, started: Instant) -> Step {
+        let pc = self.state.pc;
+        // Debugger should have already checked these (if currently active)
+        match self.state.check_pc_bounds() {
+            Ordering::Less => {
+                return Err(RuntimeError::ProtectedMemory {
+                    pc,
+                    limit: self.state.orig,
+                })
+            }
+            Ordering::Greater => {
+                return Err(RuntimeError::ProtectedMemory {
+                    pc,
+                    limit: USER_MEMORY_END,
+                })
+            }
+            _ => (),
+        }
+
+        if self.max_steps.is_some_and(|max_steps| *steps >= max_steps) {
+            return Err(RuntimeError::StepLimit {
+                pc,
+                steps: *steps,
+                runaway: self.runaway(pc, *steps),
+            });
+        }
+        if let Some(timeout) = self.timeout {
+            if steps.is_multiple_of(TIMEOUT_CHECK_STEPS) && started.elapsed() >= timeout {
+                return Err(RuntimeError::Timeout {
+                    pc,
+                    timeout,
+                    runaway: self.runaway(pc, *steps),
+                });
+            }
+        }
+        self.recent[(*steps % RECENT_STEPS as u64) as usize] = pc;
+        *steps += 1;
+
+        let instr = self.state.mem[pc as usize];
+        // PC incremented before instruction is performed
+        self.state.pc = pc.wrapping_add(1);
+        if self.tracer.is_some() {
+            self.execute_traced(pc, instr)?;
+        } else {
+            self.state.execute(instr)?;
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(pc, instr, self.state.pc);
+        }
+        Ok(())
+    }
+
+    /// Execute instruction at `pc`, and write it to the trace.
+    fn execute_traced(&mut self, pc: u16, instr: u16) -> Step {
+        let regs = self.state.reg;
+        let cc = self.state.flag();
+        self.state.writes = Some(Vec::new());
+        let result = self.state.execute(instr);
+        let writes = self.state.writes.take().unwrap_or_default();
+        result?;
+        let step = TraceStep {
+            pc,
+            instr,
+            next_pc: self.state.pc,
+            regs: (regs, self.state.reg),
+            cc: (cc, self.state.flag()),
+            writes,
+        };
+        let tracer = self
+            .tracer
+            .as_mut()
+            .expect("caller should have checked tracer");
+        if let Err(err) = tracer.record(&step, &self.labels) {
+            eprintln!("failed to write trace: {err}");
+            self.tracer = None;
+        }
+        Ok(())
+    }
+
+    /// Nearest label at or before `pc`, and the last few addresses executed in `steps`.
+    fn runaway(&self, pc: u16, steps: u64) -> Runaway {
+        let label =
+            nearest_label(&self.labels, pc).map(|(label, offset)| (label.to_string(), offset));
+        // Oldest first, wrapping around the buffer
+        let count = steps.min(RECENT_STEPS as u64);
+        let recent = (steps - count..steps)
+            .map(|step| self.recent[(step % RECENT_STEPS as u64) as usize])
+            .collect();
+        Runaway { label, recent }
+    }
+}
+
+/// Nearest label at or before `address` in sorted `labels`, and the offset from it.
+pub(crate) fn nearest_label(labels: &[(u16, String)], address: u16) -> Option<(&str, u16)> {
+    match labels.partition_point(|(label_address, _)| *label_address <= address) {
+        0 => None,
+        i => {
+            let (label_address, label) = &labels[i - 1];
+            Some((label, address - label_address))
+        }
     }
 }
 
 impl RunState {
-    pub fn execute(&mut self, instr: u16) {
+    pub fn execute(&mut self, instr: u16) -> Step {
         let opcode = (instr >> 12) as usize;
-        RunState::OP_TABLE[opcode](self, instr);
+        RunState::OP_TABLE[opcode](self, instr)
     }
 
-    const OP_TABLE: [fn(&mut RunState, u16); 16] = [
+    const OP_TABLE: [fn(&mut RunState, u16) -> Step; 16] = [
         Self::br,    // 0x0
         Self::add,   // 0x1
         Self::ld,    // 0x2
@@ -247,9 +734,47 @@ impl RunState {
         &mut self.pc
     }
 
+    /// Condition codes, as `NZP` bits.
     #[inline]
-    pub(super) fn flag(&self) -> RunFlag {
-        self.flag
+    pub(super) fn flag(&self) -> u16 {
+        self.psr & PSR_CC
+    }
+
+    #[inline]
+    fn is_user_mode(&self) -> bool {
+        self.psr & PSR_USER != 0
+    }
+
+    /// Current priority level, from 0 to 7.
+    #[inline]
+    fn priority(&self) -> u16 {
+        (self.psr & PSR_PRIORITY) >> 8
+    }
+
+    /// Replace PSR, swapping stack pointers in R6 if privilege mode changes.
+    fn set_psr(&mut self, psr: u16) {
+        let to_user = psr & PSR_USER != 0;
+        if self.is_user_mode() && !to_user {
+            self.saved_usp = self.reg(6);
+            *self.reg_mut(6) = self.saved_ssp;
+        } else if !self.is_user_mode() && to_user {
+            self.saved_ssp = self.reg(6);
+            *self.reg_mut(6) = self.saved_usp;
+        }
+        self.psr = psr;
+    }
+
+    /// Whether `TRAP` with this vector has a routine to run, natively or in memory.
+    pub(super) fn has_trap_routine(&self, vector: u8) -> bool {
+        let native = !self.os && (0x20..=0x27).contains(&vector);
+        native || self.mem(vector as u16) != 0
+    }
+
+    /// Write the address of each handler to the trap vector table.
+    fn install_trap_handlers(&mut self, traps: impl IntoIterator<Item = (u8, u16)>) {
+        for (vector, address) in traps {
+            *self.mem_mut(vector as u16) = address;
+        }
     }
 
     pub(super) fn memory_equals(&self, other: &RunState, start: u16, end: u16) -> bool {
@@ -276,17 +801,22 @@ impl RunState {
 
     #[inline]
     fn set_flags(&mut self, val: u16) {
-        self.flag = match (val as i16).cmp(&0) {
+        let flag = match (val as i16).cmp(&0) {
             Ordering::Less => RunFlag::N,
             Ordering::Equal => RunFlag::Z,
             Ordering::Greater => RunFlag::P,
-        }
+        };
+        self.psr = (self.psr & !PSR_CC) | flag as u16;
     }
 
     /// Returns `Ordering::Equal` if current program counter is within user address space.
     /// Returns `Ordering::Less` or `Ordering::Greater` if PC `<` ORIG or PC `>=` [`USER_MEMORY_END`] respectively.
+    ///
+    /// System memory may only be executed in supervisor mode.
     pub fn check_pc_bounds(&self) -> Ordering {
-        if self.pc < self.orig {
+        if !self.is_user_mode() {
+            Ordering::Equal
+        } else if self.pc < self.orig {
             Ordering::Less
         } else if self.pc >= USER_MEMORY_END {
             Ordering::Greater
@@ -295,68 +825,235 @@ impl RunState {
         }
     }
 
-    fn stack(&mut self, instr: u16) {
+    fn stack(&mut self, instr: u16) -> Step {
         if !features::stack() {
-            eprintln!(
-                "\
-                You called a reserved instruction.\n\
-                Note: Run with `-f stack` to enable stack extension feature.\n\
-                Halting...\
-                "
-            );
-            std::process::exit(1);
+            return Err(RuntimeError::ReservedOpcode {
+                pc: self.fault_pc(),
+            });
         }
 
         // Bit to determine call/ret or push/pop
         if instr & 0x0800 != 0 {
             // Call
             if instr & 0x0400 != 0 {
-                self.push_val(self.pc);
+                self.push_val(self.pc)?;
                 self.pc = self.pc.wrapping_add(Self::s_ext(instr, 10));
             }
             // Ret
             else {
-                self.pc = self.pop_val();
+                self.pc = self.pop_val()?;
             }
         } else {
             let reg = (instr >> 6) & 0b111;
             // Push
             if instr & 0x0400 != 0 {
                 let val = self.reg(reg);
-                self.push_val(val);
+                self.push_val(val)?;
             }
             // Pop
             else {
-                let val = self.pop_val();
+                let val = self.pop_val()?;
                 *self.reg_mut(reg) = val;
             }
         }
+        Ok(())
+    }
+
+    /// Take keyboard interrupt, if enabled, a key is ready and the current priority is lower.
+    ///
+    /// Once interrupts are enabled with KBSR, the keyboard is polled between instructions.
+    fn check_interrupts(&mut self) {
+        let kbsr = self.mem(KBSR);
+        if kbsr & KBSR_INTERRUPT_ENABLE == 0 {
+            return;
+        }
+        if kbsr & KBSR_READY == 0 {
+            // No more interrupts once input has ended
+            let Ok(key) = self.io.borrow_mut().poll_byte() else {
+                return;
+            };
+            *self.mem_mut(KBDR) = key as u16;
+            *self.mem_mut(KBSR) = kbsr | KBSR_READY;
+        }
+        if KEYBOARD_PRIORITY > self.priority() {
+            self.interrupt(KEYBOARD_VECTOR, KEYBOARD_PRIORITY);
+        }
+    }
+
+    /// Enter supervisor mode at `priority`, and jump to the handler in the interrupt vector table.
+    fn interrupt(&mut self, vector: u16, priority: u16) {
+        let handler = self.mem(INTERRUPT_VECTOR_TABLE + vector);
+        self.enter_supervisor(handler, priority);
+    }
+
+    /// Enter supervisor mode at `priority`, saving PSR and PC on the supervisor stack, and jump to
+    /// `address`. `RTI` returns to the saved PC.
+    fn enter_supervisor(&mut self, address: u16, priority: u16) {
+        let psr = self.psr;
+        self.set_psr(priority << 8 & PSR_PRIORITY);
+        self.push_r6(psr);
+        self.push_r6(self.pc);
+        self.pc = address;
+    }
+
+    /// Read memory from an instruction, through the device bus.
+    ///
+    /// Reading KBSR checks for a key, and reading KBDR clears the KBSR ready bit.
+    fn load(&mut self, addr: u16) -> std::result::Result<u16, RuntimeError> {
+        match addr {
+            KBSR if self.mem(KBSR) & KBSR_READY == 0 => {
+                let key = self.io.borrow_mut().poll_byte();
+                match key {
+                    Ok(key) => {
+                        *self.mem_mut(KBDR) = key as u16;
+                        *self.mem_mut(KBSR) |= KBSR_READY;
+                    }
+                    Err(TryRecvError::Empty) => (),
+                    // Program would poll forever
+                    Err(TryRecvError::Disconnected) => {
+                        return Err(RuntimeError::InputEof {
+                            pc: self.fault_pc(),
+                        })
+                    }
+                }
+            }
+            KBDR => *self.mem_mut(KBSR) &= !KBSR_READY,
+            _ => (),
+        }
+        Ok(self.mem(addr))
     }
 
-    fn push_val(&mut self, val: u16) {
+    /// Write memory from an instruction, through the device bus.
+    ///
+    /// Only the interrupt enable bit of KBSR is writable, and KBDR and DSR are read-only.
+    fn store(&mut self, addr: u16, val: u16) {
+        if let Some(writes) = &mut self.writes {
+            writes.push((addr, val));
+        }
+        match addr {
+            KBSR => {
+                let ready = self.mem(KBSR) & KBSR_READY;
+                *self.mem_mut(KBSR) = ready | val & KBSR_INTERRUPT_ENABLE;
+            }
+            KBDR | DSR => (),
+            DDR => {
+                *self.mem_mut(DDR) = val;
+                let mut io = self.io.borrow_mut();
+                io.write_byte((val & 0xFF) as u8);
+                io.flush();
+                self.wrote((val & 0xFF) as u8);
+            }
+            MCR => {
+                *self.mem_mut(MCR) = val;
+                if val & MCR_CLOCK_ENABLE == 0 {
+                    self.halt();
+                }
+            }
+            _ => *self.mem_mut(addr) = val,
+        }
+    }
+
+    /// Read one character of input for a trap.
+    ///
+    /// Non-ASCII input is read as a replacement character.
+    fn read_char(&self) -> std::result::Result<char, RuntimeError> {
+        /// '�'
+        const REPLACEMENT_CHAR: char = '\u{FFFD}';
+
+        let byte = self.io.borrow_mut().read_byte();
+        match byte {
+            Some(byte) if byte.is_ascii() => Ok(byte as char),
+            Some(_) => Ok(REPLACEMENT_CHAR),
+            None => Err(RuntimeError::InputEof {
+                pc: self.fault_pc(),
+            }),
+        }
+    }
+
+    /// Write output of a trap.
+    fn print(&self, string: &str) {
+        let mut io = self.io.borrow_mut();
+        io.write_str(string);
+        io.flush();
+        if let Some(last) = string.bytes().last() {
+            self.wrote(last);
+        }
+    }
+
+    /// Record whether console output is at the start of a line, after writing `byte`.
+    fn wrote(&self, byte: u8) {
+        self.line_start.set(byte == b'\n');
+    }
+
+    fn halt(&mut self) {
+        self.pc = HALT_ADDRESS;
+    }
+
+    /// Push onto the supervisor stack, which R6 points to while in supervisor mode.
+    fn push_r6(&mut self, val: u16) {
+        let sp = self.reg(6).wrapping_sub(1);
+        *self.reg_mut(6) = sp;
+        if let Some(writes) = &mut self.writes {
+            writes.push((sp, val));
+        }
+        *self.mem_mut(sp) = val;
+    }
+
+    /// Pop from the supervisor stack, which R6 points to while in supervisor mode.
+    fn pop_r6(&mut self) -> u16 {
+        let sp = self.reg(6);
+        *self.reg_mut(6) = sp.wrapping_add(1);
+        self.mem(sp)
+    }
+
+    fn push_val(&mut self, val: u16) -> Step {
         debug_assert!(
             features::stack(),
             "caller should have ensured stack feature is enabled",
         );
+        // Stack may not grow into the program, or the `HALT` placed after it
+        if self.reg(7) <= self.end + 1 {
+            return Err(RuntimeError::StackFault {
+                pc: self.fault_pc(),
+                overflow: true,
+            });
+        }
         // Decrement stack
         *self.reg_mut(7) -= 1;
         let sp = self.reg(7);
+        if let Some(writes) = &mut self.writes {
+            writes.push((sp, val));
+        }
         // Save onto stack
         *self.mem_mut(sp) = val;
+        Ok(())
     }
 
-    fn pop_val(&mut self) -> u16 {
+    fn pop_val(&mut self) -> std::result::Result<u16, RuntimeError> {
         debug_assert!(
             features::stack(),
             "caller should have ensured stack feature is enabled",
         );
         let sp = self.reg(7);
+        // Stack starts at the last address of user memory
+        if sp >= USER_MEMORY_END - 1 {
+            return Err(RuntimeError::StackFault {
+                pc: self.fault_pc(),
+                overflow: false,
+            });
+        }
         let val = self.mem(sp);
         *self.reg_mut(7) += 1;
-        val
+        Ok(val)
+    }
+
+    /// Address of the instruction being executed. PC is incremented before execution.
+    #[inline]
+    fn fault_pc(&self) -> u16 {
+        self.pc.wrapping_sub(1)
     }
 
-    fn add(&mut self, instr: u16) {
+    fn add(&mut self, instr: u16) -> Step {
         let dr = (instr >> 9) & 0b111;
         let sr = (instr >> 6) & 0b111;
 
@@ -372,9 +1069,10 @@ impl RunState {
         let res = val1.wrapping_add(val2);
         self.set_flags(res);
         *self.reg_mut(dr) = res;
+        Ok(())
     }
 
-    fn and(&mut self, instr: u16) {
+    fn and(&mut self, instr: u16) -> Step {
         let dr = (instr >> 9) & 0b111;
         let sr = (instr >> 6) & 0b111;
 
@@ -390,21 +1088,32 @@ impl RunState {
         let res = val1 & val2;
         self.set_flags(res);
         *self.reg_mut(dr) = res;
+        Ok(())
     }
 
-    fn br(&mut self, instr: u16) {
+    fn br(&mut self, instr: u16) -> Step {
         let flag = (instr >> 9) & 0b111;
-        if self.flag as u16 & flag != 0 {
+        if self.flag() & flag != 0 {
             self.pc = self.pc.wrapping_add(Self::s_ext(instr, 9))
         }
+        Ok(())
     }
 
-    fn jmp(&mut self, instr: u16) {
+    fn jmp(&mut self, instr: u16) -> Step {
         let br = (instr >> 6) & 0b111;
-        self.pc = self.reg(br)
+        self.pc = self.reg(br);
+        // Returning from a trap handler with `RET` restores the privilege mode and priority of the
+        // caller, but keeps the condition codes set by the handler
+        if !self.is_user_mode() && self.handler_returns.last() == Some(&self.pc) {
+            self.handler_returns.pop();
+            self.pop_r6();
+            let psr = self.pop_r6();
+            self.set_psr(psr & !PSR_CC | self.psr & PSR_CC);
+        }
+        Ok(())
     }
 
-    fn jsr(&mut self, instr: u16) {
+    fn jsr(&mut self, instr: u16) -> Step {
         *self.reg_mut(7) = self.pc;
         if instr & 0x800 == 0 {
             // reg
@@ -414,187 +1123,201 @@ impl RunState {
             // offs
             self.pc = self.pc.wrapping_add(Self::s_ext(instr, 11))
         }
+        Ok(())
     }
 
-    fn ld(&mut self, instr: u16) {
+    fn ld(&mut self, instr: u16) -> Step {
         let dr = (instr >> 9) & 0b111;
-        let val = self.mem(self.pc.wrapping_add(Self::s_ext(instr, 9)));
+        let val = self.load(self.pc.wrapping_add(Self::s_ext(instr, 9)))?;
         *self.reg_mut(dr) = val;
         self.set_flags(val);
+        Ok(())
     }
 
-    fn ldi(&mut self, instr: u16) {
+    fn ldi(&mut self, instr: u16) -> Step {
         let dr = (instr >> 9) & 0b111;
-        let ptr = self.mem(self.pc.wrapping_add(Self::s_ext(instr, 9)));
-        let val = self.mem(ptr);
+        let ptr = self.load(self.pc.wrapping_add(Self::s_ext(instr, 9)))?;
+        let val = self.load(ptr)?;
         *self.reg_mut(dr) = val;
         self.set_flags(val);
+        Ok(())
     }
 
-    fn ldr(&mut self, instr: u16) {
+    fn ldr(&mut self, instr: u16) -> Step {
         let dr = (instr >> 9) & 0b111;
         let br = (instr >> 6) & 0b111;
         let ptr = self.reg(br);
-        let val = self.mem(ptr.wrapping_add(Self::s_ext(instr, 6)));
+        let val = self.load(ptr.wrapping_add(Self::s_ext(instr, 6)))?;
         *self.reg_mut(dr) = val;
         self.set_flags(val);
+        Ok(())
     }
 
-    fn lea(&mut self, instr: u16) {
+    fn lea(&mut self, instr: u16) -> Step {
         let dr = (instr >> 9) & 0b111;
         let val = self.pc.wrapping_add(Self::s_ext(instr, 9));
         *self.reg_mut(dr) = val;
         self.set_flags(val);
+        Ok(())
     }
 
-    fn not(&mut self, instr: u16) {
+    fn not(&mut self, instr: u16) -> Step {
         let dr = (instr >> 9) & 0b111;
         let sr = (instr >> 6) & 0b111;
         let val = !self.reg(sr);
         *self.reg_mut(dr) = val;
         self.set_flags(val);
+        Ok(())
     }
 
-    fn rti(&mut self, _instr: u16) {
-        todo!("Please open an issue and I'll get RTI implemented in a jiffy :)")
+    fn rti(&mut self, _instr: u16) -> Step {
+        if self.is_user_mode() {
+            return Err(RuntimeError::PrivilegeViolation {
+                pc: self.fault_pc(),
+            });
+        }
+        self.pc = self.pop_r6();
+        let psr = self.pop_r6();
+        self.set_psr(psr);
+        if self.handler_returns.last() == Some(&self.pc) {
+            self.handler_returns.pop();
+        }
+        Ok(())
     }
 
-    fn st(&mut self, instr: u16) {
+    fn st(&mut self, instr: u16) -> Step {
         let sr = (instr >> 9) & 0b111;
-        let val = *self.reg_mut(sr);
-        *self.mem_mut(self.pc.wrapping_add(Self::s_ext(instr, 9))) = val;
+        let val = self.reg(sr);
+        self.store(self.pc.wrapping_add(Self::s_ext(instr, 9)), val);
+        Ok(())
     }
 
-    fn sti(&mut self, instr: u16) {
+    fn sti(&mut self, instr: u16) -> Step {
         let sr = (instr >> 9) & 0b111;
         let val = self.reg(sr);
-        let ptr = self.mem(self.pc.wrapping_add(Self::s_ext(instr, 9)));
-        *self.mem_mut(ptr) = val;
+        let ptr = self.load(self.pc.wrapping_add(Self::s_ext(instr, 9)))?;
+        self.store(ptr, val);
+        Ok(())
     }
 
-    fn str(&mut self, instr: u16) {
+    fn str(&mut self, instr: u16) -> Step {
         let sr = (instr >> 9) & 0b111;
         let br = (instr >> 6) & 0b111;
         let ptr = self.reg(br);
         let val = self.reg(sr);
-        *self.mem_mut(ptr.wrapping_add(Self::s_ext(instr, 6))) = val;
+        self.store(ptr.wrapping_add(Self::s_ext(instr, 6)), val);
+        Ok(())
     }
 
-    fn trap(&mut self, instr: u16) {
+    fn trap(&mut self, instr: u16) -> Step {
         let trap_vect = instr & 0xFF;
+        if self.os {
+            let routine = self.mem(trap_vect);
+            if routine == 0 {
+                return Err(RuntimeError::UnknownTrap {
+                    pc: self.fault_pc(),
+                    vector: trap_vect as u8,
+                });
+            }
+            self.enter_supervisor(routine, self.priority());
+            return Ok(());
+        }
         match trap_vect {
             // getc
             0x20 => {
-                *self.reg_mut(0) = read_char() as u16;
+                *self.reg_mut(0) = self.read_char()? as u16;
             }
             // out
             0x21 => {
-                let chr = (self.reg(0) & 0xFF) as u8 as char;
-                Output::Normal.print(chr);
-                stdout().flush().unwrap();
+                let mut io = self.io.borrow_mut();
+                io.write_byte((self.reg(0) & 0xFF) as u8);
+                io.flush();
+                self.wrote((self.reg(0) & 0xFF) as u8);
             }
             // puts
             0x22 => {
+                let mut io = self.io.borrow_mut();
                 // could probably rewrite with iterators but idk if worth
                 for addr in self.reg(0).. {
                     let chr_raw = self.mem(addr);
-                    let chr_ascii = (chr_raw & 0xFF) as u8 as char;
-                    if chr_ascii == '\0' {
+                    let chr_ascii = (chr_raw & 0xFF) as u8;
+                    if chr_ascii == b'\0' {
                         break;
                     }
-                    Output::Normal.print(chr_ascii);
+                    io.write_byte(chr_ascii);
+                    self.wrote(chr_ascii);
                 }
-                stdout().flush().unwrap();
+                io.flush();
             }
             // in
             0x23 => {
-                let ch = read_char();
+                let ch = self.read_char()?;
                 *self.reg_mut(0) = ch as u16;
-                Output::Normal.print(ch);
-                stdout().flush().unwrap();
+                self.print(ch.encode_utf8(&mut [0; 4]));
             }
             // putsp
             0x24 => {
+                let mut io = self.io.borrow_mut();
                 'string: for addr in self.reg(0).. {
                     let chr_raw = self.mem(addr);
                     for chr in [chr_raw >> 8, chr_raw & 0xFF] {
-                        let chr_ascii = chr as u8 as char;
-                        if chr_ascii == '\0' {
+                        let chr_ascii = chr as u8;
+                        if chr_ascii == b'\0' {
                             break 'string;
                         }
-                        Output::Normal.print(chr_ascii);
+                        io.write_byte(chr_ascii);
+                        self.wrote(chr_ascii);
                     }
                 }
-                stdout().flush().unwrap();
+                io.flush();
             }
             // halt
-            0x25 => {
-                self.pc = HALT_ADDRESS;
-                println!("\n{:>12}", "Halted".cyan());
+            0x25 => self.halt(),
+            // Non-standard traps
+            0x26 | 0x27 if features::strict() => {
+                return Err(RuntimeError::NonstandardTrap {
+                    pc: self.fault_pc(),
+                    vector: trap_vect as u8,
+                })
             }
             // putn
             0x26 => {
                 let val = self.reg(0);
-                Output::Normal.print_decimal(val);
+                self.print(&(val as i16).to_string());
             }
             // reg
             0x27 => {
-                Output::Normal.start_new_line();
-                Output::Normal.print_registers(self);
+                if !self.line_start.get() {
+                    self.print("\n");
+                }
+                let registers = Output::capture(|| Output::Normal.print_registers(self));
+                self.print(&registers);
             }
 
-            // Note that if custom traps are implemented, `src/debugger/eval.rs` must be modified
-            // to explicitely allow `eval` to simulate the traps
+            // Handler registered with `.trap` or by writing to the trap vector table
+            // Called like a subroutine in supervisor mode, returning with `RET` or `RTI`
+            _ if self.mem(trap_vect) != 0 => {
+                *self.reg_mut(7) = self.pc;
+                self.handler_returns.push(self.pc);
+                self.enter_supervisor(self.mem(trap_vect), self.priority());
+            }
 
             // unknown
-            _ => exception!(
-                "called a trap with an unknown vector of 0x{:02x}",
-                trap_vect
-            ),
-        }
-    }
-}
-
-// Read one byte from stdin or interactive terminal.
-fn read_char() -> char {
-    /// '�'
-    const REPLACEMENT_CHAR: char = '\u{FFFD}';
-
-    let stdin = stdin();
-    let byte = if stdin.is_terminal() {
-        term::read_byte()
-    } else {
-        Some(read_byte_stdin(stdin))
-    };
-    // Replace with marker character if non-ASCII
-    match byte {
-        Some(byte) if byte.is_ascii() => byte as char,
-        _ => REPLACEMENT_CHAR,
-    }
-}
-
-/// Read one byte from stdin.
-///
-/// Handles `UnexpectedEof` by printing error minimally and exiting.
-/// Panics on any other error.
-fn read_byte_stdin(mut stdin: io::Stdin) -> u8 {
-    let mut buf = [0; 1];
-    if let Err(err) = stdin.read_exact(&mut buf) {
-        if let io::ErrorKind::UnexpectedEof = err.kind() {
-            // This should NOT use `exception!`: it is an error with the
-            // emulator, not the CPU
-            eprintln!("unexpected end of input file stream.");
-            std::process::exit(1);
-        } else {
-            panic!("failed to read character from stdin: {:?}", err)
+            _ => {
+                return Err(RuntimeError::UnknownTrap {
+                    pc: self.fault_pc(),
+                    vector: trap_vect as u8,
+                })
+            }
         }
+        Ok(())
     }
-    buf[0]
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::io::{BufferIo, ScriptedIo};
 
     #[test]
     fn s_ext() {
@@ -678,4 +1401,286 @@ mod test {
         expect(0xffff, 15, 0xffff);
         expect(0xffff, 1, 0xffff);
     }
+
+    #[test]
+    fn rti_restores_user_mode() {
+        let mut env = RunEnvironment::from_raw(&[0x3000, 0x8000]).unwrap();
+        let state = &mut env.state;
+        // Enter supervisor mode, as an interrupt would
+        *state.reg_mut(6) = 0x4000;
+        state.set_psr(0x0000);
+        assert_eq!(state.reg(6), SUPERVISOR_STACK);
+        assert_eq!(state.saved_usp, 0x4000);
+
+        // Return to user mode with Z set
+        *state.reg_mut(6) = SUPERVISOR_STACK - 2;
+        *state.mem_mut(SUPERVISOR_STACK - 2) = 0x3005;
+        *state.mem_mut(SUPERVISOR_STACK - 1) = PSR_USER | 0b010;
+        state.rti(0x8000).unwrap();
+        assert_eq!(state.pc(), 0x3005);
+        assert_eq!(state.psr, PSR_USER | 0b010);
+        assert_eq!(state.flag(), RunFlag::Z as u16);
+        assert_eq!(state.reg(6), 0x4000);
+        assert_eq!(state.saved_ssp, SUPERVISOR_STACK);
+    }
+
+    #[test]
+    fn flags_live_in_psr() {
+        let mut env = RunEnvironment::from_raw(&[0x3000, 0x8000]).unwrap();
+        let state = &mut env.state;
+        assert_eq!(state.flag(), 0);
+        state.set_flags(0xFFFF);
+        assert_eq!(state.psr, PSR_USER | RunFlag::N as u16);
+        state.set_flags(1);
+        assert_eq!(state.psr, PSR_USER | RunFlag::P as u16);
+        assert_eq!(state.check_pc_bounds(), Ordering::Equal);
+        state.pc = 0x0400;
+        assert_eq!(state.check_pc_bounds(), Ordering::Less);
+        state.set_psr(0);
+        assert_eq!(state.check_pc_bounds(), Ordering::Equal);
+    }
+
+    #[test]
+    fn keyboard_interrupt() {
+        // Handler at x3010: read key, return
+        let mut env = RunEnvironment::from_raw(&[0x3000, 0x0000]).unwrap();
+        let state = &mut env.state;
+        *state.mem_mut(INTERRUPT_VECTOR_TABLE + KEYBOARD_VECTOR) = 0x3010;
+        *state.mem_mut(KBDR) = 'a' as u16;
+        *state.mem_mut(KBSR) = KBSR_READY;
+        *state.reg_mut(6) = 0x4000;
+        state.set_flags(1);
+
+        // Disabled
+        state.check_interrupts();
+        assert_eq!(state.pc(), 0x3000);
+
+        *state.mem_mut(KBSR) = KBSR_READY | KBSR_INTERRUPT_ENABLE;
+        state.check_interrupts();
+        assert_eq!(state.pc(), 0x3010);
+        assert_eq!(state.priority(), KEYBOARD_PRIORITY);
+        assert!(!state.is_user_mode());
+        assert_eq!(state.reg(6), SUPERVISOR_STACK - 2);
+        assert_eq!(state.mem(SUPERVISOR_STACK - 2), 0x3000);
+        assert_eq!(
+            state.mem(SUPERVISOR_STACK - 1),
+            PSR_USER | RunFlag::P as u16
+        );
+
+        // Not taken again at same priority
+        state.check_interrupts();
+        assert_eq!(state.pc(), 0x3010);
+
+        // `LDI` of KBDR clears ready bit
+        assert_eq!(state.load(KBDR), Ok('a' as u16));
+        assert_eq!(state.mem(KBSR), KBSR_INTERRUPT_ENABLE);
+
+        state.rti(0x8000).unwrap();
+        assert_eq!(state.pc(), 0x3000);
+        assert_eq!(state.priority(), 0);
+        assert_eq!(state.flag(), RunFlag::P as u16);
+        assert_eq!(state.reg(6), 0x4000);
+    }
+
+    #[test]
+    fn device_registers() {
+        let mut env = RunEnvironment::from_raw(&[0x3000, 0x0000]).unwrap();
+        let state = &mut env.state;
+        assert_eq!(state.load(DSR), Ok(DSR_READY));
+        state.store(DSR, 0);
+        assert_eq!(state.load(DSR), Ok(DSR_READY));
+
+        // Ready bit is read-only
+        *state.mem_mut(KBSR) = KBSR_READY;
+        state.store(KBSR, KBSR_INTERRUPT_ENABLE);
+        assert_eq!(state.mem(KBSR), KBSR_READY | KBSR_INTERRUPT_ENABLE);
+        state.store(KBSR, 0);
+        assert_eq!(state.mem(KBSR), KBSR_READY);
+
+        // Plain memory is unaffected
+        state.store(0x4000, 0x1234);
+        assert_eq!(state.load(0x4000), Ok(0x1234));
+
+        state.store(MCR, MCR_CLOCK_ENABLE | 1);
+        assert_eq!(state.pc(), 0x3000);
+        state.store(MCR, 0);
+        assert_eq!(state.pc(), HALT_ADDRESS);
+    }
+
+    #[test]
+    fn trap_through_table() {
+        let mut env = RunEnvironment::from_raw(&[0x3000, 0xF021]).unwrap();
+        assert!(env.load_os(&[]).is_err());
+        // Routine at x0200 returns immediately
+        let mut image = vec![0x0000; 0x202];
+        image[0x21 + 1] = 0x0200;
+        image[0x200 + 1] = 0x8000;
+        env.load_os(&image).unwrap();
+        assert!(env.load_os(&[0x2FFF, 0, 0]).is_err());
+
+        let state = &mut env.state;
+        *state.reg_mut(6) = 0x4000;
+        state.pc = 0x3001;
+        state.trap(0xF021).unwrap();
+        assert_eq!(state.pc(), 0x0200);
+        assert!(!state.is_user_mode());
+        assert_eq!(state.check_pc_bounds(), Ordering::Equal);
+        state.execute(state.mem(0x0200)).unwrap();
+        assert_eq!(state.pc(), 0x3001);
+        assert!(state.is_user_mode());
+        assert_eq!(state.reg(6), 0x4000);
+    }
+
+    #[test]
+    fn user_trap_handler() {
+        // trap x30; halt; handler: ret
+        let mut env = RunEnvironment::from_raw(&[0x3000, 0xF030, 0xF025, 0xC1C0]).unwrap();
+        let state = &mut env.state;
+        assert!(!state.has_trap_routine(0x30));
+        assert!(state.has_trap_routine(0x21));
+        *state.mem_mut(0x30) = 0x3002;
+        assert!(state.has_trap_routine(0x30));
+
+        state.pc = 0x3001;
+        state.trap(0xF030).unwrap();
+        assert_eq!(state.pc(), 0x3002);
+        assert_eq!(state.reg(7), 0x3001);
+        assert!(!state.is_user_mode());
+        state.execute(state.mem(0x3002)).unwrap();
+        assert_eq!(state.pc(), 0x3001);
+        assert!(state.is_user_mode());
+        assert_eq!(state.reg(6), 0);
+    }
+
+    #[test]
+    fn binary_trap_table() {
+        let traps = [(0x30, 0x3002)];
+        let mut raw = features::binary_header(&traps);
+        // trap x30; halt; handler: ret
+        raw.extend([0x3000, 0xF030, 0xF025, 0xC1C0]);
+        let env = RunEnvironment::from_raw(&raw).unwrap();
+        assert_eq!(env.state.pc(), 0x3000);
+        assert_eq!(env.state.mem(0x30), 0x3002);
+        assert_eq!(env.state.mem(0x3002), 0xC1C0);
+
+        // Table must hold every handler it counts
+        raw.truncate(5);
+        assert!(RunEnvironment::from_raw(&raw).is_err());
+    }
+
+    #[test]
+    fn user_trap_handler_rti() {
+        // trap x30; halt; handler: and r0, r0, #0; rti
+        let mut env = RunEnvironment::from_raw(&[0x3000, 0xF030, 0xF025, 0x5020, 0x8000]).unwrap();
+        *env.state.mem_mut(0x30) = 0x3002;
+        *env.state.reg_mut(0) = 5;
+        assert_eq!(env.run(), Ok(ExitReason::Halted));
+        assert_eq!(env.state.reg(0), 0);
+        assert!(env.state.is_user_mode());
+        assert_eq!(env.state.reg(6), 0);
+        assert!(env.state.handler_returns.is_empty());
+    }
+
+    #[test]
+    fn faults_are_returned() {
+        let run = |raw: &[u16]| RunEnvironment::from_raw(raw).unwrap().run();
+
+        // and r0, r0, #0; halt
+        assert_eq!(run(&[0x3000, 0x5020, 0xF025]), Ok(ExitReason::Halted));
+        assert_eq!(
+            run(&[0x3000, 0x5020, 0xF030]),
+            Err(RuntimeError::UnknownTrap {
+                pc: 0x3001,
+                vector: 0x30
+            })
+        );
+        assert_eq!(
+            run(&[0x3000, 0x8000]),
+            Err(RuntimeError::PrivilegeViolation { pc: 0x3000 })
+        );
+        // Stack extension is not enabled
+        let err = run(&[0x3000, 0xD000]).unwrap_err();
+        assert_eq!(err, RuntimeError::ReservedOpcode { pc: 0x3000 });
+        assert_eq!(err.exit_code(), 1);
+        // jmp r0
+        let err = run(&[0x3000, 0xC000]).unwrap_err();
+        assert_eq!(
+            err,
+            RuntimeError::ProtectedMemory {
+                pc: 0x0000,
+                limit: 0x3000
+            }
+        );
+        assert_eq!(
+            err.exit_message(),
+            "exception: entered protected memory area < 0x3000, exiting"
+        );
+
+        // Branch to itself forever
+        let mut env = RunEnvironment::from_raw(&[0x3000, 0x5020, 0x0FFF]).unwrap();
+        env.set_max_steps(Some(3));
+        assert_eq!(
+            env.run(),
+            Err(RuntimeError::StepLimit {
+                pc: 0x3001,
+                steps: 3,
+                runaway: Runaway {
+                    label: None,
+                    recent: vec![0x3000, 0x3001, 0x3001],
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn stack_overflow_stops_at_program_end() {
+        features::init("stack".parse().unwrap());
+        // and r0, r0, #0; push r0; br -2
+        let mut env = RunEnvironment::from_raw(&[0x3000, 0x5020, 0xD400, 0x0FFE]).unwrap();
+        assert_eq!(
+            env.run(),
+            Err(RuntimeError::StackFault {
+                pc: 0x3001,
+                overflow: true
+            })
+        );
+        // Last word of the program and the `HALT` after it are left intact
+        assert_eq!(env.state.reg(7), 0x3004);
+        assert_eq!(env.state.mem(0x3002), 0x0FFE);
+        assert_eq!(env.state.mem(0x3003), 0xF025);
+    }
+
+    #[test]
+    fn buffered_io() {
+        // getc; out; putn; halt
+        let raw = [0x3000, 0xF020, 0xF021, 0xF026, 0xF025];
+        let mut env = RunEnvironment::from_raw(&raw).unwrap();
+        let io = BufferIo::new("A");
+        env.set_io(io.clone());
+        assert_eq!(env.run(), Ok(ExitReason::Halted));
+        assert_eq!(io.output_string(), "A65");
+
+        // Register dump starts on a new line: getc; out; reg; halt
+        let raw = [0x3000, 0xF020, 0xF021, 0xF027, 0xF025];
+        let mut env = RunEnvironment::from_raw(&raw).unwrap();
+        let io = BufferIo::new("A");
+        env.set_io(io.clone());
+        assert_eq!(env.run(), Ok(ExitReason::Halted));
+        assert!(io.output_string().starts_with("A\n"));
+        assert!(!io.output_string().starts_with("A\n\n"));
+
+        let mut env = RunEnvironment::from_raw(&raw).unwrap();
+        env.set_io(BufferIo::default());
+        assert_eq!(env.run(), Err(RuntimeError::InputEof { pc: 0x3000 }));
+
+        // Poll keyboard until a key is ready, then print it: ldi r0, kbsr; brzp -2; ldi r0, kbdr;
+        // out; halt
+        let raw = [0x3000, 0xA004, 0x07FE, 0xA003, 0xF021, 0xF025, KBSR, KBDR];
+        let mut env = RunEnvironment::from_raw(&raw).unwrap();
+        let io = ScriptedIo::new().input("x");
+        env.set_io(io.clone());
+        assert_eq!(env.run(), Ok(ExitReason::Halted));
+        assert!(io.is_finished());
+        assert_eq!(io.output_string(), "x");
+    }
 }
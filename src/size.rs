@@ -0,0 +1,394 @@
+//! Memory map of an assembled program, printed by `lace size`.
+//!
+//! Large `.blkw` and `.stringz` blocks push labels apart, until PC-relative instructions can no
+//! longer reach them. The report shows where every label ends up, how much of each subroutine is
+//! code or data, and which references are close to the limit of their offset field.
+
+use std::fmt::Write;
+
+use miette::Result;
+
+use crate::air::{Air, AirStmt};
+use crate::cst::SyntaxTree;
+use crate::runtime::USER_MEMORY_END;
+use crate::symbol::{with_symbol_table, Label};
+use crate::AsmParser;
+
+/// Width of memory maps, in cells.
+const MAP_WIDTH: usize = 64;
+
+/// Layout of an assembled program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Size {
+    pub orig: u16,
+    /// Every label and its address, in address order.
+    pub labels: Vec<(String, u16)>,
+    /// Program split at each subroutine, in address order.
+    pub sections: Vec<Section>,
+    /// Whether each word is data rather than code, in address order.
+    pub data: Vec<bool>,
+    /// PC-relative references which use most of their offset field.
+    pub reaches: Vec<Reach>,
+}
+
+/// Words from the start of a subroutine (or the program) until the next subroutine.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub address: u16,
+    pub code: u16,
+    pub data: u16,
+}
+
+/// Reference from an instruction to a label, measured against its offset field.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reach {
+    /// Address of the instruction
+    pub address: u16,
+    pub mnemonic: &'static str,
+    pub label: String,
+    pub offset: i32,
+    pub bits: u32,
+}
+
+impl Reach {
+    /// Largest offset the field can hold, in the direction of this reference.
+    pub fn limit(&self) -> i32 {
+        if self.offset < 0 {
+            1 << (self.bits - 1)
+        } else {
+            (1 << (self.bits - 1)) - 1
+        }
+    }
+
+    pub fn in_range(&self) -> bool {
+        self.offset.abs() <= self.limit()
+    }
+
+    /// Whether the reference uses at least three quarters of its offset field.
+    fn is_near_limit(&self) -> bool {
+        self.offset.abs() * 4 >= self.limit() * 3
+    }
+}
+
+/// Assemble source and measure its layout.
+///
+/// Offsets are not checked, so programs with references out of range can still be measured.
+/// Symbol table is populated while assembling.
+pub fn measure(src: &'static str) -> Result<Size> {
+    let tree = SyntaxTree::parse(src)?;
+    let mut air = AsmParser::from_tree(&tree)?.parse()?;
+    air.backpatch()?;
+    Ok(layout(&air))
+}
+
+fn layout(air: &Air) -> Size {
+    let orig = air.orig().unwrap_or(0x3000);
+    let address = |line: u16| orig.wrapping_add(line).wrapping_sub(1);
+
+    let mut labels: Vec<(String, u16)> = with_symbol_table(|sym| {
+        sym.iter()
+            .map(|(name, line)| (name.clone(), *line))
+            .collect()
+    });
+    labels.sort_by(|(a_name, a_line), (b_name, b_line)| (a_line, a_name).cmp(&(b_line, b_name)));
+    let name_at = |line: u16| {
+        labels
+            .iter()
+            .find(|(_, label_line)| *label_line == line)
+            .map(|(name, _)| name.clone())
+    };
+
+    // Subroutines are the targets of `JSR` and `CALL`
+    let mut starts = vec![1];
+    for stmt in air {
+        if let AirStmt::JumbSub {
+            dest_label: Label::Ref(line),
+        }
+        | AirStmt::Call {
+            dest_label: Label::Ref(line),
+        } = stmt.stmt
+        {
+            if (1..=air.len() as u16).contains(&line) {
+                starts.push(line);
+            }
+        }
+    }
+    starts.sort();
+    starts.dedup();
+    let mut sections: Vec<Section> = starts
+        .iter()
+        .map(|line| Section {
+            name: name_at(*line).unwrap_or_else(|| "(entry)".to_string()),
+            address: address(*line),
+            code: 0,
+            data: 0,
+        })
+        .collect();
+
+    let mut data = Vec::with_capacity(air.len());
+    let mut reaches = Vec::new();
+    for stmt in air {
+        let is_data = matches!(stmt.stmt, AirStmt::RawWord { .. });
+        data.push(is_data);
+        let section = starts.partition_point(|start| *start <= stmt.line) - 1;
+        if is_data {
+            sections[section].data += 1;
+        } else {
+            sections[section].code += 1;
+        }
+
+        let Some((mnemonic, bits)) = offset_field(&stmt.stmt) else {
+            continue;
+        };
+        let Some(Label::Ref(target)) = stmt.stmt.label() else {
+            continue;
+        };
+        let reach = Reach {
+            address: address(stmt.line),
+            mnemonic,
+            label: name_at(*target).unwrap_or_else(|| format!("x{:04X}", address(*target))),
+            offset: *target as i32 - stmt.line as i32 - 1,
+            bits,
+        };
+        if reach.is_near_limit() {
+            reaches.push(reach);
+        }
+    }
+
+    Size {
+        orig,
+        labels: labels
+            .into_iter()
+            .map(|(name, line)| (name, address(line)))
+            .collect(),
+        sections,
+        data,
+        reaches,
+    }
+}
+
+/// Mnemonic and width of the PC-relative offset field of an instruction.
+fn offset_field(stmt: &AirStmt) -> Option<(&'static str, u32)> {
+    Some(match stmt {
+        AirStmt::Branch { .. } => ("br", 9),
+        AirStmt::Load { .. } => ("ld", 9),
+        AirStmt::LoadInd { .. } => ("ldi", 9),
+        AirStmt::LoadEAddr { .. } => ("lea", 9),
+        AirStmt::Store { .. } => ("st", 9),
+        AirStmt::StoreInd { .. } => ("sti", 9),
+        AirStmt::JumbSub { .. } => ("jsr", 11),
+        AirStmt::Call { .. } => ("call", 10),
+        _ => return None,
+    })
+}
+
+impl Size {
+    pub fn code(&self) -> usize {
+        self.data.iter().filter(|is_data| !**is_data).count()
+    }
+
+    pub fn total(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Words available to user programs starting at the origin.
+    pub fn user_space(&self) -> usize {
+        USER_MEMORY_END.saturating_sub(self.orig) as usize
+    }
+
+    /// Label containing the given address, with the offset from it.
+    fn location(&self, address: u16) -> String {
+        match self
+            .labels
+            .iter()
+            .rev()
+            .find(|(_, label)| *label <= address)
+        {
+            Some((name, label)) if *label == address => name.clone(),
+            Some((name, label)) => format!("{name}+{}", address - label),
+            None => format!("x{address:04X}"),
+        }
+    }
+}
+
+/// Render report as plain text.
+pub fn render(size: &Size) -> String {
+    let mut out = String::new();
+    let end = size.orig.wrapping_add(size.total() as u16);
+
+    out.push_str("Labels\n");
+    for (name, address) in &size.labels {
+        let _ = writeln!(out, "  x{address:04X}  {name}");
+    }
+
+    let width = size
+        .sections
+        .iter()
+        .map(|section| section.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("section".len());
+    let _ = writeln!(
+        out,
+        "\nSections\n  {:<width$}  address   code   data  total",
+        "section"
+    );
+    for section in &size.sections {
+        let _ = writeln!(
+            out,
+            "  {:<width$}  x{:04X}   {:>5}  {:>5}  {:>5}",
+            section.name,
+            section.address,
+            section.code,
+            section.data,
+            section.code + section.data
+        );
+    }
+
+    let space = size.user_space();
+    let _ = writeln!(
+        out,
+        "\nFootprint\n  {} words ({} code, {} data) of {} words of user space (x{:04X}-x{:04X}), {:.1}%",
+        size.total(),
+        size.code(),
+        size.total() - size.code(),
+        space,
+        size.orig,
+        USER_MEMORY_END.wrapping_sub(1),
+        size.total() as f64 * 100.0 / space.max(1) as f64
+    );
+
+    let program_cell = size.total().div_ceil(MAP_WIDTH).max(1);
+    let _ = writeln!(
+        out,
+        "\nProgram (x{:04X}-x{:04X}, {} per cell)\n  {}",
+        size.orig,
+        end.wrapping_sub(1),
+        plural(program_cell, "word"),
+        map(
+            &size.data,
+            program_cell,
+            size.total().div_ceil(program_cell)
+        )
+    );
+    let space_cell = space.div_ceil(MAP_WIDTH).max(1);
+    let _ = writeln!(
+        out,
+        "\nUser space (x{:04X}-x{:04X}, {} per cell)\n  {}",
+        size.orig,
+        USER_MEMORY_END.wrapping_sub(1),
+        plural(space_cell, "word"),
+        map(&size.data, space_cell, space.div_ceil(space_cell))
+    );
+    out.push_str("  # code  = data  . free\n");
+
+    if !size.reaches.is_empty() {
+        out.push_str("\nReferences near their offset limit\n");
+        for reach in &size.reaches {
+            let status = if reach.in_range() {
+                ""
+            } else {
+                ", out of range"
+            };
+            let _ = writeln!(
+                out,
+                "  {} at {} -> {}: {} of {} words ({}-bit offset{status})",
+                reach.mnemonic,
+                size.location(reach.address),
+                reach.label,
+                reach.offset,
+                if reach.offset < 0 {
+                    -reach.limit()
+                } else {
+                    reach.limit()
+                },
+                reach.bits,
+            );
+        }
+    }
+    out
+}
+
+/// Row of cells, each showing whether it contains any code or data.
+fn map(data: &[bool], cell: usize, cells: usize) -> String {
+    (0..cells)
+        .map(|i| {
+            let words = data.iter().skip(i * cell).take(cell);
+            let mut c = '.';
+            for is_data in words {
+                if !is_data {
+                    return '#';
+                }
+                c = '=';
+            }
+            c
+        })
+        .collect()
+}
+
+fn plural(count: usize, word: &str) -> String {
+    if count == 1 {
+        format!("1 {word}")
+    } else {
+        format!("{count} {word}s")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sections_and_labels() {
+        let src =
+            ".orig x3000\njsr print\nhalt\nmsg .stringz \"hi\"\nprint lea r0, msg\nputs\nret\n";
+        let size = measure(src).unwrap();
+        crate::reset_state();
+        assert_eq!(
+            size.labels,
+            [("msg".to_string(), 0x3002), ("print".to_string(), 0x3005)]
+        );
+        assert_eq!(
+            size.sections,
+            [
+                Section {
+                    name: "(entry)".to_string(),
+                    address: 0x3000,
+                    code: 2,
+                    data: 3,
+                },
+                Section {
+                    name: "print".to_string(),
+                    address: 0x3005,
+                    code: 3,
+                    data: 0,
+                },
+            ]
+        );
+        assert!(size.reaches.is_empty());
+        let out = render(&size);
+        assert!(out.contains("8 words (5 code, 3 data) of 52736 words"));
+        assert!(out.contains("\n  ##===###\n"));
+    }
+
+    #[test]
+    fn references_near_limit() {
+        let src = "main ld r0, far\nlea r1, near\nhalt\nnear .blkw #200\n.blkw #60\nfar .fill #1\n";
+        let size = measure(src).unwrap();
+        crate::reset_state();
+        assert_eq!(
+            size.reaches,
+            [Reach {
+                address: 0x3000,
+                mnemonic: "ld",
+                label: "far".to_string(),
+                offset: 262,
+                bits: 9,
+            }]
+        );
+        assert!(!size.reaches[0].in_range());
+        assert!(render(&size)
+            .contains("ld at main -> far: 262 of 255 words (9-bit offset, out of range)"));
+    }
+}
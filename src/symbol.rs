@@ -3,12 +3,17 @@ use std::{cell::RefCell, ops::Range, str::FromStr};
 use fxhash::FxHashMap;
 use miette::{miette, Result, SourceSpan};
 
+use crate::error;
+
 thread_local! {
     pub static SYMBOL_TABLE: RefCell<FxHashMap<String, u16>> = RefCell::new(FxHashMap::default());
+    /// Constants given by a project manifest, which replace labels of the same name
+    static DEFINES: RefCell<FxHashMap<String, u16>> = RefCell::new(FxHashMap::default());
 }
 
 pub fn reset_state() {
     with_symbol_table(|sym| sym.clear());
+    crate::features::reset_pragmas();
 }
 
 /// Access to symbol table via closure
@@ -19,6 +24,17 @@ where
     SYMBOL_TABLE.with_borrow_mut(f)
 }
 
+/// Set constants to substitute for labels. Unlike the symbol table, these persist across
+/// [`reset_state`].
+pub fn set_defines(defines: FxHashMap<String, u16>) {
+    DEFINES.set(defines);
+}
+
+/// Value of a defined constant, if any.
+pub fn define(name: &str) -> Option<u16> {
+    DEFINES.with_borrow(|defines| defines.get(name).copied())
+}
+
 /// This is not allowed to be cloned to avoid double frees.
 pub struct StaticSource {
     src: *mut String,
@@ -43,7 +59,8 @@ impl StaticSource {
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub enum Label {
     Ref(u16),
-    Unfilled(String),
+    /// Label name, and location of the reference for diagnostics
+    Unfilled(String, Span),
 }
 
 impl Label {
@@ -60,25 +77,28 @@ impl Label {
     }
 
     /// Used on non-prefix labels to give them a discrete line number reference
-    pub fn try_fill(label: &str) -> Self {
+    pub fn try_fill(label: &str, span: Span) -> Self {
         with_symbol_table(|sym| {
             // Fill with existing label value
             if let Some(val) = sym.get(label) {
                 Label::Ref(*val)
             } else {
-                Label::Unfilled(label.to_string())
+                Label::Unfilled(label.to_string(), span)
             }
         })
     }
 
     /// Used when all prefix labels are guaranteed to exist in table
-    pub fn filled(self) -> Result<Self> {
+    pub fn filled(self, src: &'static str) -> Result<Self> {
         with_symbol_table(|sym| match &self {
-            Self::Unfilled(label) => {
+            Self::Unfilled(label, span) => {
                 if let Some(line) = sym.get(label.as_str()) {
                     Ok(Self::Ref(*line))
                 } else {
-                    Err(miette!("Label not found"))
+                    let suggestion = error::closest_match(label, sym.keys().map(String::as_str));
+                    Err(error::backpatch_label_not_found(
+                        label, *span, src, suggestion,
+                    ))
                 }
             }
             Self::Ref(_) => Ok(self),
@@ -86,8 +106,8 @@ impl Label {
     }
 
     /// For comparison in tests
-    pub fn empty(val: &str) -> Self {
-        Label::Unfilled(val.to_string())
+    pub fn empty(val: &str, span: Span) -> Self {
+        Label::Unfilled(val.to_string(), span)
     }
 
     /// Function for testing purposes only
@@ -98,7 +118,7 @@ impl Label {
     /// Check if label is filled
     pub fn is_unfilled(&self) -> bool {
         match self {
-            Label::Unfilled(_) => false,
+            Label::Unfilled(..) => false,
             Label::Ref(_) => true,
         }
     }
@@ -287,12 +307,10 @@ pub enum DirKind {
     Blkw,
     Fill,
     Break,
+    Feature,
+    Trap,
 }
 
-/// Newtype representing an offset from a particular address.
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
-pub struct LineOffs(u16);
-
 /// Used to refer to offsets from the start of a source file.
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct SrcOffset(pub usize);
@@ -1,4 +1,4 @@
-use std::cell::RefCell;
+use std::{cell::RefCell, time::Duration};
 
 use crossterm::{
     event::{self, Event, KeyEvent},
@@ -100,6 +100,18 @@ pub fn read_byte() -> Option<u8> {
     None
 }
 
+/// Read one byte from interactive terminal, if a key has already been pressed.
+///
+/// Non-ASCII characters are read as one non-ASCII byte.
+///
+/// Caller must ensure terminal is NOT in raw mode.
+pub fn poll_byte() -> Option<u8> {
+    enable_raw_mode();
+    let ch = poll_char();
+    disable_raw_mode();
+    ch.map(|ch| if ch.is_ascii() { ch as u8 } else { 0xFF })
+}
+
 thread_local! {
     /// Must only be used inside `read_byte`.
     static BUFFERED_BYTE_COUNT: RefCell<u8> = const { RefCell::new(0) };
@@ -129,6 +141,23 @@ fn read_char() -> char {
     ch
 }
 
+/// Read single character from interactive terminal, without waiting.
+///
+/// Events are consumed until [`Key::Char`] or [`Key::Enter`] are read, or no event is ready.
+///
+/// Caller must ensure terminal is in raw mode.
+fn poll_char() -> Option<char> {
+    while event::poll(Duration::ZERO).expect("failed to poll terminal event") {
+        let event = event::read().expect("failed to read terminal event");
+        match event.try_into() {
+            Ok(Key::Char(ch)) => return Some(ch),
+            Ok(Key::Enter) => return Some('\n'),
+            _ => continue,
+        }
+    }
+    None
+}
+
 impl TryFrom<Event> for Key {
     type Error = ();
     fn try_from(event: Event) -> Result<Self, Self::Error> {
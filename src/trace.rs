@@ -0,0 +1,386 @@
+//! Execution traces, for `lace run --trace`.
+//!
+//! Every executed instruction is written with its cycle number, address, nearest label,
+//! disassembly, and any register, condition code and memory changes, so a run can be diffed
+//! against a reference solution.
+
+use std::{
+    fmt::Write as _,
+    io::{self, BufWriter, Write},
+    ops::RangeInclusive,
+};
+
+use clap::ValueEnum;
+use serde_json::{json, Map, Value};
+
+use crate::runtime::nearest_label;
+
+/// How each traced instruction is written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum TraceFormat {
+    /// One aligned line per instruction.
+    #[default]
+    Text,
+    /// One JSON object per instruction, each on its own line.
+    Jsonl,
+}
+
+/// Which executed instructions are written to a trace. Every instruction is traced by default.
+#[derive(Clone, Debug, Default)]
+pub struct TraceFilter {
+    /// Only instructions at these addresses
+    pub range: Option<RangeInclusive<u16>>,
+    /// Only instructions executed during a call to the subroutine at this label, including any
+    /// subroutines it calls
+    pub subroutine: Option<String>,
+}
+
+/// Writes a trace of executed instructions.
+pub struct Tracer {
+    out: BufWriter<Box<dyn Write>>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    /// Address of `filter.subroutine`, once labels are known
+    subroutine: Option<u16>,
+    /// Return addresses of active calls to the subroutine
+    calls: Vec<u16>,
+    /// Amount of instructions executed, including those filtered out
+    cycle: u64,
+}
+
+/// Machine state before and after executing one instruction.
+pub(crate) struct TraceStep {
+    pub pc: u16,
+    pub instr: u16,
+    pub next_pc: u16,
+    pub regs: ([u16; 8], [u16; 8]),
+    pub cc: (u16, u16),
+    /// Address and value of each memory write, in order
+    pub writes: Vec<(u16, u16)>,
+}
+
+impl Tracer {
+    pub fn new(out: impl Write + 'static, format: TraceFormat, filter: TraceFilter) -> Self {
+        Self {
+            out: BufWriter::new(Box::new(out)),
+            format,
+            filter,
+            subroutine: None,
+            calls: Vec::new(),
+            cycle: 0,
+        }
+    }
+
+    /// Label of subroutine which the trace is restricted to, if any.
+    pub(crate) fn subroutine_label(&self) -> Option<&str> {
+        self.filter.subroutine.as_deref()
+    }
+
+    pub(crate) fn set_subroutine_address(&mut self, address: u16) {
+        self.subroutine = Some(address);
+    }
+
+    /// Write executed instruction, if it passes the filter.
+    pub(crate) fn record(&mut self, step: &TraceStep, labels: &[(u16, String)]) -> io::Result<()> {
+        self.cycle += 1;
+        let in_subroutine = !self.calls.is_empty();
+        if self.calls.last() == Some(&step.next_pc) && is_return(step.instr) {
+            self.calls.pop();
+        }
+        if self.subroutine == Some(step.next_pc) && is_call(step.instr) {
+            self.calls.push(step.pc.wrapping_add(1));
+        }
+
+        if self.subroutine.is_some() && !in_subroutine {
+            return Ok(());
+        }
+        if let Some(range) = &self.filter.range {
+            if !range.contains(&step.pc) {
+                return Ok(());
+            }
+        }
+
+        let label = nearest_label(labels, step.pc).map(|(label, offset)| match offset {
+            0 => label.to_string(),
+            _ => format!("{label}+{offset}"),
+        });
+        let disasm = disassemble(step.pc, step.instr);
+        let (regs_before, regs_after) = step.regs;
+        let changed_regs = (0..8).filter(|&i| regs_before[i] != regs_after[i]);
+
+        match self.format {
+            TraceFormat::Text => {
+                let mut changes = Vec::new();
+                for i in changed_regs {
+                    changes.push(format!(
+                        "r{i} x{:04x} -> x{:04x}",
+                        regs_before[i], regs_after[i]
+                    ));
+                }
+                if step.cc.0 != step.cc.1 {
+                    changes.push(format!(
+                        "cc {} -> {}",
+                        cc_name(step.cc.0),
+                        cc_name(step.cc.1)
+                    ));
+                }
+                for (addr, val) in &step.writes {
+                    changes.push(format!("[x{addr:04x}] <- x{val:04x}"));
+                }
+                let mut line = format!(
+                    "{:>8}  x{:04x}  {:<16}  {:<20}",
+                    self.cycle,
+                    step.pc,
+                    label.as_deref().unwrap_or("-"),
+                    disasm,
+                );
+                if !changes.is_empty() {
+                    write!(line, "  {}", changes.join(", ")).expect("writing to `String`");
+                }
+                writeln!(self.out, "{}", line.trim_end())
+            }
+            TraceFormat::Jsonl => {
+                let registers: Map<String, Value> = changed_regs
+                    .map(|i| {
+                        let change = json!({ "old": regs_before[i], "new": regs_after[i] });
+                        (format!("r{i}"), change)
+                    })
+                    .collect();
+                let cc = (step.cc.0 != step.cc.1)
+                    .then(|| json!({ "old": cc_name(step.cc.0), "new": cc_name(step.cc.1) }));
+                let writes: Vec<Value> = step
+                    .writes
+                    .iter()
+                    .map(|(addr, val)| json!({ "address": addr, "value": val }))
+                    .collect();
+                let entry = json!({
+                    "cycle": self.cycle,
+                    "pc": step.pc,
+                    "label": label,
+                    "word": step.instr,
+                    "instruction": disasm,
+                    "registers": registers,
+                    "cc": cc,
+                    "writes": writes,
+                });
+                writeln!(self.out, "{entry}")
+            }
+        }
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Instruction which saves a return address and jumps: `JSR`, `JSRR`, `CALL` or `TRAP`.
+pub(crate) fn is_call(instr: u16) -> bool {
+    match instr >> 12 {
+        0x4 | 0xF => true,
+        0xD => instr & 0x0C00 == 0x0C00,
+        _ => false,
+    }
+}
+
+/// Instruction which can return from a subroutine: `JMP` (including `RET`), `RETS` or `RTI`.
+pub(crate) fn is_return(instr: u16) -> bool {
+    match instr >> 12 {
+        0x8 | 0xC => true,
+        0xD => instr & 0x0C00 == 0x0800,
+        _ => false,
+    }
+}
+
+/// Condition codes as letters, such as `z`, or `-` if none are set.
+fn cc_name(cc: u16) -> String {
+    let name: String = [(0b100, 'n'), (0b010, 'z'), (0b001, 'p')]
+        .into_iter()
+        .filter(|(bit, _)| cc & bit != 0)
+        .map(|(_, letter)| letter)
+        .collect();
+    if name.is_empty() {
+        "-".to_string()
+    } else {
+        name
+    }
+}
+
+/// Disassemble instruction at `address`, showing PC-relative operands as absolute addresses.
+pub fn disassemble(address: u16, instr: u16) -> String {
+    let dr = (instr >> 9) & 0b111;
+    let sr = (instr >> 6) & 0b111;
+    let target = |bits: u32| {
+        address
+            .wrapping_add(1)
+            .wrapping_add(sext(instr, bits) as u16)
+    };
+    match instr >> 12 {
+        0x0 => {
+            let flags: String = [(0x800, 'n'), (0x400, 'z'), (0x200, 'p')]
+                .into_iter()
+                .filter(|(bit, _)| instr & bit != 0)
+                .map(|(_, letter)| letter)
+                .collect();
+            if flags.is_empty() {
+                "nop".to_string()
+            } else {
+                format!("br{flags} x{:04x}", target(9))
+            }
+        }
+        op @ (0x1 | 0x5) => {
+            let name = if op == 0x1 { "add" } else { "and" };
+            if instr & 0x20 == 0 {
+                format!("{name} r{dr}, r{sr}, r{}", instr & 0b111)
+            } else {
+                format!("{name} r{dr}, r{sr}, #{}", sext(instr, 5))
+            }
+        }
+        op @ (0x2 | 0x3 | 0xA | 0xB | 0xE) => {
+            let name = match op {
+                0x2 => "ld",
+                0x3 => "st",
+                0xA => "ldi",
+                0xB => "sti",
+                _ => "lea",
+            };
+            format!("{name} r{dr}, x{:04x}", target(9))
+        }
+        0x4 if instr & 0x800 != 0 => format!("jsr x{:04x}", target(11)),
+        0x4 => format!("jsrr r{sr}"),
+        op @ (0x6 | 0x7) => {
+            let name = if op == 0x6 { "ldr" } else { "str" };
+            format!("{name} r{dr}, r{sr}, #{}", sext(instr, 6))
+        }
+        0x8 => "rti".to_string(),
+        0x9 => format!("not r{dr}, r{sr}"),
+        0xC if sr == 7 => "ret".to_string(),
+        0xC => format!("jmp r{sr}"),
+        0xD => match instr & 0x0C00 {
+            0x0C00 => format!("call x{:04x}", target(10)),
+            0x0800 => "rets".to_string(),
+            0x0400 => format!("push r{sr}"),
+            _ => format!("pop r{sr}"),
+        },
+        _ => match instr & 0xFF {
+            0x20 => "getc".to_string(),
+            0x21 => "out".to_string(),
+            0x22 => "puts".to_string(),
+            0x23 => "in".to_string(),
+            0x24 => "putsp".to_string(),
+            0x25 => "halt".to_string(),
+            0x26 => "putn".to_string(),
+            0x27 => "reg".to_string(),
+            vector => format!("trap x{vector:02x}"),
+        },
+    }
+}
+
+/// Sign-extend the lowest `bits` bits of `val`.
+fn sext(val: u16, bits: u32) -> i16 {
+    let shift = 16 - bits;
+    ((val << shift) as i16) >> shift
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn disassemble_instructions() {
+        for (instr, expected) in [
+            (0x1021, "add r0, r0, #1"),
+            (0x5260, "and r1, r1, #0"),
+            (0x1042, "add r0, r1, r2"),
+            (0x0FFE, "brnzp x2fff"),
+            (0x0000, "nop"),
+            (0x2002, "ld r0, x3003"),
+            (0xE1FF, "lea r0, x3000"),
+            (0x6E7F, "ldr r7, r1, #-1"),
+            (0x4801, "jsr x3002"),
+            (0x4080, "jsrr r2"),
+            (0xC1C0, "ret"),
+            (0x9040, "not r0, r1"),
+            (0xDC01, "call x3002"),
+            (0xD800, "rets"),
+            (0xD440, "push r1"),
+            (0xD040, "pop r1"),
+            (0xF025, "halt"),
+            (0xF030, "trap x30"),
+        ] {
+            assert_eq!(disassemble(0x3000, instr), expected, "x{instr:04x}");
+        }
+    }
+
+    /// Writer which can be read while the tracer holds it.
+    #[derive(Clone, Default)]
+    struct Shared(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn filter_subroutine() {
+        let labels = vec![(0x3000, "main".to_string()), (0x3010, "sub".to_string())];
+        let out = Shared::default();
+        let mut tracer = Tracer::new(out.clone(), TraceFormat::Text, TraceFilter::default());
+        tracer.set_subroutine_address(0x3010);
+        // jsr sub; sub: add r0, r0, #1; ret; add r0, r0, #1
+        // Each step has R0 and R7, then condition codes, before and after
+        for (pc, instr, next_pc, (r0, r7), (new_r0, new_r7), cc) in [
+            (0x3000, 0x480F, 0x3010, (0, 0), (0, 0x3001), (0b000, 0b000)),
+            (
+                0x3010,
+                0x1021,
+                0x3011,
+                (0, 0x3001),
+                (1, 0x3001),
+                (0b000, 0b001),
+            ),
+            (
+                0x3011,
+                0xC1C0,
+                0x3001,
+                (1, 0x3001),
+                (1, 0x3001),
+                (0b001, 0b001),
+            ),
+            (
+                0x3001,
+                0x1021,
+                0x3002,
+                (1, 0x3001),
+                (2, 0x3001),
+                (0b001, 0b001),
+            ),
+        ] {
+            let step = TraceStep {
+                pc,
+                instr,
+                next_pc,
+                regs: (
+                    [r0, 0, 0, 0, 0, 0, 0, r7],
+                    [new_r0, 0, 0, 0, 0, 0, 0, new_r7],
+                ),
+                cc,
+                writes: Vec::new(),
+            };
+            tracer.record(&step, &labels).unwrap();
+        }
+        tracer.flush().unwrap();
+
+        let out = String::from_utf8(out.0.take()).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "       2  x3010  sub               add r0, r0, #1        r0 x0000 -> x0001, cc - -> p",
+                "       3  x3011  sub+1             ret",
+            ]
+        );
+    }
+}
@@ -84,3 +84,18 @@ fn check_every_command() {
             include_str!("expected/check_every_command").replace("\r\n", "\n"),
         ));
 }
+
+#[test]
+fn polling_keeps_piped_commands() {
+    // Program polls the keyboard forever, and must not read the commands as keys
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("debug")
+        .arg("tests/files/echo.asm")
+        .arg("--minimal")
+        .write_stdin("si;registers;si;registers;exit\n");
+
+    cmd.assert()
+        .success()
+        .stderr(contains("PC x3001"))
+        .stderr(contains("PC x3000"));
+}
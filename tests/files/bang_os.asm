@@ -0,0 +1,16 @@
+; Operating system whose `PUTS` prints '!' instead
+.orig x0000
+.blkw #34
+.fill x0028
+.blkw #2
+.fill x002C
+.blkw #2
+ld r0, bang
+sti r0, ddr
+rti
+bang .fill x21
+and r0, r0, #0
+sti r0, mcr
+ddr .fill xfe06
+mcr .fill xfffe
+.end
@@ -0,0 +1,15 @@
+.orig x3000
+poll ldi r1, kbsr
+brzp poll
+ldi r0, kbdr
+display ldi r1, dsr
+brzp display
+sti r0, ddr
+and r0, r0, #0
+sti r0, mcr
+kbsr .fill xfe00
+kbdr .fill xfe02
+dsr .fill xfe04
+ddr .fill xfe06
+mcr .fill xfffe
+.end
@@ -0,0 +1,4 @@
+.orig x3000
+trap x30
+halt
+.end
@@ -0,0 +1,12 @@
+; Enable stack extension without `--features stack`
+.feature stack
+
+    call hw_sub
+    halt
+
+hw_sub
+    lea r0 hw
+    puts
+    rets
+
+hw .stringz "Hello from a pragma\n"
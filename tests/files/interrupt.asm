@@ -0,0 +1,22 @@
+.orig x3000
+lea r0, handler
+sti r0, ivt
+ld r0, enable
+sti r0, kbsr
+wait ld r1, key
+brz wait
+halt
+handler ldi r0, kbdr
+st r0, key
+lea r0, msg
+puts
+ld r0, key
+out
+rti
+ivt .fill x0180
+kbsr .fill xfe00
+kbdr .fill xfe02
+enable .fill x4000
+key .fill #0
+msg .stringz "Key: "
+.end
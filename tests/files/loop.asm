@@ -0,0 +1,2 @@
+loop add r0, r0, #0
+br loop
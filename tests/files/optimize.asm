@@ -0,0 +1,6 @@
+lea r0, msg
+add r1, r1, #0
+puts
+br done
+done halt
+msg .stringz "Optimized"
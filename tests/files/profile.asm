@@ -0,0 +1,10 @@
+.orig x3000
+and r1, r1, #0
+add r1, r1, #3
+loop jsr double
+add r1, r1, #-1
+brp loop
+halt
+double add r0, r0, r0
+ret
+.end
@@ -0,0 +1,6 @@
+.orig x3000
+lea r0, msg
+puts
+halt
+msg .stringz "Hi"
+.end
@@ -0,0 +1,10 @@
+.orig x3000
+and r0, r0, #0
+jsr double
+st r0, result
+halt
+double add r0, r0, #1
+add r0, r0, r0
+ret
+result .fill #0
+.end
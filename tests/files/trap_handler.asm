@@ -0,0 +1,16 @@
+.orig x3000
+.trap x30, shout
+lea r0, msg
+trap x30
+halt
+; Print string in r0, followed by '!'
+shout st r7, saved
+puts
+ld r0, bang
+out
+ld r7, saved
+ret
+saved .fill #0
+bang .fill x21
+msg .stringz "Hey"
+.end
@@ -0,0 +1,14 @@
+.orig x3000
+.trap x30, shout
+lea r0, msg
+trap x30
+halt
+; Print string in r0, followed by '!'
+; Entered in supervisor mode when running with `--os`, so returns with `rti`
+shout puts
+ld r0, bang
+out
+rti
+bang .fill x21
+msg .stringz "Hey"
+.end
@@ -0,0 +1,7 @@
+.orig x3000
+lea r0, msg
+puts
+in
+halt
+msg .stringz "Type: "
+.end
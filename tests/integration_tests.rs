@@ -1,4 +1,5 @@
 use assert_cmd::Command;
+use predicates::prelude::PredicateBooleanExt;
 use predicates::str::contains;
 use tempfile::tempdir;
 
@@ -64,3 +65,422 @@ fn compile_and_run() {
 
     cmd.assert().success().stdout(contains("Hello, world!"));
 }
+
+#[test]
+fn compile_and_clean_artifacts() {
+    let dir = tempdir().expect("Could not make tempdir");
+    let out_dir = dir.path().join(".lace");
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("compile")
+        .arg("tests/files/hw.asm")
+        .arg("--out-dir")
+        .arg(&out_dir);
+    cmd.assert().success().stdout(contains("Saved target"));
+
+    assert!(out_dir.join("hw.lc3").exists());
+    assert!(out_dir.join("manifest").exists());
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("clean")
+        .arg("tests/files/hw.asm")
+        .arg("--out-dir")
+        .arg(&out_dir);
+    cmd.assert().success().stdout(contains("Removed"));
+    assert!(!out_dir.join("hw.lc3").exists());
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("clean").arg("--all").arg("--out-dir").arg(&out_dir);
+    cmd.assert().success();
+    assert!(!out_dir.exists());
+}
+
+#[test]
+fn feature_pragma_enables_stack() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run").arg("tests/files/feature_pragma.asm");
+    cmd.assert()
+        .success()
+        .stdout(contains("Hello from a pragma"));
+
+    let dir = tempdir().expect("Could not make tempdir");
+    let outfile_path = dir.path().join("feature_pragma.lc3");
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("compile")
+        .arg("tests/files/feature_pragma.asm")
+        .arg(&outfile_path);
+    cmd.assert().success();
+
+    // Required features are recorded in binary
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run").arg(&outfile_path);
+    cmd.assert()
+        .success()
+        .stdout(contains("Hello from a pragma"));
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run")
+        .arg(&outfile_path)
+        .arg("--features")
+        .arg("strict");
+    cmd.assert()
+        .failure()
+        .stderr(contains("Binary requires features 'stack'"));
+}
+
+#[test]
+fn checks_stack_example() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("check")
+        .arg("tests/files/stack.asm")
+        .arg("--features")
+        .arg("stack");
+    cmd.assert().success().stdout(contains("no errors found"));
+}
+
+#[test]
+fn check_json_diagnostics() {
+    let dir = tempdir().expect("Could not make tempdir");
+    let path = dir.path().join("errors.asm");
+    std::fs::write(&path, "addd r0 r0 r1\nbr lop\nloop halt\n").unwrap();
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("check")
+        .arg(&path)
+        .arg("--message-format")
+        .arg("json");
+
+    let output = cmd.assert().failure().get_output().stdout.clone();
+    let lines: Vec<serde_json::Value> = String::from_utf8(output)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["code"], "parse::unknown_mnemonic");
+    assert_eq!(lines[1]["code"], "backpatch::label_not_found");
+    assert_eq!(lines[1]["span"]["start_line"], 2);
+}
+
+#[test]
+fn check_sarif_log() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("check")
+        .arg("tests/files/hw.asm")
+        .arg("--message-format")
+        .arg("sarif");
+
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let log: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    assert_eq!(log["version"], "2.1.0");
+    assert_eq!(log["runs"][0]["results"], serde_json::json!([]));
+}
+
+#[test]
+fn explains_error_code() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("explain").arg("backpatch::label_not_found");
+    cmd.assert()
+        .success()
+        .stdout(contains("Erroneous code example"));
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("explain").arg("backpatch::label_not_fond");
+    cmd.assert()
+        .failure()
+        .stderr(contains("did you mean `backpatch::label_not_found`?"));
+}
+
+#[test]
+fn builds_and_tests_project() {
+    let dir = tempdir().expect("Could not make tempdir");
+    let root = dir.path();
+    std::fs::create_dir_all(root.join("lib")).unwrap();
+    std::fs::write(
+        root.join("lace.toml"),
+        r#"
+        features = ["stack"]
+        include = ["lib"]
+
+        [defines]
+        COUNT = 3
+
+        [[entry]]
+        path = "main.asm"
+
+        [[test]]
+        name = "echoes input"
+        input = "q"
+        output = "***q"
+        "#,
+    )
+    .unwrap();
+    std::fs::write(
+        root.join("main.asm"),
+        ".orig x3000\nld r1, count\nloop call star\nadd r1, r1, #-1\nbrp loop\ngetc\nout\nhalt\ncount .fill COUNT\n.include \"stars.asm\"\n",
+    )
+    .unwrap();
+    std::fs::write(
+        root.join("lib/stars.asm"),
+        "star lea r0, text\nputs\nrets\ntext .stringz \"*\"\n",
+    )
+    .unwrap();
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.current_dir(root).arg("build");
+    cmd.assert().success();
+    assert!(root.join(".lace/main.lc3").exists());
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.current_dir(root).arg("test");
+    cmd.assert()
+        .success()
+        .stdout(contains("Passed echoes input"));
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.current_dir(root).arg("run").write_stdin("z");
+    cmd.assert().success().stdout(contains("***z"));
+}
+
+#[test]
+fn stops_after_max_steps() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run")
+        .arg("tests/files/loop.asm")
+        .arg("--max-steps")
+        .arg("100");
+    cmd.assert()
+        .code(124)
+        .stderr(contains(
+            "exceeded limit of 100 instructions at pc 0x3000 (loop)",
+        ))
+        .stderr(contains("last executed: 0x3000 0x3001 0x3000"));
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run")
+        .arg("tests/files/loop.asm")
+        .arg("--timeout")
+        .arg("0.2");
+    cmd.assert()
+        .code(124)
+        .stderr(contains("exceeded time limit of 200ms"));
+
+    // Debugger pauses, and limit applies again after each command
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("debug")
+        .arg("tests/files/loop.asm")
+        .arg("-m")
+        .arg("--max-steps")
+        .arg("10")
+        .arg("-c")
+        .arg("continue; continue; exit");
+    cmd.assert()
+        .success()
+        .stderr(contains("RuntimeError").count(2));
+}
+
+#[test]
+fn highlights_source_as_html() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("highlight")
+        .arg("tests/files/hw.asm")
+        .arg("--format")
+        .arg("html")
+        .arg("--addresses")
+        .arg("--mark")
+        .arg("3");
+
+    cmd.assert()
+        .success()
+        .stdout(contains("<span class=\"comment\">; comment</span>"))
+        .stdout(contains(
+            "<span class=\"marked\"><span class=\"gutter\">x3001 </span><span class=\"mnemonic\">puts</span></span>",
+        ));
+}
+
+#[test]
+fn reports_size() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("size").arg("tests/files/hw.asm");
+
+    cmd.assert()
+        .success()
+        .stdout(contains("x3003  hw"))
+        .stdout(contains("17 words (3 code, 14 data)"));
+}
+
+#[test]
+fn compile_optimized() {
+    let dir = tempdir().expect("Could not make tempdir");
+    let outfile_path = dir.path().join("opt.lc3");
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("compile")
+        .arg("tests/files/optimize.asm")
+        .arg(&outfile_path)
+        .arg("--optimize");
+    cmd.assert()
+        .success()
+        .stdout(contains("opt::branch_next"))
+        .stdout(contains("1 statement removed"));
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg(&outfile_path);
+    cmd.assert().success().stdout(contains("Optimized"));
+}
+
+#[test]
+fn keyboard_interrupt() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run")
+        .arg("tests/files/interrupt.asm")
+        .write_stdin("k");
+    cmd.assert().success().stdout(contains("Key: k"));
+}
+
+#[test]
+fn polling_device_registers() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run").arg("tests/files/echo.asm").write_stdin("q");
+    cmd.assert()
+        .success()
+        .stdout(contains("q"))
+        .stdout(contains("Halted"));
+}
+
+#[test]
+fn run_with_os() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run")
+        .arg("tests/files/traps.asm")
+        .arg("--os")
+        .write_stdin("y");
+    cmd.assert()
+        .success()
+        .stdout(contains("Type: y"))
+        .stdout(contains("Halted"));
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run")
+        .arg("tests/files/puts.asm")
+        .arg("--os")
+        .arg("tests/files/bang_os.asm");
+    cmd.assert()
+        .success()
+        .stdout(contains("binary\n!"))
+        .stdout(contains("Hi").not());
+}
+
+#[test]
+fn user_trap_handler() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run").arg("tests/files/trap_handler.asm");
+    cmd.assert().success().stdout(contains("Hey!"));
+
+    // Handler is recorded in binary
+    let dir = tempdir().expect("Could not make tempdir");
+    let outfile_path = dir.path().join("trap_handler.lc3");
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("compile")
+        .arg("tests/files/trap_handler.asm")
+        .arg(&outfile_path);
+    cmd.assert().success();
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run").arg(&outfile_path);
+    cmd.assert().success().stdout(contains("Hey!"));
+
+    // Handler is kept when the operating system is loaded, and returns with `rti`
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run")
+        .arg("tests/files/trap_handler_os.asm")
+        .arg("--os");
+    cmd.assert()
+        .success()
+        .stdout(contains("Hey!"))
+        .stdout(contains("Halted"));
+}
+
+#[test]
+fn runtime_fault() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run").arg("tests/files/fault.asm");
+    cmd.assert()
+        .failure()
+        .stderr(contains("called a trap with an unknown vector of 0x30"));
+
+    // Debugger pauses at the faulting instruction
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("debug")
+        .arg("tests/files/fault.asm")
+        .arg("-m")
+        .arg("-c")
+        .arg("continue; registers");
+    cmd.assert()
+        .stderr(contains("RuntimeError"))
+        .stderr(contains("PC x3000"));
+}
+
+#[test]
+fn traces_execution() {
+    let dir = tempdir().expect("Could not make tempdir");
+    let trace_path = dir.path().join("trace.jsonl");
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run")
+        .arg("tests/files/trace.asm")
+        .arg("--trace")
+        .arg(&trace_path);
+    cmd.assert().success();
+    let trace = std::fs::read_to_string(&trace_path).unwrap();
+    assert_eq!(trace.lines().count(), 7);
+    assert!(trace.contains("x3005  double+1          add r0, r0, r0        r0 x0001 -> x0002"));
+    assert!(trace.contains("st r0, x3007          [x3007] <- x0002"));
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run")
+        .arg("tests/files/trace.asm")
+        .arg("--trace")
+        .arg(&trace_path)
+        .arg("--trace-format")
+        .arg("jsonl")
+        .arg("--trace-subroutine")
+        .arg("double");
+    cmd.assert().success();
+    let trace = std::fs::read_to_string(&trace_path).unwrap();
+    let cycles: Vec<u64> = trace
+        .lines()
+        .map(|line| {
+            serde_json::from_str::<serde_json::Value>(line).unwrap()["cycle"]
+                .as_u64()
+                .unwrap()
+        })
+        .collect();
+    assert_eq!(cycles, [3, 4, 5]);
+}
+
+#[test]
+fn profiles_program() {
+    let dir = tempdir().expect("Could not make tempdir");
+    let folded_path = dir.path().join("stacks.txt");
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run")
+        .arg("tests/files/profile.asm")
+        .arg("--profile")
+        .arg("--profile-folded")
+        .arg(&folded_path);
+    cmd.assert()
+        .success()
+        .stderr(contains("Profile of 18 instructions"))
+        .stderr(contains("loop                           10   55.6%"))
+        .stderr(contains(
+            "double                          6   33.3%        3",
+        ))
+        .stderr(contains(
+            "     8            3   16.7%  double add r0, r0, r0",
+        ));
+    let folded = std::fs::read_to_string(&folded_path).unwrap();
+    assert_eq!(folded, "main 12\nmain;double 6\n");
+}